@@ -1,22 +1,264 @@
-extern crate core;
-
-mod rdns;
-
-use crate::rdns::dns::Rdns;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use rdns::rdns::dns::{BlockResponse, Dnssec, RdnsConfig, UpstreamFamily};
+use rdns::rdns::{dnssec, worker, zone};
 use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(short, long, default_value = "0.0.0.0")]
     host: String,
     #[clap(short, long, default_value_t = 53)]
     port: u16,
+    /// Log more of what is going on, may be repeated. RUST_LOG overrides
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Minimum TTL of answers served from the cache
+    #[clap(long, default_value_t = 0)]
+    min_answer_ttl: u32,
+    /// Seconds a record is cached for at least, if its TTL is not 0
+    #[clap(long, default_value_t = 0)]
+    min_ttl: u32,
+    /// Seconds a record is cached for at most
+    #[clap(long, default_value_t = 86400)]
+    max_ttl: u32,
+    /// Seconds a negative answer is cached for at most, 0 not to cache them
+    #[clap(long, default_value_t = 10800)]
+    negative_max_ttl: u32,
+    /// Milliseconds to wait for a packet before running periodic
+    /// maintenance, 0 to wait forever
+    #[clap(long, default_value_t = 1000)]
+    read_timeout: u64,
+    /// Milliseconds to wait for an upstream server before asking again
+    #[clap(long, default_value_t = 2000)]
+    query_timeout: u64,
+    /// Milliseconds a client connection may stay idle before it is closed
+    #[clap(long, default_value_t = 10000)]
+    tcp_idle_timeout: u64,
+    /// Times to ask an upstream server again before giving up
+    #[clap(long, default_value_t = 2)]
+    retries: u32,
+    /// Referrals to follow for a query before giving up
+    #[clap(long, default_value_t = 16)]
+    max_delegations: u32,
+    /// File to append a line to for every answered query
+    #[clap(long)]
+    query_log: Option<PathBuf>,
+    /// Address family used to reach authoritative servers: auto, v4 or v6
+    #[clap(long, default_value = "auto")]
+    upstream_family: UpstreamFamily,
+    /// Only reach authoritative servers, the roots included, over IPv6.
+    /// Same as `--upstream-family v6`
+    #[clap(long, conflicts_with = "upstream-family")]
+    prefer_ipv6: bool,
+    /// Root server to start resolving from instead of the well-known ones,
+    /// may be repeated
+    #[clap(long = "root-hint")]
+    root_hints: Vec<IpAddr>,
+    /// Port to ask authoritative servers on
+    #[clap(long, default_value_t = 53)]
+    upstream_port: u16,
+    /// Local address to send upstream queries from, defaults to the
+    /// listening socket
+    #[clap(long)]
+    outbound_addr: Option<IpAddr>,
+    /// Primary server allowed to send NOTIFY, after which the zone is
+    /// transferred from it, may be repeated
+    #[clap(long = "primary")]
+    primaries: Vec<IpAddr>,
+    /// Let several processes listen on the same port
+    #[clap(long)]
+    reuse_port: bool,
+    /// Seconds between summaries of the queries handled, 0 to disable
+    #[clap(long, default_value_t = 0)]
+    stats_interval: u64,
+    /// Hosts file whose names are answered with its addresses
+    #[clap(long)]
+    hosts: Option<PathBuf>,
+    /// TTL of the answers from the hosts file
+    #[clap(long, default_value_t = 300)]
+    hosts_ttl: u32,
+    /// Zone file to serve authoritatively, may be repeated
+    #[clap(long = "zone")]
+    zones: Vec<PathBuf>,
+    /// File of names to block, one per line
+    #[clap(long)]
+    blocklist: Option<PathBuf>,
+    /// Also block the names below those of the blocklist
+    #[clap(long)]
+    block_subdomains: bool,
+    /// Answer for blocked names, unless their entry gives another: null
+    /// (0.0.0.0 or ::), nxdomain, nodata, refused or sinkhole
+    #[clap(long, default_value = "null")]
+    block_response: BlockResponse,
+    /// Address to answer with for blocked names with the sinkhole response,
+    /// may be given once for IPv4 and once for IPv6
+    #[clap(long)]
+    block_sinkhole: Vec<IpAddr>,
+    /// TTL of the answers for blocked names
+    #[clap(long, default_value_t = 300)]
+    block_ttl: u32,
+    /// Resolver to forward queries to, as ip:port, instead of resolving
+    /// them from the root
+    #[clap(long)]
+    forward: Option<SocketAddr>,
+    /// Pass EDNS options between clients and the forwarder untouched
+    #[clap(long, requires = "forward")]
+    edns_passthrough: bool,
+    /// Address to serve DNS-over-HTTPS on. Over TLS with --tls-cert and
+    /// --tls-key, otherwise as plain HTTP behind a TLS-terminating proxy
+    #[clap(long)]
+    doh_listen: Option<SocketAddr>,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[clap(long)]
+    metrics_listen: Option<SocketAddr>,
+    /// PEM certificate chain to serve DNS-over-TLS and DNS-over-HTTPS with
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key to serve DNS-over-TLS and DNS-over-HTTPS with
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Port to serve DNS-over-TLS on
+    #[clap(long, default_value_t = 853)]
+    dot_port: u16,
+    /// Query the forwarder over TLS, checking its certificate is valid for
+    /// this name
+    #[clap(long, requires = "forward")]
+    forward_tls: Option<String>,
+    /// PEM file of the certificate authorities to trust for --forward-tls
+    #[clap(long, requires = "forward-tls")]
+    forward_ca: Option<PathBuf>,
+    /// Records to keep in the cache before evicting the least recently used.
+    /// Evictions and expirations are logged at debug level as rdns::cache
+    #[clap(long, default_value_t = 10000)]
+    cache_size: usize,
+    /// Only serve queries over UDP
+    #[clap(long)]
+    no_tcp: bool,
+    /// Send every server the full question name
+    #[clap(long)]
+    no_qname_minimization: bool,
+    /// Send names upstream in the case given, without the 0x20 check
+    #[clap(long)]
+    no_case_randomization: bool,
+    /// Send upstream queries from the listening port rather than a random
+    /// one each
+    #[clap(long)]
+    no_port_randomization: bool,
+    /// Threads serving queries, sharing the cache
+    #[clap(long, default_value_t = 1)]
+    workers: usize,
+    /// Validate answers with DNSSEC: off, requested (for clients setting
+    /// the DO or AD bit) or always (unless they set CD)
+    #[clap(long, default_value = "off")]
+    dnssec: Dnssec,
+    /// DS record of a zone whose keys to trust, instead of the root's, as
+    /// "<zone> <key tag> <algorithm> <digest type> <digest>". May be
+    /// repeated
+    #[clap(long = "trust-anchor")]
+    trust_anchors: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check a zone file for errors without serving it
+    CheckZone { file: PathBuf },
+}
+
+/// Prints the problems of the zone file at `path`, exiting with a failure
+/// status if there are any
+fn check_zone(path: &Path) -> Result<(), Box<dyn Error>> {
+    let problems = zone::check(&fs::read_to_string(path)?);
+    for problem in &problems {
+        eprintln!("{}: {}", path.display(), problem);
+    }
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+    println!("{}: OK", path.display());
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
-    let mut d = Rdns::new(&args.host, args.port)?;
-    d.start()?;
+    if let Some(Command::CheckZone { file }) = &args.command {
+        return check_zone(file);
+    }
+    // the summaries asked for with --stats-interval are shown regardless
+    let level = match args.verbose {
+        0 => "warn,rdns::stats=info",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+    let trust_anchors = match args.trust_anchors.is_empty() {
+        true => dnssec::root_anchors(),
+        false => args
+            .trust_anchors
+            .iter()
+            .map(|x| dnssec::parse_anchor(x))
+            .collect::<Result<_, _>>()?,
+    };
+    let config = RdnsConfig {
+        min_answer_ttl: args.min_answer_ttl,
+        min_ttl: args.min_ttl,
+        max_ttl: args.max_ttl,
+        negative_max_ttl: args.negative_max_ttl,
+        read_timeout: match args.read_timeout {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        },
+        query_timeout: Duration::from_millis(args.query_timeout),
+        tcp_idle_timeout: Duration::from_millis(args.tcp_idle_timeout),
+        retries: args.retries,
+        max_delegations: args.max_delegations,
+        query_log: args.query_log,
+        upstream_family: match args.prefer_ipv6 {
+            true => UpstreamFamily::V6,
+            false => args.upstream_family,
+        },
+        root_hints: args.root_hints,
+        upstream_port: args.upstream_port,
+        outbound_addr: args.outbound_addr,
+        primaries: args.primaries,
+        reuse_port: args.reuse_port,
+        tcp: !args.no_tcp,
+        qname_minimization: !args.no_qname_minimization,
+        case_randomization: !args.no_case_randomization,
+        port_randomization: !args.no_port_randomization,
+        hosts: args.hosts,
+        hosts_ttl: args.hosts_ttl,
+        zones: args.zones,
+        blocklist: args.blocklist,
+        block_subdomains: args.block_subdomains,
+        block_response: args.block_response,
+        block_sinkhole: args.block_sinkhole,
+        block_ttl: args.block_ttl,
+        forward: args.forward,
+        edns_passthrough: args.edns_passthrough,
+        doh_listen: args.doh_listen,
+        metrics_listen: args.metrics_listen,
+        tls_cert: args.tls_cert,
+        tls_key: args.tls_key,
+        dot_port: args.dot_port,
+        forward_tls: args.forward_tls,
+        forward_ca: args.forward_ca,
+        cache_size: args.cache_size,
+        workers: args.workers,
+        dnssec: args.dnssec,
+        trust_anchors,
+        stats_interval: match args.stats_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        ..RdnsConfig::default()
+    };
+    worker::serve(&args.host, args.port, config)?;
     Ok(())
 }
@@ -0,0 +1,150 @@
+use crate::rdns::dns::BlockResponse;
+use crate::rdns::domain_name::{DomainName, DomainNameExt, ToDomainName};
+use crate::rdns::error::RdnsError;
+use crate::rdns::util::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Names not to resolve, read from a file with one per line and `#`
+/// starting a comment. `*.example.com` blocks the names below
+/// `example.com` but not `example.com` itself. A name may be followed by
+/// the response for it, e.g. `example.com nxdomain`, instead of the
+/// configured one. Unicode names are kept as their A-labels
+#[derive(Default)]
+pub struct Blocklist {
+    /// Lowercase names without the trailing dot, with their own response
+    names: HashMap<String, Option<BlockResponse>>,
+    /// Names whose subdomains are blocked, likewise
+    wildcards: HashMap<String, Option<BlockResponse>>,
+}
+
+impl Blocklist {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| RdnsError::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut blocklist = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let mut fields = line.split('#').next().unwrap().split_whitespace();
+            let name = match fields.next() {
+                Some(x) if x.is_ascii() => x.trim_end_matches('.').to_ascii_lowercase(),
+                Some(x) => {
+                    let (wildcard, x) = match x.strip_prefix("*.") {
+                        Some(x) => ("*.", x),
+                        None => ("", x),
+                    };
+                    let ascii = x
+                        .to_string()
+                        .to_ascii_domain_name()
+                        .map_err(|e| RdnsError::Config(format!("line {}: {}", i + 1, e)))?;
+                    format!("{}{}", wildcard, ascii.canonical())
+                }
+                None => continue,
+            };
+            let response = match fields.next().map(|x| x.parse()).transpose() {
+                Ok(x) => x,
+                Err(e) => return Err(RdnsError::Config(format!("line {}: {}", i + 1, e))),
+            };
+            if fields.next().is_some() {
+                return Err(RdnsError::Config(format!(
+                    "line {}: expected <name> [response]",
+                    i + 1
+                )));
+            }
+            match name.strip_prefix("*.") {
+                Some(parent) => blocklist.wildcards.insert(parent.to_string(), response),
+                None => blocklist.names.insert(name, response),
+            };
+        }
+        Ok(blocklist)
+    }
+
+    /// How a query for `name` is answered if it is blocked: by the response
+    /// of its entry, or else `default`. With `subdomains`, every entry also
+    /// blocks the names below it
+    pub fn response(
+        &self,
+        name: &DomainName,
+        subdomains: bool,
+        default: BlockResponse,
+    ) -> Option<BlockResponse> {
+        if let Some(x) = self.names.get(&name.canonical()) {
+            return Some(x.unwrap_or(default));
+        }
+        // the proper suffixes, longest first
+        (1..name.len()).find_map(|i| {
+            let suffix = name[i..].to_vec().canonical();
+            let entry = self.wildcards.get(&suffix).or_else(|| match subdomains {
+                true => self.names.get(&suffix),
+                false => None,
+            })?;
+            Some(entry.unwrap_or(default))
+        })
+    }
+
+    /// Whether an entry asks for a sinkhole address of its own accord
+    pub fn has_sinkhole(&self) -> bool {
+        self.names
+            .values()
+            .chain(self.wildcards.values())
+            .any(|x| *x == Some(BlockResponse::Sinkhole))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::domain_name::ToDomainName;
+
+    #[test]
+    fn entries_may_have_their_own_response() {
+        let blocklist = Blocklist::parse(
+            "ads.example # comment\n\
+             tracker.example refused\n\
+             *.cdn.example nodata\n",
+        )
+        .unwrap();
+        let response = |name: &str, subdomains| {
+            blocklist.response(
+                &name.to_string().to_domain_name(),
+                subdomains,
+                BlockResponse::NxDomain,
+            )
+        };
+        assert_eq!(
+            response("ADS.example", false),
+            Some(BlockResponse::NxDomain)
+        );
+        assert_eq!(
+            response("tracker.example", false),
+            Some(BlockResponse::Refused)
+        );
+        assert_eq!(response("a.tracker.example", false), None);
+        assert_eq!(
+            response("a.tracker.example", true),
+            Some(BlockResponse::Refused)
+        );
+        assert_eq!(
+            response("x.cdn.example", false),
+            Some(BlockResponse::NoData)
+        );
+        assert_eq!(response("cdn.example", true), None);
+        assert!(Blocklist::parse("ads.example bogus\n").is_err());
+    }
+
+    #[test]
+    fn unicode_entries_block_their_a_labels() {
+        let blocklist = Blocklist::parse("bücher.example\n*.bücher.test\n").unwrap();
+        for name in ["xn--bcher-kva.example", "www.xn--bcher-kva.test"] {
+            let response = blocklist.response(
+                &name.to_string().to_domain_name(),
+                false,
+                BlockResponse::NxDomain,
+            );
+            assert_eq!(response, Some(BlockResponse::NxDomain), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// (class, type, name) of the records cached
+pub type CacheKey = (u16, u16, String);
+
+/// Map holding at most `capacity` entries. Inserting into a full cache
+/// evicts the entry least recently inserted or looked up
+pub struct DnsCache<V> {
+    capacity: usize,
+    /// Entries with the tick they were last used at
+    entries: HashMap<CacheKey, (V, u64)>,
+    /// Keys by the tick they were last used at, oldest first
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl<V> DnsCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Looks up `key` and marks it as recently used
+    pub fn get(&mut self, key: &CacheKey) -> Option<&V> {
+        let tick = self.next_tick();
+        let (_, used) = self.entries.get_mut(key)?;
+        let key = self.order.remove(used).unwrap();
+        *used = tick;
+        self.order.insert(tick, key);
+        self.entries.get(&self.order[&tick]).map(|(v, _)| v)
+    }
+
+    /// Looks up `key` without changing its recency
+    pub fn peek(&self, key: &CacheKey) -> Option<&V> {
+        self.entries.get(key).map(|(v, _)| v)
+    }
+
+    /// Inserts `value` under `key`, returning the entry evicted to make
+    /// room for it, if any
+    pub fn insert(&mut self, key: CacheKey, value: V) -> Option<(CacheKey, V)> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let tick = self.next_tick();
+        let mut evicted = None;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.order.remove(&used);
+        } else if self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            let (value, _) = self.entries.remove(&oldest).unwrap();
+            evicted = Some((oldest, value));
+        }
+        self.order.insert(tick, key);
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) -> Option<V> {
+        let (value, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        Some(value)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&CacheKey, &V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (value, used)| {
+            let keep = f(key, value);
+            if !keep {
+                order.remove(used);
+            }
+            keep
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&CacheKey, &V)> {
+        self.entries.iter().map(|(k, (v, _))| (k, v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> CacheKey {
+        (1, 1, name.to_string())
+    }
+
+    #[test]
+    fn oldest_entry_is_evicted() {
+        let mut cache = DnsCache::new(2);
+        assert!(cache.insert(key("a"), 1).is_none());
+        assert!(cache.insert(key("b"), 2).is_none());
+        // replacing an entry makes no room
+        assert!(cache.insert(key("a"), 3).is_none());
+        assert_eq!(cache.insert(key("c"), 4), Some((key("b"), 2)));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.peek(&key("a")), Some(&3));
+    }
+
+    #[test]
+    fn lookup_refreshes_recency() {
+        let mut cache = DnsCache::new(2);
+        cache.insert(key("a"), 1);
+        cache.insert(key("b"), 2);
+        assert_eq!(cache.get(&key("a")), Some(&1));
+        assert_eq!(cache.insert(key("c"), 3), Some((key("b"), 2)));
+        // peeking does not
+        cache.peek(&key("a"));
+        assert_eq!(cache.insert(key("d"), 4), Some((key("a"), 1)));
+        cache.retain(|_, x| *x != 3);
+        assert_eq!(cache.insert(key("e"), 5), None);
+        assert_eq!(cache.len(), 2);
+    }
+}
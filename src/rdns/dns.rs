@@ -1,12 +1,38 @@
-use crate::rdns::domain_name::{ToDomainName, ToReadableName};
+use crate::rdns::blocklist::Blocklist;
+use crate::rdns::cache::{CacheKey, DnsCache};
+use crate::rdns::dnssec::{self, Verdict};
+use crate::rdns::doh;
+use crate::rdns::domain_name::{
+    validate, DomainName, DomainNameExt, ToDomainName, ToReadableName, MAX_NAME_LEN,
+};
+use crate::rdns::error::RdnsError;
+use crate::rdns::hosts::Hosts;
+use crate::rdns::http::HttpConn;
+use crate::rdns::metrics::{self, Metrics};
+use crate::rdns::query_log::{QueryLog, Source};
 use crate::rdns::records::{
-    DNSClass, DNSHeader, DNSPacket, DNSQuestion, DNSRcode, DNSRdata, DNSResourceRecord, DNSType,
+    DNSClass, DNSHeader, DNSOpcode, DNSPacket, DNSQuestion, DNSRcode, DNSRdata, DNSResourceRecord,
+    DNSType, EdnsOption,
 };
+use crate::rdns::tcp::{self, TcpConn, UpstreamConn};
+use crate::rdns::tls;
+use crate::rdns::transfer::{self, soa_serial};
 use crate::rdns::util::Either::{Left, Right};
-use crate::rdns::util::{Either, RangeRandExtRS, RangeRandExtS, Result};
+use crate::rdns::util::{Clock, Either, RangeRandExtS, Result, SystemClock};
+use crate::rdns::zone::{self, Zone};
 use chrono::{DateTime, Duration, Local};
+use log::{debug, info, trace, warn};
+use rustls::{ClientConfig, ServerConfig, ServerConnection, StreamOwned};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{iter, thread, time};
 
 const ROOT_SERVERS: [&str; 13] = [
     "198.41.0.4",
@@ -24,210 +50,4210 @@ const ROOT_SERVERS: [&str; 13] = [
     "202.12.27.33",
 ];
 
-fn get_a_root_addr() -> Result<IpAddr> {
-    let a: IpAddr = ROOT_SERVERS[(0..ROOT_SERVERS.len()).rand()].parse()?;
-    Ok(a)
+const ROOT_SERVERS_V6: [&str; 13] = [
+    "2001:503:ba3e::2:30",
+    "2801:1b8:10::b",
+    "2001:500:2::c",
+    "2001:500:2d::d",
+    "2001:500:a8::e",
+    "2001:500:2f::f",
+    "2001:500:12::d0d",
+    "2001:500:1::53",
+    "2001:7fe::53",
+    "2001:503:c27::2:30",
+    "2001:7fd::1",
+    "2001:500:9f::42",
+    "2001:dc3::35",
+];
+
+/// Large enough for any UDP datagram, so `recv_from` never truncates
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// UDP payload size advertised to upstream servers through EDNS0
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 4096;
+
+/// Largest UDP response to a client that did not advertise more through
+/// EDNS0 (RFC 1035 section 4.2.1)
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// How long a server stays marked lame for a zone
+const LAME_TTL: i64 = 15 * 60;
+
+/// CNAMEs followed for a query before the client gets SERVFAIL
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Chance of asking a random nameserver of a zone instead of the fastest
+const RTT_PROBE_RATE: f64 = 0.1;
+
+/// How long a measured RTT is remembered
+const RTT_TTL: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// Code of the edns-tcp-keepalive option (RFC 7828)
+const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+/// Upper bound on the time `resolve_full` waits for a resolution
+const RESOLVE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// The zone a referral delegates to, i.e. the owner of its NS records
+fn referral_zone(pkt: &DNSPacket) -> Option<DomainName> {
+    pkt.authorities
+        .iter()
+        .find(|x| x.r#type == DNSType::NS as u16)
+        .map(|x| x.name.clone())
+}
+
+/// The name the CNAMEs in the answer to the question of `pkt` lead to,
+/// if they lead elsewhere and no record of the type asked for came along
+fn cname_target(pkt: &DNSPacket) -> Option<DomainName> {
+    let question = pkt.questions.first()?;
+    if question.qtype == DNSType::CNAME as u16
+        || question.qtype == DNSType::ALL as u16
+        || pkt.answers.iter().any(|x| x.r#type == question.qtype)
+    {
+        return None;
+    }
+    let mut name = question.qname.clone();
+    // bounded, in case the CNAMEs of the answer form a loop
+    for _ in 0..pkt.answers.len() {
+        let next = pkt.answers.iter().find_map(|x| match x.rdata.as_ref() {
+            DNSRdata::Cname(target) if x.name.eq_ignore_case(&name) => Some(target.clone()),
+            _ => None,
+        });
+        match next {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    (!name.eq_ignore_case(&question.qname)).then_some(name)
+}
+
+/// Adds to the answer of `pkt` the CNAMEs its DNAMEs imply on the way
+/// from the question (RFC 6672 section 3.2), for servers that leave them
+/// out. A name that would get too long by the substitution is left as is
+fn synthesize_cnames(pkt: &mut DNSPacket) {
+    let mut name = match pkt.questions.first() {
+        Some(x) => x.qname.clone(),
+        None => return,
+    };
+    // bounded, in case the DNAMEs lead back below themselves
+    for _ in 0..MAX_CNAME_CHAIN {
+        let cname = pkt.answers.iter().find_map(|x| match x.rdata.as_ref() {
+            DNSRdata::Cname(target) if x.name.eq_ignore_case(&name) => Some(target.clone()),
+            _ => None,
+        });
+        if let Some(target) = cname {
+            name = target;
+            continue;
+        }
+        // only the names below the owner are redirected, not the owner
+        let dname = pkt.answers.iter().find(|x| {
+            matches!(x.rdata.as_ref(), DNSRdata::Dname(_))
+                && name.len() > x.name.len()
+                && name.is_subdomain_of(&x.name)
+        });
+        let (dname, target) = match dname.map(|x| (x, x.rdata.as_ref())) {
+            Some((x, DNSRdata::Dname(target))) => (x, target),
+            _ => break,
+        };
+        let mut rewritten = name[..name.len() - dname.name.len()].to_vec();
+        rewritten.extend(target.iter().cloned());
+        if rewritten.wire_len() > MAX_NAME_LEN {
+            break;
+        }
+        let cname = DNSResourceRecord {
+            name,
+            r#type: DNSType::CNAME as u16,
+            class: dname.class,
+            ttl: dname.ttl,
+            rdata: Arc::new(DNSRdata::Cname(rewritten.clone())),
+        };
+        pkt.answers.push(cname);
+        name = rewritten;
+    }
+}
+
+/// The authoritative answer to `question`, for the apex of a zone, made of
+/// the addresses in `pkt` for the target of its ALIAS record, served for at
+/// most `ttl`. The target not existing leaves the apex without addresses
+fn alias_answer(pkt: &DNSPacket, question: &DNSQuestion, ttl: u32) -> DNSPacket {
+    let mut answer = DNSPacket::new(pkt.id(), false);
+    answer.header.aa = 1;
+    answer.questions = vec![question.clone()];
+    if pkt.header.rcode != DNSRcode::NameError as u8 {
+        answer.header.rcode = pkt.header.rcode;
+    }
+    answer.answers = pkt
+        .answers
+        .iter()
+        .filter(|x| x.r#type == question.qtype)
+        .map(|x| DNSResourceRecord {
+            name: question.qname.clone(),
+            ttl: x.ttl.min(ttl),
+            ..x.clone()
+        })
+        .collect();
+    answer
+}
+
+/// Name and type of the question of `pkt`, for logging
+fn describe(pkt: &DNSPacket) -> String {
+    match pkt.questions.first() {
+        Some(q) => format!(
+            "{} {}",
+            q.qname.to_domain_name(),
+            DNSType::from_num(q.qtype)
+        ),
+        None => String::from("no question"),
+    }
+}
+
+/// Whether `pkt` looks up the DNSKEY or DS records of a zone, to validate
+/// an answer with
+fn is_key_lookup(pkt: &DNSPacket) -> bool {
+    let qtype = pkt.questions[0].qtype;
+    qtype == DNSType::DNSKEY as u16 || qtype == DNSType::DS as u16
+}
+
+/// The edns-tcp-keepalive option of `pkt`, if it has one
+fn keepalive_option(pkt: &DNSPacket) -> Option<&EdnsOption> {
+    pkt.additionals.iter().find_map(|x| match x.rdata.as_ref() {
+        DNSRdata::Opt { options, .. } => options.iter().find(|x| x.code == EDNS_TCP_KEEPALIVE),
+        _ => None,
+    })
+}
+
+/// Our OPT record, carrying `options`, with the DO bit asking for or
+/// telling of DNSSEC records if `dnssec_ok` (RFC 3225)
+fn opt_with(options: Vec<EdnsOption>, dnssec_ok: bool) -> DNSResourceRecord {
+    DNSResourceRecord {
+        rdata: Arc::new(DNSRdata::Opt {
+            udp_payload_size: EDNS_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok,
+            z: 0,
+            options,
+        }),
+        ..DNSResourceRecord::opt(EDNS_UDP_PAYLOAD_SIZE)
+    }
+}
+
+/// Name and type of the records of a cache entry, for logging
+fn describe_key(key: &CacheKey) -> String {
+    format!("{} {}", key.2, DNSType::from_num(key.1))
+}
+
+/// `pkt` on the wire, cut down to its header, question and OPT record with
+/// TC set if it exceeds `limit` octets (RFC 2181 section 9)
+fn encode(pkt: &DNSPacket, limit: usize) -> Result<Vec<u8>> {
+    let msg = pkt.assemble()?;
+    if msg.len() <= limit {
+        return Ok(msg);
+    }
+    let mut truncated = DNSPacket::new(pkt.id(), false);
+    truncated.header = pkt.header.clone();
+    truncated.header.tc = 1;
+    truncated.questions = pkt.questions.clone();
+    truncated.additionals = pkt
+        .additionals
+        .iter()
+        .filter(|x| x.r#type == DNSType::OPT as u16)
+        .cloned()
+        .collect();
+    truncated.assemble()
+}
+
+/// Reads a datagram, `None` if the socket timed out or would block
+fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> Result<Option<(usize, SocketAddr)>> {
+    match socket.recv_from(buf) {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Binds a listening socket. On Linux, sockets bound to the same address
+/// with SO_REUSEPORT each get a share of the incoming datagrams and
+/// connections, hashed by source address, so every process must set it and
+/// run as the same user. Other systems may only deliver to the last socket
+/// bound
+fn bind(addr: SocketAddr, r#type: Type, reuse_port: bool) -> Result<Socket> {
+    let protocol = match r#type {
+        Type::STREAM => Protocol::TCP,
+        _ => Protocol::UDP,
+    };
+    let socket = Socket::new(Domain::for_address(addr), r#type, Some(protocol))?;
+    if reuse_port {
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Where a query came from and its response goes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Client {
+    Udp(SocketAddr),
+    /// Connection id and peer
+    Tcp(u64, SocketAddr),
+    /// Likewise, over HTTP
+    Doh(u64, SocketAddr),
+    /// Server replying on the socket of the upstream query with this id
+    Upstream(u16, SocketAddr),
+}
+
+/// What the response to a client's query depends on, besides the question
+#[derive(Debug, Copy, Clone)]
+struct QueryInfo {
+    /// RD bit, echoed in the response
+    rd: u8,
+    /// UDP payload size advertised in the OPT record, `None` if the query
+    /// had none
+    edns: Option<u16>,
+    /// Whether the query asked for the idle timeout of its connection
+    keepalive: bool,
+    /// DO bit of the OPT record, asking for the DNSSEC records
+    dnssec_ok: bool,
+    /// AD bit, asking whether the answer was validated without the records
+    ad: bool,
+}
+
+impl QueryInfo {
+    fn of(query: &DNSPacket) -> Self {
+        let opt = query
+            .additionals
+            .iter()
+            .find_map(|x| match x.rdata.as_ref() {
+                DNSRdata::Opt {
+                    udp_payload_size,
+                    dnssec_ok,
+                    ..
+                } => Some((*udp_payload_size, *dnssec_ok)),
+                _ => None,
+            });
+        Self {
+            rd: query.header.rd,
+            edns: opt.map(|x| x.0),
+            keepalive: keepalive_option(query).is_some(),
+            dnssec_ok: opt.is_some_and(|x| x.1),
+            ad: query.header.ad(),
+        }
+    }
+
+    /// Largest response that may be sent to the client over UDP
+    fn udp_limit(&self) -> usize {
+        self.edns.unwrap_or(0).max(MIN_UDP_PAYLOAD_SIZE) as usize
+    }
+}
+
+impl Client {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Self::Udp(addr) | Self::Tcp(_, addr) | Self::Doh(_, addr) | Self::Upstream(_, addr) => {
+                *addr
+            }
+        }
+    }
 }
 
 pub struct RdnsData {
-    src_addr: SocketAddr,
-    packet_stack: Vec<DNSPacket>,
+    /// `None` if the resolution was started through `resolve_full`
+    client: Option<Client>,
+    /// Id the client chose, the upstream queries use one of our own
+    client_id: u16,
+    /// How the response has to be shaped for the client
+    info: QueryInfo,
+    /// The client's query, followed by queries for the addresses of
+    /// nameservers it is waiting on
+    packet_stack: Vec<PendingQuery>,
+    /// Nameservers whose address has been looked up for this request, so a
+    /// delegation that depends on itself fails instead of looping
+    visited: HashSet<String>,
+    /// Referrals followed so far, over all the queries of the stack
+    delegations: u32,
+    /// Answers leading from the client's question to the name being
+    /// resolved, when a CNAME was followed
+    chain: Vec<DNSResourceRecord>,
+    /// Question of the client and TTL of the ALIAS record, when the target
+    /// of one is resolved to answer for the apex of a local zone
+    alias: Option<(DNSQuestion, u32)>,
+    /// Whether the answers are validated with DNSSEC
+    validate: bool,
+    /// Cleared once an answer validated is found insecure
+    secure: bool,
+    /// DNSKEY and DS RRsets looked up for the validation, with their
+    /// signatures, keyed by lowercase owner and type
+    fetched: HashMap<(String, u16), Vec<DNSResourceRecord>>,
+    /// Answer waiting on one of those lookups to be validated
+    unverified: Option<DNSPacket>,
+    started: time::Instant,
+}
+
+/// A query being resolved and where in the delegation tree it is
+struct PendingQuery {
+    packet: DNSPacket,
+    /// Zone whose nameservers are being asked, the root at first
+    zone: DomainName,
+    /// Address the query was last sent to
+    server: IpAddr,
+    /// Addresses of other nameservers of `zone` to fall back to
+    candidates: Vec<IpAddr>,
+    /// Names of nameservers of `zone` without a known address, to look up
+    /// in turn once `candidates` run out
+    ns_names: Vec<String>,
+    /// Labels of the question's name last sent, in an NS query, while the
+    /// name is minimized (RFC 7816). `None` when the full question is sent
+    minimized: Option<usize>,
+    /// Cleared if a server did not answer a minimized query as expected,
+    /// the full question is sent from then on
+    minimize: bool,
+    /// Name in the question last sent. Its letters are of random case
+    /// (DNS 0x20), which the response must echo exactly
+    sent_qname: DomainName,
+    /// Socket the query was last sent from, on a random port, if it has
+    /// one of its own. Responses are only taken from it then
+    socket: Option<UdpSocket>,
+    /// Server whose response was truncated, which is asked over TCP
+    tcp_server: Option<IpAddr>,
+    /// Connection the query was last sent over, if sent over TCP
+    conn: Option<UpstreamConn>,
+    /// When the query was last sent, to measure the server's RTT
+    sent: time::Instant,
+    /// Retransmissions to `server` so far
+    retries: u32,
+}
+
+impl PendingQuery {
+    /// The query to send `server`, asking for `sent_qname`
+    fn outgoing(&self) -> DNSPacket {
+        let mut pkt = self.packet.clone();
+        let question = &mut pkt.questions[0];
+        question.qname = self.sent_qname.clone();
+        if self.minimized.is_some() {
+            question.qtype = DNSType::NS as u16;
+        }
+        pkt
+    }
+
+    /// The name `sent_qname` stands for, in the client's case
+    fn qname(&self) -> DomainName {
+        let qname = &self.packet.questions[0].qname;
+        qname[qname.len() - self.minimized.unwrap_or(qname.len())..].to_vec()
+    }
+}
+
+/// `name` with the case of each letter picked at random
+fn randomize_case(name: &[String]) -> DomainName {
+    name.iter()
+        .map(|label| {
+            label
+                .chars()
+                .map(|c| match rand::random() {
+                    true => c.to_ascii_uppercase(),
+                    false => c.to_ascii_lowercase(),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Counters for the periodic summary, reset after each one
+#[derive(Default)]
+struct Stats {
+    queries: u64,
+    cache_hits: u64,
+    /// Total time taken to answer the queries
+    latency: time::Duration,
+}
+
+/// Smoothed round-trip time of a nameserver
+struct RttEntry {
+    srtt: time::Duration,
+    updated: time::Instant,
+}
+
+/// Address family used to reach authoritative servers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpstreamFamily {
+    /// Use whatever glue is present and reachable from the socket
+    Auto,
+    V4,
+    V6,
+}
+
+impl FromStr for UpstreamFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "v4" => Ok(Self::V4),
+            "v6" => Ok(Self::V6),
+            _ => Err(format!("unknown address family `{}`", s)),
+        }
+    }
+}
+
+/// Which answers are validated with DNSSEC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dnssec {
+    /// The DNSSEC records are not even asked for
+    Off,
+    /// Only those for clients setting the DO or AD bit
+    Requested,
+    /// All of them, for clients not setting the CD bit
+    Always,
+}
+
+impl FromStr for Dnssec {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "requested" => Ok(Self::Requested),
+            "always" => Ok(Self::Always),
+            _ => Err(format!("unknown DNSSEC mode `{}`", s)),
+        }
+    }
+}
+
+/// How queries for blocked names are answered
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockResponse {
+    /// 0.0.0.0 or ::
+    Null,
+    NxDomain,
+    /// No records, with an SOA for the name to cache the answer by
+    NoData,
+    Refused,
+    /// The addresses of `block_sinkhole` of the family asked for, NODATA
+    /// for a family without one
+    Sinkhole,
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "null" => Ok(Self::Null),
+            "nxdomain" => Ok(Self::NxDomain),
+            "nodata" => Ok(Self::NoData),
+            "refused" => Ok(Self::Refused),
+            "sinkhole" => Ok(Self::Sinkhole),
+            _ => Err(format!("unknown block response `{}`", s)),
+        }
+    }
+}
+
+/// Tunables of the resolver
+#[derive(Debug, Clone)]
+pub struct RdnsConfig {
+    /// Minimum TTL handed out in answers served from the cache,
+    /// so that clients do not come back right away
+    pub min_answer_ttl: u32,
+    /// Bounds on how long records are cached, whatever their TTL says.
+    /// Records with a TTL of 0 are never cached
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// Longest a negative answer is cached, whatever its SOA says. RFC 2308
+    /// section 5 suggests 1 to 3 hours
+    pub negative_max_ttl: u32,
+    /// How long to block on the socket before running the periodic
+    /// maintenance, `None` to block forever
+    pub read_timeout: Option<time::Duration>,
+    /// How long to wait for an upstream server before sending the query
+    /// again. Checked whenever a packet arrives or `read_timeout` passes
+    pub query_timeout: time::Duration,
+    /// How long a client connection may stay idle before it is closed,
+    /// told to clients asking with the edns-tcp-keepalive option (RFC 7828)
+    pub tcp_idle_timeout: time::Duration,
+    /// Times an unanswered query is sent again before the client gets
+    /// SERVFAIL
+    pub retries: u32,
+    /// Referrals followed for a query, including those of the lookups of
+    /// nameserver addresses, before the client gets SERVFAIL
+    pub max_delegations: u32,
+    /// File to append a line to for every answered query. It is flushed
+    /// on each maintenance tick and reopened on SIGHUP
+    pub query_log: Option<PathBuf>,
+    pub upstream_family: UpstreamFamily,
+    /// Root servers to start resolving from instead of the well-known ones,
+    /// for a private DNS tree
+    pub root_hints: Vec<IpAddr>,
+    /// Port the authoritative servers are asked on
+    pub upstream_port: u16,
+    /// Local address to send upstream queries from, through a socket of
+    /// their own. `None` sends them from the listening socket
+    pub outbound_addr: Option<IpAddr>,
+    /// Primaries whose NOTIFY messages are acknowledged, others are refused.
+    /// The zone a NOTIFY is about is then transferred from the primary that
+    /// sent it, on `upstream_port`, incrementally if it can
+    pub primaries: Vec<IpAddr>,
+    /// Set SO_REUSEADDR and SO_REUSEPORT on the listening socket, so that
+    /// several resolver processes can share the same port
+    pub reuse_port: bool,
+    /// How often to log a summary of the queries handled, at info level
+    /// under the `rdns::stats` target, `None` to never. Checked on each
+    /// maintenance tick
+    pub stats_interval: Option<time::Duration>,
+    /// Also serve queries over TCP, on the same address and port
+    pub tcp: bool,
+    /// Randomize the case of the letters in the names sent upstream and
+    /// drop responses that do not echo it, making them harder to spoof
+    pub case_randomization: bool,
+    /// Send each upstream query from a socket of its own, on a port picked
+    /// at random, and take responses only from it, making them harder to
+    /// spoof
+    pub port_randomization: bool,
+    /// Only send each server the labels of the name it needs to refer us
+    /// further down (RFC 7816), rather than the full question. Not done
+    /// when forwarding
+    pub qname_minimization: bool,
+    /// Hosts file whose names are answered with its addresses instead of
+    /// being resolved
+    pub hosts: Option<PathBuf>,
+    /// TTL of the answers from the hosts file
+    pub hosts_ttl: u32,
+    /// Zone files whose records are served authoritatively
+    pub zones: Vec<PathBuf>,
+    /// File of names whose queries are answered according to
+    /// `block_response`, or the response of their entry, instead of being
+    /// resolved
+    pub blocklist: Option<PathBuf>,
+    /// Also block the names below those of the blocklist
+    pub block_subdomains: bool,
+    pub block_response: BlockResponse,
+    /// Addresses, at most one of each family, given for blocked names with
+    /// the sinkhole response
+    pub block_sinkhole: Vec<IpAddr>,
+    /// TTL of the answers for blocked names
+    pub block_ttl: u32,
+    /// Resolver to send the queries of clients to, with recursion desired,
+    /// instead of resolving them from the root
+    pub forward: Option<SocketAddr>,
+    /// Pass the EDNS options of clients on to the forwarder and its options
+    /// back to them, whether or not they are understood
+    pub edns_passthrough: bool,
+    /// Address to serve DNS-over-HTTPS queries (RFC 8484) on. Over TLS
+    /// with `tls_cert` and `tls_key` if given, otherwise as plain HTTP for
+    /// a TLS-terminating proxy in front
+    pub doh_listen: Option<SocketAddr>,
+    /// Address to serve the metrics on over HTTP, at `/metrics` in the
+    /// Prometheus text format
+    pub metrics_listen: Option<SocketAddr>,
+    /// PEM files of the certificate chain and private key to serve
+    /// DNS-over-TLS (RFC 7858) with, on `dot_port`, and DNS-over-HTTPS
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub dot_port: u16,
+    /// Name the certificate of `forward` must be valid for, to query it
+    /// over TLS instead of UDP
+    pub forward_tls: Option<String>,
+    /// PEM file of the certificate authorities to trust for `forward_tls`,
+    /// `None` for the usual public ones
+    pub forward_ca: Option<PathBuf>,
+    /// RRsets kept in the cache, and as many negative answers, before
+    /// the least recently used are evicted
+    pub cache_size: usize,
+    /// Threads serving the port, each resolving on its own with the caches
+    /// shared. More than one implies `reuse_port`, and upstream queries
+    /// are sent from a port of each worker's own
+    pub workers: usize,
+    /// Validate answers with DNSSEC, from `trust_anchors` down. Answers
+    /// found bogus get SERVFAIL, secure ones the AD bit for clients that
+    /// set DO or AD. Only answers resolved from the root are validated,
+    /// not those from the cache, and not negative ones, as NSEC and NSEC3
+    /// records are not checked: a referral without DS records is taken to
+    /// be to an unsigned zone. Not supported when forwarding
+    pub dnssec: Dnssec,
+    /// DS records of the zones whose keys are trusted without their parent
+    /// vouching for them, the root's
+    pub trust_anchors: Vec<DNSResourceRecord>,
+    /// Time source for TTL expiration
+    pub clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl Default for RdnsConfig {
+    fn default() -> Self {
+        Self {
+            min_answer_ttl: 0,
+            min_ttl: 0,
+            max_ttl: 86400,
+            negative_max_ttl: 3 * 60 * 60,
+            read_timeout: Some(time::Duration::from_secs(1)),
+            query_timeout: time::Duration::from_secs(2),
+            tcp_idle_timeout: time::Duration::from_secs(10),
+            retries: 2,
+            max_delegations: 16,
+            query_log: None,
+            upstream_family: UpstreamFamily::Auto,
+            root_hints: vec![],
+            upstream_port: 53,
+            outbound_addr: None,
+            primaries: vec![],
+            reuse_port: false,
+            stats_interval: None,
+            tcp: true,
+            qname_minimization: true,
+            port_randomization: true,
+            case_randomization: true,
+            hosts: None,
+            hosts_ttl: 300,
+            zones: vec![],
+            blocklist: None,
+            block_subdomains: false,
+            block_response: BlockResponse::Null,
+            block_sinkhole: vec![],
+            block_ttl: 300,
+            forward: None,
+            edns_passthrough: false,
+            doh_listen: None,
+            metrics_listen: None,
+            tls_cert: None,
+            tls_key: None,
+            dot_port: 853,
+            forward_tls: None,
+            forward_ca: None,
+            cache_size: 10000,
+            workers: 1,
+            dnssec: Dnssec::Off,
+            trust_anchors: dnssec::root_anchors(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Builds an `Rdns` listening on `host` and `port`, with the settings not
+/// given left at their defaults
+pub struct RdnsBuilder {
+    host: String,
+    port: u16,
+    config: RdnsConfig,
+}
+
+impl RdnsBuilder {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            config: RdnsConfig::default(),
+        }
+    }
+
+    /// Replaces all the settings given so far
+    pub fn config(mut self, config: RdnsConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn cache_capacity(mut self, entries: usize) -> Self {
+        self.config.cache_size = entries;
+        self
+    }
+
+    /// How long to wait for an upstream server before asking again
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.query_timeout = timeout;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.config.retries = retries;
+        self
+    }
+
+    pub fn max_delegations(mut self, max_delegations: u32) -> Self {
+        self.config.max_delegations = max_delegations;
+        self
+    }
+
+    pub fn min_answer_ttl(mut self, ttl: u32) -> Self {
+        self.config.min_answer_ttl = ttl;
+        self
+    }
+
+    /// Bounds on how long records are cached
+    pub fn ttl_bounds(mut self, min: u32, max: u32) -> Self {
+        self.config.min_ttl = min;
+        self.config.max_ttl = max;
+        self
+    }
+
+    /// Bound on how long negative answers are cached
+    pub fn negative_max_ttl(mut self, max: u32) -> Self {
+        self.config.negative_max_ttl = max;
+        self
+    }
+
+    pub fn forwarder(mut self, addr: SocketAddr) -> Self {
+        self.config.forward = Some(addr);
+        self
+    }
+
+    pub fn upstream_family(mut self, family: UpstreamFamily) -> Self {
+        self.config.upstream_family = family;
+        self
+    }
+
+    pub fn outbound_addr(mut self, ip: IpAddr) -> Self {
+        self.config.outbound_addr = Some(ip);
+        self
+    }
+
+    pub fn tcp(mut self, tcp: bool) -> Self {
+        self.config.tcp = tcp;
+        self
+    }
+
+    pub fn hosts_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.hosts = Some(path.into());
+        self
+    }
+
+    /// May be called for each zone to serve
+    pub fn zone_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.zones.push(path.into());
+        self
+    }
+
+    pub fn blocklist(mut self, path: impl Into<PathBuf>, response: BlockResponse) -> Self {
+        self.config.blocklist = Some(path.into());
+        self.config.block_response = response;
+        self
+    }
+
+    /// Address given for blocked names with the sinkhole response, may be
+    /// called once for each family
+    pub fn block_sinkhole(mut self, addr: IpAddr) -> Self {
+        self.config.block_sinkhole.push(addr);
+        self
+    }
+
+    pub fn query_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.query_log = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Rdns> {
+        Rdns::with_config(&self.host, self.port, self.config)
+    }
 }
 
 pub struct Rdns {
     socket: UdpSocket,
+    /// Socket for upstream queries if `outbound_addr` is configured
+    outbound: Option<UdpSocket>,
+    /// Socket for upstream queries to IPv6 servers when the one above is
+    /// IPv4, if the host has IPv6
+    outbound6: Option<UdpSocket>,
+    tcp: Option<TcpListener>,
+    /// DNS-over-TLS listener, whose connections join `tcp_conns`
+    dot: Option<(TcpListener, Arc<ServerConfig>)>,
+    tcp_conns: HashMap<u64, TcpConn>,
+    /// To reach the forwarder over TLS, if configured
+    forward_tls: Option<Arc<ClientConfig>>,
+    /// DoH listener, with the configuration to serve HTTPS on it unless
+    /// TLS is left to a proxy
+    doh: Option<(TcpListener, Option<Arc<ServerConfig>>)>,
+    doh_conns: HashMap<u64, HttpConn>,
+    metrics_listener: Option<TcpListener>,
+    metrics_conns: HashMap<u64, HttpConn>,
+    next_conn: u64,
     id_map: HashMap<u16, RdnsData>,
+    shared: Shared,
+    hosts: Hosts,
+    blocklist: Blocklist,
+    /// Expiration of servers found lame for a zone, keyed by (server, zone)
+    lame: HashMap<(IpAddr, String), DateTime<Local>>,
+    /// DNSKEY records validated, by lowercase zone, and when they expire
+    keys: HashMap<String, (DateTime<Local>, Vec<DNSResourceRecord>)>,
+    rtt: HashMap<IpAddr, RttEntry>,
+    last_tick: time::Instant,
+    stats: Stats,
+    last_stats: time::Instant,
+    /// Final responses of resolutions without a client, by id
+    resolved: HashMap<u16, DNSPacket>,
+    /// Cleared for a `Resolver`, which only sends queries of its own and
+    /// must not resolve for whoever reaches its socket
+    serve_clients: bool,
+    config: RdnsConfig,
+}
+
+/// What the workers serving the same port have in common, the caches
+/// above all. Each `Rdns` otherwise keeps its own state, such as the lame
+/// servers and round-trip times it has seen
+#[derive(Clone)]
+pub struct Shared {
+    cache: Arc<Mutex<DnsCache<RdnsCacheEntry>>>,
+    /// NXDOMAIN and NODATA answers
+    negative_cache: Arc<Mutex<DnsCache<NegativeCacheEntry>>>,
+    /// Nameservers of the zones referred to, keyed by zone
+    ns_cache: Arc<Mutex<DnsCache<NsCacheEntry>>>,
+    metrics: Arc<Mutex<Metrics>>,
+    query_log: Arc<Mutex<Option<QueryLog>>>,
+    /// Set by SIGHUP to have the query log reopened
+    reopen_log: Arc<AtomicBool>,
+    /// Served authoritatively, and kept up to date by transfers from their
+    /// primaries
+    zones: Arc<Mutex<Vec<Zone>>>,
+}
+
+impl Shared {
+    pub fn new(config: &RdnsConfig) -> Result<Self> {
+        let query_log = match &config.query_log {
+            Some(path) => Some(QueryLog::open(path)?),
+            None => None,
+        };
+        let reopen_log = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        if query_log.is_some() {
+            signal_hook::flag::register(signal_hook::consts::SIGHUP, reopen_log.clone())?;
+        }
+        let zones = config
+            .zones
+            .iter()
+            .map(|x| Zone::load(x))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            cache: Arc::new(Mutex::new(DnsCache::new(config.cache_size))),
+            negative_cache: Arc::new(Mutex::new(DnsCache::new(config.cache_size))),
+            ns_cache: Arc::new(Mutex::new(DnsCache::new(config.cache_size))),
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+            query_log: Arc::new(Mutex::new(query_log)),
+            reopen_log,
+            zones: Arc::new(Mutex::new(zones)),
+        })
+    }
+}
+
+/// What validating an answer came to, or the records it needs first
+enum Validation {
+    Done(Verdict),
+    /// The DNSKEY or DS RRset of a zone, to be looked up
+    Fetch(DomainName, DNSType),
 }
 
+/// A cached RRset, the records of one type for one name
 pub struct RdnsCacheEntry {
     expiration: DateTime<Local>,
-    record: DNSResourceRecord,
+    records: Vec<DNSResourceRecord>,
+}
+
+/// Nameservers of a zone, from a referral to it
+struct NsCacheEntry {
+    expiration: DateTime<Local>,
+    /// Lowercase names of the nameservers
+    names: Vec<String>,
+    /// Their addresses from the glue, if any
+    addrs: Vec<IpAddr>,
+    /// Those of `names` the glue has no address for
+    glueless: Vec<String>,
+    /// DS records of the zone and their signatures, if the referral had
+    /// any
+    ds: Vec<DNSResourceRecord>,
+}
+
+/// A cached negative answer
+struct NegativeCacheEntry {
+    expiration: DateTime<Local>,
+    /// NXDOMAIN, or NOERROR for NODATA
+    rcode: u8,
+    /// SOA of the zone, returned in the authority section
+    soa: DNSResourceRecord,
 }
 
 impl Rdns {
-    fn recv(&mut self, buf: &mut [u8]) -> (usize, SocketAddr) {
-        self.socket.recv_from(buf).expect("no data received")
+    fn now(&self) -> DateTime<Local> {
+        self.config.clock.now()
     }
 
-    fn send_to(&self, addr: &SocketAddr, pkt: &DNSPacket) -> Result<()> {
-        self.socket.send_to(&pkt.assemble()?, addr)?;
-        Ok(())
+    /// Whether the client socket is the only one to read from, which then
+    /// blocks for up to `read_timeout`
+    fn single_socket(&self) -> bool {
+        self.outbound.is_none()
+            && self.outbound6.is_none()
+            && self.tcp.is_none()
+            && self.dot.is_none()
+            && self.doh.is_none()
+            && self.metrics_listener.is_none()
+            && !self.config.port_randomization
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        let mut buf = [0u8; 4096];
-        let mut cache: HashMap<(u16, String), RdnsCacheEntry> = HashMap::new();
+    /// Receives a packet, or `None` if the read timed out
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Client)>> {
+        if !self.single_socket() {
+            return self.recv_any(buf);
+        }
+        let conns = self
+            .id_map
+            .values()
+            .any(|x| x.packet_stack.last().unwrap().conn.is_some());
+        if !conns {
+            return Ok(recv_from(&self.socket, buf)?.map(|(n, addr)| (n, Client::Udp(addr))));
+        }
+        // upstream connections are polled along with it for now
+        self.socket.set_nonblocking(true)?;
+        let res = self.recv_any(buf);
+        self.socket.set_nonblocking(false)?;
+        res
+    }
+
+    /// Receives a packet from any of the sockets and connections, all
+    /// non-blocking, waiting for one of them to be ready while none has
+    /// anything, until `read_timeout`
+    fn recv_any(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Client)>> {
+        let deadline = self.config.read_timeout.map(|x| time::Instant::now() + x);
         loop {
-            let (num_read, from_addr) = self.recv(&mut buf);
-            let cbuf = &buf[..num_read];
-            let mut received = match DNSPacket::from_raw(cbuf) {
-                Ok(x) => x,
-                Err(_) => continue,
-            };
-            let id = received.id();
-            // if is an existing query
-            if self.id_map.contains_key(&id) {
-                let original = self.id_map.get(&id).unwrap();
-                if original.src_addr == from_addr {
-                    self.error(&mut received, DNSRcode::Refused, &from_addr)?;
-                }
-                // if has answer
-                if received.answers.len() != 0 {
-                    // if is the answer to a self-generated query for NS information
-                    if original.packet_stack.len() > 1 {
-                        let addr = match received.answers[0].rdata.as_ref() {
-                            DNSRdata::A(ip) => ip,
-                            _ => {
-                                // error: must be an A record
-                                self.id_map.remove(&id).unwrap();
-                                continue;
-                            }
-                        }
-                        .clone();
-                        self.id_map.get_mut(&id).unwrap().packet_stack.pop();
-                        let original = self.id_map.get(&id).unwrap();
-                        self.new_query(
-                            &original.packet_stack.last().unwrap(),
-                            &SocketAddr::new(addr.into(), 53),
-                        )?;
-                        continue;
-                    }
-                    // if is the answer to the original query
-                    for ans in &received.answers {
-                        cache.insert(
-                            (ans.r#type, ans.name.to_domain_name()),
-                            RdnsCacheEntry {
-                                expiration: Local::now() + Duration::seconds(ans.ttl as i64),
-                                record: ans.clone(),
-                            },
-                        );
-                    }
-                    let original = self.id_map.remove(&id).unwrap();
-                    self.send_to(&original.src_addr, &received)?;
-                    continue;
+            for socket in iter::once(&self.socket)
+                .chain(&self.outbound)
+                .chain(&self.outbound6)
+            {
+                if let Some((n, addr)) = recv_from(socket, buf)? {
+                    return Ok(Some((n, Client::Udp(addr))));
                 }
-                // if no answer
-                match self.check_for_ns_addr(&received) {
-                    Right(names) => {
-                        // if is empty, then just return the record
-                        if names.is_empty() {
-                            let original = self.id_map.remove(&id).unwrap();
-                            self.send_to(&original.src_addr, &received)?;
-                            continue;
-                        }
-                        let n = &names[(0..names.len()).rand()];
-                        self.query_for(id, n)?
+            }
+            for (id, data) in &self.id_map {
+                let pending = data.packet_stack.last().unwrap();
+                if let Some(socket) = &pending.socket {
+                    if let Some((n, addr)) = recv_from(socket, buf)? {
+                        return Ok(Some((n, Client::Upstream(*id, addr))));
                     }
-                    Left(ip) => self.new_query(
-                        &original.packet_stack.last().unwrap(),
-                        &SocketAddr::new(ip.into(), 53),
-                    )?,
                 }
-                continue;
             }
-            // new query
-            if !received.header.is_query() {
-                continue;
+            if let Some(x) = self.recv_upstream_tcp(buf)? {
+                return Ok(Some(x));
             }
-            if received.answers.len() != 0 {
-                continue;
+            if let Some(x) = self.recv_tcp(buf)? {
+                return Ok(Some(x));
             }
-            // check cache
-            let question = &received.questions[0];
-            match cache.get(&(question.qtype, question.qname.to_domain_name())) {
-                Some(cached_res) => {
-                    if Local::now() >= cached_res.expiration {
-                        cache.remove(&(question.qtype, question.qname.to_domain_name()));
-                        ()
-                    } else {
-                        let mut rec = cached_res.record.clone();
-                        rec.ttl = (cached_res.expiration - Local::now()).num_seconds() as u32;
-                        // return result
-                        self.send_to(
-                            &from_addr,
-                            &DNSPacket {
-                                header: DNSHeader::new(received.id(), false),
-                                questions: vec![question.clone()],
-                                answers: vec![rec],
-                                authorities: vec![],
-                                additionals: vec![],
-                            },
-                        )?;
-                        continue;
-                    }
-                }
-                None => (),
+            if let Some(x) = self.recv_doh(buf)? {
+                return Ok(Some(x));
             }
-            self.id_map.insert(
-                id,
-                RdnsData {
-                    src_addr: from_addr,
-                    packet_stack: vec![received],
-                },
-            );
-            self.new_query(
-                &self.id_map.get(&id).unwrap().packet_stack.last().unwrap(),
-                &SocketAddr::new(get_a_root_addr()?, 53),
-            )?;
+            self.serve_metrics()?;
+            if deadline.is_some_and(|x| time::Instant::now() >= x) {
+                return Ok(None);
+            }
+            self.wait(deadline)?;
         }
     }
 
-    pub fn new(host: &str, port: u16) -> Result<Rdns> {
-        let addr = SocketAddr::new(host.parse()?, port);
-        let datamap = HashMap::new();
-        let r = Rdns {
-            socket: UdpSocket::bind(addr)?,
-            id_map: datamap,
+    /// Blocks until one of the sockets or connections can be read, or
+    /// written for those with something left to write, or until
+    /// `deadline`. Connections that are done reading are left out, the
+    /// end of their stream would always be ready
+    fn wait(&self, deadline: Option<time::Instant>) -> Result<()> {
+        let mut fds = vec![];
+        let mut add = |fd: RawFd, read: bool, write: bool| {
+            let events = match (read, write) {
+                (false, false) => return,
+                (true, false) => libc::POLLIN,
+                (false, true) => libc::POLLOUT,
+                (true, true) => libc::POLLIN | libc::POLLOUT,
+            };
+            fds.push(libc::pollfd {
+                fd,
+                events,
+                revents: 0,
+            });
         };
-        Ok(r)
-    }
-
-    fn query_for(&mut self, id: u16, domain_name: &String) -> Result<()> {
-        if !self.id_map.contains_key(&id) {
-            panic!("no");
+        for socket in iter::once(&self.socket)
+            .chain(&self.outbound)
+            .chain(&self.outbound6)
+        {
+            add(socket.as_raw_fd(), true, false);
+        }
+        for data in self.id_map.values() {
+            let pending = data.packet_stack.last().unwrap();
+            if let Some(socket) = &pending.socket {
+                add(socket.as_raw_fd(), true, false);
+            }
+            if let Some(conn) = &pending.conn {
+                add(conn.as_raw_fd(), true, conn.wants_write());
+            }
+        }
+        let listeners = self
+            .tcp
+            .iter()
+            .chain(self.dot.iter().map(|x| &x.0))
+            .chain(self.doh.iter().map(|x| &x.0))
+            .chain(&self.metrics_listener);
+        for listener in listeners {
+            add(listener.as_raw_fd(), true, false);
+        }
+        for conn in self.tcp_conns.values() {
+            add(conn.as_raw_fd(), conn.wants_read(), conn.wants_write());
+        }
+        for conn in self.doh_conns.values().chain(self.metrics_conns.values()) {
+            add(conn.as_raw_fd(), conn.wants_read(), conn.wants_write());
+        }
+        // in whole milliseconds, rounded up not to wake up early
+        let timeout = match deadline {
+            Some(x) => {
+                let left = x.saturating_duration_since(time::Instant::now());
+                left.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfd structs
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e.into());
+            }
         }
-        let mut pkt = DNSPacket::new(id, true);
-        pkt.questions.push(DNSQuestion::new(
-            domain_name.to_domain_name(),
-            DNSType::A as u16,
-        ));
-        self.new_query(&pkt, &SocketAddr::new(get_a_root_addr()?, 53))?;
-        let data = self.id_map.get_mut(&id).unwrap();
-        data.packet_stack.push(pkt);
         Ok(())
     }
 
-    fn new_query(&self, pkt: &DNSPacket, to_addr: &SocketAddr) -> Result<()> {
-        self.socket.send_to(&pkt.assemble()?, to_addr)?;
-        Ok(())
+    /// Gets on with the queries sent over TCP and returns the first complete
+    /// response. Those whose connection broke go to another server
+    fn recv_upstream_tcp(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Client)>> {
+        let mut res = None;
+        let mut broken = vec![];
+        for (id, data) in self.id_map.iter_mut() {
+            let conn = match &mut data.packet_stack.last_mut().unwrap().conn {
+                Some(x) => x,
+                None => continue,
+            };
+            match conn.poll() {
+                Ok(Some(msg)) if msg.len() <= buf.len() => {
+                    buf[..msg.len()].copy_from_slice(&msg);
+                    res = Some((msg.len(), Client::Upstream(*id, conn.peer)));
+                    break;
+                }
+                Ok(Some(_)) => broken.push(*id),
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("connection to {} broke: {}", conn.peer, e);
+                    broken.push(*id);
+                }
+            }
+        }
+        for id in broken {
+            self.pending_mut(id).conn = None;
+            self.next_server(id)?;
+        }
+        Ok(res)
     }
 
-    fn check_for_ns_addr(&self, pkt: &DNSPacket) -> Either<Ipv4Addr, Vec<String>> {
-        let mut nameservs = HashSet::new();
-        for x in &pkt.authorities {
-            if x.r#type == DNSType::NS as u16 {
-                if let DNSRdata::Ns(dn) = x.rdata.as_ref() {
-                    nameservs.insert(dn.to_domain_name());
+    /// Accepts pending connections, plain and TLS, and returns the first
+    /// complete message found on any of them. A connection that cannot be
+    /// set up is dropped, the others are still served
+    fn recv_tcp(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Client)>> {
+        while let Some(listener) = &self.tcp {
+            match listener.accept() {
+                Ok((stream, peer)) => match TcpConn::new(stream, peer) {
+                    Ok(conn) => {
+                        self.next_conn += 1;
+                        self.tcp_conns.insert(self.next_conn, conn);
+                    }
+                    Err(e) => debug!("dropping connection from {}: {}", peer, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // such as running out of file descriptors, tried again
+                // on the next poll
+                Err(e) => {
+                    warn!("failed to accept a TCP connection: {}", e);
+                    break;
                 }
             }
         }
-        let mut v = Vec::new();
-        for x in &pkt.additionals {
-            if let DNSRdata::A(ip) = x.rdata.as_ref() {
-                if nameservs.contains(x.name.to_domain_name().as_str()) {
-                    v.push(ip);
+        while let Some((listener, config)) = &self.dot {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    // the handshake goes on as the connection is read
+                    let conn = ServerConnection::new(config.clone())
+                        .map_err(RdnsError::from)
+                        .and_then(|x| TcpConn::new(StreamOwned::new(x, stream), peer));
+                    match conn {
+                        Ok(conn) => {
+                            self.next_conn += 1;
+                            self.tcp_conns.insert(self.next_conn, conn);
+                        }
+                        Err(e) => debug!("dropping connection from {}: {}", peer, e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("failed to accept a TLS connection: {}", e);
+                    break;
                 }
             }
         }
-        if v.is_empty() {
-            return Right(nameservs.into_iter().collect());
+        let mut res = None;
+        let mut broken = vec![];
+        for (id, conn) in self.tcp_conns.iter_mut() {
+            match conn.read_message() {
+                Ok(Some(msg)) => {
+                    buf[..msg.len()].copy_from_slice(&msg);
+                    res = Some((msg.len(), Client::Tcp(*id, conn.peer)));
+                    break;
+                }
+                Ok(None) => {}
+                Err(_) => broken.push(*id),
+            }
+        }
+        for id in broken {
+            self.tcp_conns.remove(&id);
         }
-        Left(*v.rand())
+        Ok(res)
     }
 
-    fn error(&self, pkt: &mut DNSPacket, rcode: DNSRcode, addr: &SocketAddr) -> Result<()> {
-        pkt.header.set_rcode(rcode);
-        self.send_to(&addr, pkt)?;
-        Ok(())
+    /// Accepts pending DoH connections and returns the first complete query
+    /// found on any of them. Invalid requests are answered right away, and
+    /// a connection that cannot be set up is dropped
+    fn recv_doh(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Client)>> {
+        let (listener, config) = match &self.doh {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    // the handshake goes on as the request is read
+                    let conn = match config {
+                        Some(config) => ServerConnection::new(config.clone())
+                            .map_err(RdnsError::from)
+                            .and_then(|x| HttpConn::new(StreamOwned::new(x, stream), peer)),
+                        None => HttpConn::new(stream, peer),
+                    };
+                    match conn {
+                        Ok(conn) => {
+                            self.next_conn += 1;
+                            self.doh_conns.insert(self.next_conn, conn);
+                        }
+                        Err(e) => debug!("dropping connection from {}: {}", peer, e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("failed to accept a DoH connection: {}", e);
+                    break;
+                }
+            }
+        }
+        let mut res = None;
+        let mut done = vec![];
+        for (id, conn) in self.doh_conns.iter_mut() {
+            match conn.read_request() {
+                Ok(Some(req)) => match req.and_then(|x| doh::message(&x)) {
+                    Ok(msg) if msg.len() <= buf.len() => {
+                        buf[..msg.len()].copy_from_slice(&msg);
+                        res = Some((msg.len(), Client::Doh(*id, conn.peer)));
+                        break;
+                    }
+                    Ok(_) => {
+                        let _ = conn.write_error(413);
+                        done.push(*id);
+                    }
+                    Err(status) => {
+                        let _ = conn.write_error(status);
+                        done.push(*id);
+                    }
+                },
+                Ok(None) => {}
+                Err(_) => done.push(*id),
+            }
+        }
+        for id in done {
+            self.doh_conns.remove(&id);
+        }
+        Ok(res)
+    }
+
+    /// Accepts pending connections to the metrics endpoint and answers
+    /// those whose request is complete. A connection that cannot be set up
+    /// is dropped
+    fn serve_metrics(&mut self) -> Result<()> {
+        let listener = match &self.metrics_listener {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => match HttpConn::new(stream, peer) {
+                    Ok(conn) => {
+                        self.next_conn += 1;
+                        self.metrics_conns.insert(self.next_conn, conn);
+                    }
+                    Err(e) => debug!("dropping connection from {}: {}", peer, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("failed to accept a metrics connection: {}", e);
+                    break;
+                }
+            }
+        }
+        let mut done = vec![];
+        for (id, conn) in self.metrics_conns.iter_mut() {
+            let _ = match conn.read_request() {
+                Ok(Some(Ok(req))) if req.path != "/metrics" => conn.write_error(404),
+                Ok(Some(Ok(req))) if req.method != "GET" => conn.write_error(405),
+                Ok(Some(Ok(_))) => {
+                    let headers = [("Content-Type", metrics::CONTENT_TYPE.to_string())];
+                    conn.write_response(
+                        200,
+                        &headers,
+                        self.shared.metrics.lock().unwrap().render().as_bytes(),
+                    )
+                }
+                Ok(Some(Err(status))) => conn.write_error(status),
+                Ok(None) => continue,
+                Err(_) => Ok(()),
+            };
+            done.push(*id);
+        }
+        for id in done {
+            self.metrics_conns.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Periodic housekeeping, run whenever the socket has been idle
+    /// for `read_timeout` or that much time has passed since the last run
+    fn tick(&mut self) -> Result<()> {
+        let now = self.now();
+        let shared = &self.shared;
+        let mut expired = 0;
+        let mut sweep = |cache: &str, key: &CacheKey, expiration: &DateTime<Local>| {
+            let keep = now < *expiration;
+            if !keep {
+                debug!(target: "rdns::cache", "{} entry for {} expired", cache, describe_key(key));
+                expired += 1;
+            }
+            keep
+        };
+        shared
+            .cache
+            .lock()
+            .unwrap()
+            .retain(|key, entry| sweep("record", key, &entry.expiration));
+        shared
+            .negative_cache
+            .lock()
+            .unwrap()
+            .retain(|key, entry| sweep("negative", key, &entry.expiration));
+        shared
+            .ns_cache
+            .lock()
+            .unwrap()
+            .retain(|key, entry| sweep("NS", key, &entry.expiration));
+        shared.metrics.lock().unwrap().cache_expirations += expired;
+        self.lame.retain(|_, expiration| now < *expiration);
+        self.keys.retain(|_, (expiration, _)| now < *expiration);
+        self.rtt.retain(|_, x| x.updated.elapsed() < RTT_TTL);
+        let idle_timeout = self.config.tcp_idle_timeout;
+        self.tcp_conns
+            .retain(|_, x| x.last_active.elapsed() < idle_timeout);
+        self.doh_conns
+            .retain(|_, x| x.last_active.elapsed() < idle_timeout);
+        self.metrics_conns
+            .retain(|_, x| x.last_active.elapsed() < idle_timeout);
+        if let Some(log) = self.shared.query_log.lock().unwrap().as_mut() {
+            if self.shared.reopen_log.swap(false, Ordering::Relaxed) {
+                if let Err(e) = log.reopen() {
+                    warn!("failed to reopen the query log: {}", e);
+                }
+            }
+            if let Err(e) = log.flush() {
+                warn!("failed to write the query log: {}", e);
+            }
+        }
+        if let Some(interval) = self.config.stats_interval {
+            if self.last_stats.elapsed() >= interval {
+                self.log_stats();
+                self.stats = Stats::default();
+                self.last_stats = time::Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    fn log_stats(&self) {
+        let Stats {
+            queries,
+            cache_hits,
+            latency,
+        } = self.stats;
+        let (hit_rate, avg_latency) = match queries {
+            0 => (0.0, time::Duration::ZERO),
+            n => (cache_hits as f64 * 100.0 / n as f64, latency / n as u32),
+        };
+        info!(
+            target: "rdns::stats",
+            "{} queries, {:.1}% cache hits, {} in flight, {}ms average latency",
+            queries,
+            hit_rate,
+            self.id_map.len(),
+            avg_latency.as_millis()
+        );
+    }
+
+    fn log_query(
+        &mut self,
+        client: &SocketAddr,
+        query: &DNSPacket,
+        rcode: u8,
+        source: Source,
+        started: time::Instant,
+    ) {
+        self.stats.queries += 1;
+        self.stats.cache_hits += (source == Source::Cache) as u64;
+        self.stats.latency += started.elapsed();
+        let mut metrics = self.shared.metrics.lock().unwrap();
+        metrics.queries += 1;
+        match source {
+            Source::Cache => metrics.cache_hits += 1,
+            Source::Hosts => metrics.hosts_answers += 1,
+            Source::Zone => metrics.zone_answers += 1,
+            Source::Blocklist => metrics.blocked += 1,
+            Source::Upstream => metrics.cache_misses += 1,
+        }
+        if rcode == DNSRcode::ServerFailure as u8 {
+            metrics.servfails += 1;
+        } else if rcode == DNSRcode::NameError as u8 {
+            metrics.nxdomains += 1;
+        }
+        metrics.observe_latency(started.elapsed());
+        drop(metrics);
+        let now = self.now();
+        let mut log = self.shared.query_log.lock().unwrap();
+        let (log, question) = match (log.as_mut(), query.questions.first()) {
+            (Some(log), Some(question)) => (log, question),
+            _ => return,
+        };
+        let elapsed = started.elapsed().as_millis();
+        // answering goes on without the log, the disk may be full
+        if let Err(e) = log.log(now, client.ip(), question, rcode, source, elapsed) {
+            warn!("failed to write the query log: {}", e);
+        }
+    }
+
+    /// Answers the client of `id` with an error and its original question.
+    /// If only the lookup of a nameserver's address failed, the query
+    /// waiting on it falls back to the other nameservers instead
+    fn fail(&mut self, id: u16, rcode: DNSRcode) -> Result<()> {
+        let data = self.id_map.get_mut(&id).unwrap();
+        if data.packet_stack.len() > 1 {
+            let lookup = &data.packet_stack.last().unwrap().packet;
+            debug!("looking up {} failed", describe(lookup));
+            // without the keys the answer cannot be validated
+            if is_key_lookup(lookup) {
+                data.packet_stack.truncate(1);
+            } else {
+                data.packet_stack.pop();
+                return self.query_next_ns(id);
+            }
+        }
+        let original = &self.id_map[&id].packet_stack[0].packet;
+        debug!("{} failed with {:?}", describe(original), rcode);
+        let mut pkt = DNSPacket::new(original.id(), false);
+        pkt.header.set_rcode(rcode);
+        pkt.questions = original.questions.clone();
+        self.reply(id, &pkt)
+    }
+
+    /// Sends `pkt` back to the client of `id` and finishes its resolution
+    fn reply(&mut self, id: u16, pkt: &DNSPacket) -> Result<()> {
+        let mut original = self.id_map.remove(&id).unwrap();
+        let mut pkt = pkt.clone();
+        pkt.header.id = original.client_id;
+        // only the answers are validated, and those for the target of an
+        // ALIAS are not what the client asked about
+        if self.config.dnssec != Dnssec::Off {
+            pkt.header.set_ad(
+                original.validate
+                    && original.secure
+                    && pkt.header.rcode == DNSRcode::Normal as u8
+                    && !pkt.answers.is_empty()
+                    && original.alias.is_none(),
+            );
+        }
+        // answer the question of the client as it was asked, with the
+        // CNAMEs followed, the first of which is for the name asked for.
+        // DNAMEs may come before it
+        let query = &mut original.packet_stack[0].packet;
+        if let Some(first) = original
+            .chain
+            .iter()
+            .find(|x| x.r#type == DNSType::CNAME as u16)
+        {
+            query.questions[0].qname = first.name.clone();
+        }
+        pkt.answers.splice(0..0, original.chain.iter().cloned());
+        pkt.questions = query.questions.clone();
+        if let Some((question, ttl)) = original.alias.take() {
+            pkt = alias_answer(&pkt, &question, ttl);
+            original.packet_stack[0].packet.questions = vec![question];
+        }
+        let client = match original.client {
+            Some(x) => x,
+            None => {
+                self.resolved.insert(id, pkt);
+                return Ok(());
+            }
+        };
+        self.respond(&client, original.info, &pkt);
+        self.log_query(
+            &client.addr(),
+            &original.packet_stack[0].packet,
+            pkt.header.rcode,
+            Source::Upstream,
+            original.started,
+        );
+        Ok(())
+    }
+
+    /// Sends `pkt` to `client` in response to a query described by `info`.
+    /// Recursion is available whether or not the client asked for it.
+    /// Failing to reach one client is no reason to stop serving the others,
+    /// so errors are only logged
+    fn respond(&mut self, client: &Client, info: QueryInfo, pkt: &DNSPacket) {
+        let mut pkt = pkt.clone();
+        pkt.header.rd = info.rd;
+        pkt.header.ra = 1;
+        // only clients that know of DNSSEC are told an answer is secure
+        // (RFC 6840 section 5.8), and only those asking for its records get
+        // them, unless they are what the question is about (RFC 4035
+        // section 3.2.1)
+        if !info.dnssec_ok && !info.ad {
+            pkt.header.set_ad(false);
+        }
+        let qtype = pkt.questions.first().map(|x| x.qtype);
+        if !info.dnssec_ok
+            && qtype != Some(DNSType::RRSIG as u16)
+            && qtype != Some(DNSType::ALL as u16)
+        {
+            for section in [&mut pkt.answers, &mut pkt.authorities] {
+                section.retain(|x| x.r#type != DNSType::RRSIG as u16);
+            }
+        }
+        // the OPT record is hop by hop, an upstream one is never relayed and
+        // a client that sent none gets none (RFC 6891 section 6.1.1). Only
+        // the options of the forwarder's may be passed on
+        let mut options = self.passed_options(&pkt);
+        // in units of 100 milliseconds, only ever over TCP
+        if info.keepalive && matches!(client, Client::Tcp(..)) {
+            let timeout = self.config.tcp_idle_timeout.as_millis() / 100;
+            options.push(EdnsOption {
+                code: EDNS_TCP_KEEPALIVE,
+                data: (timeout.min(u16::MAX as u128) as u16)
+                    .to_be_bytes()
+                    .to_vec(),
+            });
+        }
+        pkt.additionals.retain(|x| x.r#type != DNSType::OPT as u16);
+        if info.edns.is_some() {
+            pkt.additionals.push(opt_with(options, info.dnssec_ok));
+        }
+        match client {
+            Client::Udp(addr) => {
+                let res = encode(&pkt, info.udp_limit())
+                    .and_then(|msg| Ok(self.socket.send_to(&msg, addr)?));
+                if let Err(e) = res {
+                    debug!("cannot respond to {}: {}", addr, e);
+                }
+            }
+            Client::Tcp(id, addr) => {
+                // the client may have gone away in the meantime
+                if let Some(conn) = self.tcp_conns.get_mut(id) {
+                    let res = encode(&pkt, MAX_MESSAGE_SIZE).and_then(|x| conn.write_message(&x));
+                    if let Err(e) = res {
+                        debug!("cannot respond to {}: {}", addr, e);
+                        self.tcp_conns.remove(id);
+                    }
+                }
+            }
+            Client::Doh(id, _) => {
+                // one request per connection, closed once answered
+                if let Some(mut conn) = self.doh_conns.remove(id) {
+                    let _ = doh::respond(&mut conn, &pkt);
+                }
+            }
+            Client::Upstream(..) => {}
+        }
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        loop {
+            self.poll(&mut buf)?;
+        }
+    }
+
+    /// Resolves `name` from the root down and returns the final response
+    /// as is, including authority and additional sections. Packets of
+    /// other in-flight resolutions are handled in the meantime
+    pub fn resolve_full(&mut self, name: &str, qtype: DNSType) -> Result<DNSPacket> {
+        let qname = name.to_string().to_ascii_domain_name()?;
+        validate(&qname)?;
+        let pkt = DNSPacket::query(0, DNSQuestion::new(qname, qtype as u16));
+        let id = self.begin(None, pkt, None, time::Instant::now())?;
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let deadline = time::Instant::now() + RESOLVE_TIMEOUT;
+        loop {
+            if let Some(pkt) = self.resolved.remove(&id) {
+                return Ok(pkt);
+            }
+            if time::Instant::now() >= deadline {
+                self.id_map.remove(&id);
+                return Err(RdnsError::UpstreamTimeout(name.to_string()));
+            }
+            self.poll(&mut buf)?;
+        }
+    }
+
+    /// Runs the maintenance tick if due, waits for and handles one packet,
+    /// then sends again the upstream queries that timed out
+    pub(crate) fn poll(&mut self, buf: &mut [u8]) -> Result<()> {
+        if let Some(interval) = self.config.read_timeout {
+            if self.last_tick.elapsed() >= interval {
+                self.tick()?;
+                self.last_tick = time::Instant::now();
+            }
+        }
+        if let Some((num_read, client)) = self.recv(buf)? {
+            self.handle(&buf[..num_read], client)?;
+        }
+        self.retransmit()
+    }
+
+    /// Sends again the upstream queries unanswered for `query_timeout`. Once
+    /// the retries are used up, the server is treated as lame and another
+    /// nameserver of the zone is asked, if any is left
+    fn retransmit(&mut self) -> Result<()> {
+        let timeout = self.config.query_timeout;
+        let expired: Vec<u16> = self
+            .id_map
+            .iter()
+            .filter(|(_, x)| x.packet_stack.last().unwrap().sent.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let retries = self.pending_mut(id).retries;
+            if retries >= self.config.retries {
+                self.next_server(id)?;
+                continue;
+            }
+            trace!(
+                "no response from {}, asking again",
+                self.pending_mut(id).server
+            );
+            self.send_pending(id)?;
+            self.pending_mut(id).retries = retries + 1;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, buf: &[u8], client: Client) -> Result<()> {
+        let from_addr = client.addr();
+        let started = time::Instant::now();
+        let mut received = match DNSPacket::from_raw(buf) {
+            Ok(x) => x,
+            Err(_) if !self.serve_clients => return Ok(()),
+            Err(_) => return self.format_error(buf, &client),
+        };
+        if received.header.is_query() && !self.serve_clients {
+            return Ok(());
+        }
+        let id = received.id();
+        if DNSOpcode::from_num(received.header.opcode) == DNSOpcode::Notify {
+            return self.handle_notify(&received, &client);
+        }
+        // if is the response to one of our upstream queries
+        if !received.header.is_query() {
+            let original = match self.id_map.get(&id) {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            // an answer from anywhere else is spoofed or stale
+            let pending = original.packet_stack.last().unwrap();
+            let on_its_socket = match client {
+                Client::Upstream(x, _) => x == id,
+                Client::Udp(_) => pending.socket.is_none(),
+                _ => false,
+            };
+            if !on_its_socket || from_addr.ip() != pending.server {
+                return Ok(());
+            }
+            // so is one that does not echo the casing of the question, if
+            // it may carry records to cache
+            let echoed =
+                received
+                    .questions
+                    .first()
+                    .is_some_and(|x| match self.config.case_randomization {
+                        true => x.qname == pending.sent_qname,
+                        false => x.qname.eq_ignore_case(&pending.sent_qname),
+                    });
+            let rcode = received.header.rcode;
+            if !echoed && (rcode == DNSRcode::Normal as u8 || rcode == DNSRcode::NameError as u8) {
+                return Ok(());
+            }
+            // records for the name asked about get back the client's case
+            let (sent, qname) = (pending.sent_qname.clone(), pending.qname());
+            for x in received.questions.iter_mut().map(|x| &mut x.qname).chain(
+                received
+                    .answers
+                    .iter_mut()
+                    .chain(&mut received.authorities)
+                    .chain(&mut received.additionals)
+                    .map(|x| &mut x.name),
+            ) {
+                if *x == sent {
+                    x.clone_from(&qname);
+                }
+            }
+            trace!(
+                "response from {} for {}, rcode {}",
+                from_addr,
+                describe(&received),
+                received.header.rcode
+            );
+            let (server, rtt) = (pending.server, pending.sent.elapsed());
+            self.record_rtt(server, rtt);
+            // the full response only fits over TCP, ask again over it
+            // unless that is where this one came from
+            let pending = self.pending_mut(id);
+            if received.header.tc == 1 && pending.tcp_server != Some(server) {
+                trace!("response from {} truncated, asking over TCP", from_addr);
+                pending.tcp_server = Some(server);
+                return self.send_pending(id);
+            }
+            let original = &self.id_map[&id];
+            // anything but a referral ends the minimized query
+            let referral = received.header.rcode == DNSRcode::Normal as u8
+                && received.answers.is_empty()
+                && referral_zone(&received).is_some();
+            if original.packet_stack.last().unwrap().minimized.is_some() && !referral {
+                return self.descend(id, &received);
+            }
+            // the client must not see the upstream refusing us, another
+            // server is asked, up to SERVFAIL once none is left
+            let rcode = received.header.rcode;
+            if rcode == DNSRcode::Refused as u8 || rcode == DNSRcode::ServerFailure as u8 {
+                return self.next_server(id);
+            }
+            // a non-authoritative answer means the server is lame,
+            // unless it is the only one we know of
+            if !received.answers.is_empty()
+                && received.header.aa == 0
+                && !original.packet_stack.last().unwrap().candidates.is_empty()
+            {
+                return self.next_server(id);
+            }
+            // if has answer
+            if !received.answers.is_empty() {
+                // if is the answer to a self-generated query for NS information
+                if original.packet_stack.len() > 1 {
+                    if is_key_lookup(&original.packet_stack.last().unwrap().packet) {
+                        return self.keys_fetched(id, received.answers);
+                    }
+                    let addr: IpAddr = match received.answers[0].rdata.as_ref() {
+                        DNSRdata::A(ip) => (*ip).into(),
+                        DNSRdata::Aaaa(ip) => (*ip).into(),
+                        // must be an address record
+                        _ => return self.fail(id, DNSRcode::ServerFailure),
+                    };
+                    let data = self.id_map.get_mut(&id).unwrap();
+                    data.packet_stack.pop();
+                    data.packet_stack.last_mut().unwrap().server = addr;
+                    return self.send_pending(id);
+                }
+                // if is the answer to the original query. Only what the
+                // server is authoritative for is believed, the rest of a
+                // CNAME chain is asked of the servers of its zone
+                let zone = &original.packet_stack[0].zone;
+                received.answers.retain(|x| x.name.is_subdomain_of(zone));
+                if received.answers.is_empty() {
+                    return self.fail(id, DNSRcode::ServerFailure);
+                }
+                synthesize_cnames(&mut received);
+                received.normalize_ttls();
+                return self.check_answer(id, received);
+            }
+            // if no answer
+            if received.is_negative() {
+                let lookup = &original.packet_stack.last().unwrap().packet;
+                let question = &lookup.questions[0];
+                // no DS records for a zone means it is not signed
+                if original.packet_stack.len() > 1 && is_key_lookup(lookup) {
+                    return self.keys_fetched(id, vec![]);
+                }
+                if original.packet_stack.len() > 1
+                    && received.header.rcode == DNSRcode::Normal as u8
+                    && question.qtype == DNSType::A as u16
+                    && self.is_usable_addr(&Ipv6Addr::UNSPECIFIED.into())
+                {
+                    // the nameserver may have an IPv6 address only
+                    let name = question.qname.to_domain_name();
+                    self.id_map.get_mut(&id).unwrap().packet_stack.pop();
+                    self.query_for(id, &name, DNSType::AAAA)?;
+                } else if original.packet_stack.len() > 1 {
+                    // the nameserver we were looking up does not exist,
+                    // another one of the zone may
+                    self.fail(id, DNSRcode::ServerFailure)?;
+                } else {
+                    // relay as is, keeping the SOA for negative caching
+                    self.cache_negative(&received);
+                    self.reply(id, &received)?;
+                }
+                return Ok(());
+            }
+            let (zone, addrs) = match self.check_for_ns_addr(&received) {
+                // if is empty, then just return the record
+                Right(names) if names.is_empty() => return self.reply(id, &received),
+                x => (referral_zone(&received).unwrap(), x),
+            };
+            // a referral must lead below the zone we asked about,
+            // otherwise the server is lame
+            let pending = original.packet_stack.last().unwrap();
+            if zone.eq_ignore_case(&pending.zone) || !zone.is_subdomain_of(&pending.zone) {
+                return self.next_server(id);
+            }
+            let data = self.id_map.get_mut(&id).unwrap();
+            data.delegations += 1;
+            if data.delegations > self.config.max_delegations {
+                return self.fail(id, DNSRcode::ServerFailure);
+            }
+            self.cache_referral(&zone, &received, &addrs);
+            let (mut known, mut names) = match addrs {
+                Left((ips, names)) => (ips, names),
+                Right(names) => (vec![], names),
+            };
+            // skip resolving a nameserver if its address is cached, those
+            // without one are looked up once the others fail
+            known.extend(names.iter().filter_map(|x| self.cached_ns_addr(x)));
+            known.retain(|ip| !self.is_lame(ip, &zone));
+            names.retain(|x| self.cached_ns_addr(x).is_none());
+            let pending = self.pending_mut(id);
+            pending.zone = zone;
+            pending.ns_names = names;
+            if known.is_empty() {
+                pending.candidates = vec![];
+                return self.query_next_ns(id);
+            }
+            let i = self.pick_server(&known);
+            let pending = self.pending_mut(id);
+            pending.server = known.swap_remove(i);
+            pending.candidates = known;
+            return self.send_pending(id);
+        }
+        // new query, which only clients send
+        if !received.answers.is_empty() || matches!(client, Client::Upstream(..)) {
+            return Ok(());
+        }
+        // a query has exactly one question (RFC 9619), the rest of the
+        // resolution relies on it
+        if received.questions.len() != 1 {
+            return self.error(&mut received, DNSRcode::FormatError, &client);
+        }
+        debug!("query for {} from {}", describe(&received), from_addr);
+        // the keepalive option is only for TCP, and without a timeout from
+        // clients (RFC 7828 section 3.2.1)
+        if let Some(option) = keepalive_option(&received) {
+            if matches!(client, Client::Udp(_)) || !option.data.is_empty() {
+                return self.error(&mut received, DNSRcode::FormatError, &client);
+            }
+        }
+        // zone transfers are not served, over any transport
+        if let Some(q) = received.questions.first() {
+            if q.qtype == DNSType::AXFR as u16 || q.qtype == DNSType::IXFR as u16 {
+                return self.error(&mut received, DNSRcode::Refused, &client);
+            }
+        }
+        if let Some(pkt) = self.hosts_answer(&received) {
+            self.respond(&client, QueryInfo::of(&received), &pkt);
+            self.log_query(&from_addr, &received, 0, Source::Hosts, started);
+            return Ok(());
+        }
+        if let Some((target, ttl)) = self.zone_alias(&received) {
+            let question = received.questions[0].clone();
+            let target_question = DNSQuestion::new(target, question.qtype);
+            if let Some(answers) = self.cached_answer(&target_question) {
+                let mut pkt = DNSPacket::new(received.id(), false);
+                pkt.questions = vec![target_question];
+                pkt.answers = answers;
+                let pkt = alias_answer(&pkt, &question, ttl);
+                self.respond(&client, QueryInfo::of(&received), &pkt);
+                self.log_query(&from_addr, &received, 0, Source::Zone, started);
+                return Ok(());
+            }
+            debug!("resolving the ALIAS target of {}", describe(&received));
+            received.questions[0] = target_question;
+            self.begin(Some(client), received, Some((question, ttl)), started)?;
+            return Ok(());
+        }
+        if let Some(pkt) = self.zone_answer(&received) {
+            self.respond(&client, QueryInfo::of(&received), &pkt);
+            self.log_query(
+                &from_addr,
+                &received,
+                pkt.header.rcode,
+                Source::Zone,
+                started,
+            );
+            return Ok(());
+        }
+        if let Some(pkt) = self.blocked_answer(&received) {
+            self.respond(&client, QueryInfo::of(&received), &pkt);
+            let rcode = pkt.header.rcode;
+            self.log_query(&from_addr, &received, rcode, Source::Blocklist, started);
+            return Ok(());
+        }
+        // check cache
+        let question = &received.questions[0];
+        if let Some(answers) = self.cached_answer(question) {
+            self.respond(
+                &client,
+                QueryInfo::of(&received),
+                &DNSPacket {
+                    header: DNSHeader::new(received.id(), false),
+                    questions: vec![question.clone()],
+                    answers,
+                    authorities: vec![],
+                    additionals: vec![],
+                },
+            );
+            debug!("cache hit for {}", describe(&received));
+            self.log_query(&from_addr, &received, 0, Source::Cache, started);
+            return Ok(());
+        }
+        if let Some(pkt) = self.negative_answer(&received) {
+            self.respond(&client, QueryInfo::of(&received), &pkt);
+            self.log_query(
+                &from_addr,
+                &received,
+                pkt.header.rcode,
+                Source::Cache,
+                started,
+            );
+            return Ok(());
+        }
+        debug!("cache miss for {}, resolving", describe(&received));
+        self.begin(Some(client), received, None, started)?;
+        Ok(())
+    }
+
+    /// Caches the RRsets in the answer section of `received`. A TTL of 0
+    /// means the records are only good for this answer
+    fn cache_answers(&self, received: &DNSPacket) {
+        let mut rrsets: HashMap<CacheKey, Vec<DNSResourceRecord>> = HashMap::new();
+        for ans in received.answers.iter().filter(|x| x.ttl > 0) {
+            rrsets
+                .entry((ans.class, ans.r#type, ans.name.canonical()))
+                .or_default()
+                .push(ans.clone());
+        }
+        let now = self.now();
+        let mut cache = self.shared.cache.lock().unwrap();
+        for (key, records) in rrsets {
+            // the TTLs of an RRset were made equal by `normalize_ttls`
+            let ttl = records[0]
+                .ttl
+                .max(self.config.min_ttl)
+                .min(self.config.max_ttl);
+            let expiration = now + Duration::seconds(ttl as i64);
+            let evicted = cache.insert(
+                key,
+                RdnsCacheEntry {
+                    expiration,
+                    records,
+                },
+            );
+            self.note_eviction("record", evicted.map(|(key, _)| key));
+        }
+    }
+
+    /// Logs and counts the entry of `cache` evicted by an insertion, if any
+    fn note_eviction(&self, cache: &str, key: Option<CacheKey>) {
+        if let Some(key) = key {
+            debug!(target: "rdns::cache", "{} entry for {} evicted, the cache is full", cache, describe_key(&key));
+            self.shared.metrics.lock().unwrap().cache_evictions += 1;
+        }
+    }
+
+    /// The answer to `question` from the cache: the CNAMEs cached for its
+    /// name, in the order they are followed, then the RRset asked for.
+    /// `None` unless the whole chain is cached
+    fn cached_answer(&self, question: &DNSQuestion) -> Option<Vec<DNSResourceRecord>> {
+        let now = self.now();
+        let mut cache = self.shared.cache.lock().unwrap();
+        let mut answers = vec![];
+        let mut name = question.qname.canonical();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let mut key = (question.qclass, question.qtype, name);
+            let fresh = |cache: &DnsCache<RdnsCacheEntry>, key: &CacheKey| {
+                cache.peek(key).is_some_and(|x| now < x.expiration)
+            };
+            if !fresh(&cache, &key) && question.qtype != DNSType::CNAME as u16 {
+                key.1 = DNSType::CNAME as u16;
+            }
+            if !fresh(&cache, &key) {
+                return None;
+            }
+            let entry = cache.get(&key).unwrap();
+            let ttl = (entry.expiration - now).num_seconds() as u32;
+            let ttl = ttl.max(self.config.min_answer_ttl);
+            answers.extend(
+                entry
+                    .records
+                    .iter()
+                    .map(|x| DNSResourceRecord { ttl, ..x.clone() }),
+            );
+            if key.1 == question.qtype {
+                return Some(answers);
+            }
+            name = match entry.records[0].rdata.as_ref() {
+                DNSRdata::Cname(target) => target.canonical(),
+                _ => return None,
+            };
+        }
+        None
+    }
+
+    /// The response to an A or AAAA `query` for a name of the hosts file,
+    /// with its addresses of the family asked for, or to a PTR `query` for
+    /// one of its addresses, if any
+    fn hosts_answer(&self, query: &DNSPacket) -> Option<DNSPacket> {
+        let question = query.questions.first()?;
+        if question.qclass != DNSClass::IN as u16 {
+            return None;
+        }
+        let mut pkt = DNSPacket::new(query.id(), false);
+        pkt.header.aa = 1;
+        pkt.questions = vec![question.clone()];
+        if question.qtype == DNSType::PTR as u16 {
+            let names = self
+                .hosts
+                .reverse_lookup(&question.qname.to_domain_name())?;
+            for name in names {
+                pkt.answers.push(DNSResourceRecord {
+                    name: question.qname.clone(),
+                    r#type: question.qtype,
+                    class: question.qclass,
+                    ttl: self.config.hosts_ttl,
+                    rdata: Arc::new(DNSRdata::Ptr(name.clone().to_domain_name())),
+                });
+            }
+            return Some(pkt);
+        }
+        if question.qtype != DNSType::A as u16 && question.qtype != DNSType::AAAA as u16 {
+            return None;
+        }
+        let addrs = self.hosts.lookup(&question.qname.to_domain_name())?;
+        for ip in addrs {
+            let rdata = match (ip, DNSType::from_num(question.qtype)) {
+                (IpAddr::V4(ip), DNSType::A) => DNSRdata::A(*ip),
+                (IpAddr::V6(ip), DNSType::AAAA) => DNSRdata::Aaaa(*ip),
+                _ => continue,
+            };
+            pkt.answers.push(DNSResourceRecord {
+                name: question.qname.clone(),
+                r#type: question.qtype,
+                class: question.qclass,
+                ttl: self.config.hosts_ttl,
+                rdata: Arc::new(rdata),
+            });
+        }
+        Some(pkt)
+    }
+
+    /// The response to `query` from the most specific local zone it is in,
+    /// if any
+    fn zone_answer(&self, query: &DNSPacket) -> Option<DNSPacket> {
+        let question = query.questions.first()?;
+        if question.qclass != DNSClass::IN as u16 {
+            return None;
+        }
+        let zones = self.shared.zones.lock().unwrap();
+        let zone = zones
+            .iter()
+            .filter(|x| x.contains(&question.qname))
+            .max_by_key(|x| x.apex().len())?;
+        Some(zone.answer(query.id(), question))
+    }
+
+    /// The EDNS options of `pkt` to pass through to the other side, when
+    /// forwarding with `edns_passthrough`. The keepalive option is about
+    /// the connection it came over, it never is
+    fn passed_options(&self, pkt: &DNSPacket) -> Vec<EdnsOption> {
+        if self.config.forward.is_none() || !self.config.edns_passthrough {
+            return vec![];
+        }
+        pkt.additionals
+            .iter()
+            .find_map(|x| match x.rdata.as_ref() {
+                DNSRdata::Opt { options, .. } => Some(options.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| x.code != EDNS_TCP_KEEPALIVE)
+            .collect()
+    }
+
+    /// Target and TTL of the ALIAS record answering `query`, if it asks for
+    /// the addresses of the apex of a local zone that has one
+    fn zone_alias(&self, query: &DNSPacket) -> Option<(DomainName, u32)> {
+        let question = query.questions.first()?;
+        if question.qclass != DNSClass::IN as u16 {
+            return None;
+        }
+        self.shared
+            .zones
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|x| x.alias(question))
+            .map(|(target, ttl)| (target.clone(), ttl))
+    }
+
+    /// The response to `query` for a blocked name, if it is
+    fn blocked_answer(&self, query: &DNSPacket) -> Option<DNSPacket> {
+        let question = query.questions.first()?;
+        if question.qclass != DNSClass::IN as u16 {
+            return None;
+        }
+        let response = self.blocklist.response(
+            &question.qname,
+            self.config.block_subdomains,
+            self.config.block_response,
+        )?;
+        let ttl = self.config.block_ttl;
+        let sinkhole = |v6: bool| {
+            self.config
+                .block_sinkhole
+                .iter()
+                .find(|x| x.is_ipv6() == v6)
+                .copied()
+        };
+        // the address given for the type asked for, if any
+        let addr = match (response, DNSType::from_num(question.qtype)) {
+            (BlockResponse::Null, DNSType::A) => Some(Ipv4Addr::UNSPECIFIED.into()),
+            (BlockResponse::Null, DNSType::AAAA) => Some(Ipv6Addr::UNSPECIFIED.into()),
+            (BlockResponse::Sinkhole, DNSType::A) => sinkhole(false),
+            (BlockResponse::Sinkhole, DNSType::AAAA) => sinkhole(true),
+            _ => None,
+        };
+        let mut pkt = DNSPacket::new(query.id(), false);
+        pkt.questions = vec![question.clone()];
+        match (response, addr) {
+            (BlockResponse::NxDomain, _) => pkt.header.set_rcode(DNSRcode::NameError),
+            (BlockResponse::Refused, _) => pkt.header.set_rcode(DNSRcode::Refused),
+            (_, Some(addr)) => pkt.answers.push(DNSResourceRecord {
+                name: question.qname.clone(),
+                r#type: question.qtype,
+                class: question.qclass,
+                ttl,
+                rdata: Arc::new(match addr {
+                    IpAddr::V4(ip) => DNSRdata::A(ip),
+                    IpAddr::V6(ip) => DNSRdata::Aaaa(ip),
+                }),
+            }),
+            // NODATA, with the SOA a negative answer needs to be cached
+            (_, None) => pkt
+                .authorities
+                .push(zone::synthesize_soa(&question.qname, ttl, ttl)),
+        }
+        Some(pkt)
+    }
+
+    /// Caches a negative answer to a client's query for as long as its SOA
+    /// allows (RFC 2308 section 5). Answers without an SOA are not cached
+    fn cache_negative(&mut self, pkt: &DNSPacket) {
+        let (question, soa) = match (
+            pkt.questions.first(),
+            pkt.authorities
+                .iter()
+                .find(|x| x.r#type == DNSType::SOA as u16),
+        ) {
+            (Some(q), Some(soa)) => (q, soa),
+            _ => return,
+        };
+        let minimum = match soa.rdata.as_ref() {
+            DNSRdata::Soa { minimum, .. } => *minimum,
+            _ => return,
+        };
+        let ttl = soa.ttl.min(minimum).min(self.config.negative_max_ttl);
+        if ttl == 0 {
+            return;
+        }
+        let mut soa = soa.clone();
+        soa.ttl = ttl;
+        let evicted = self.shared.negative_cache.lock().unwrap().insert(
+            (question.qclass, question.qtype, question.qname.canonical()),
+            NegativeCacheEntry {
+                expiration: self.now() + Duration::seconds(ttl as i64),
+                rcode: pkt.header.rcode,
+                soa,
+            },
+        );
+        self.note_eviction("negative", evicted.map(|(key, _)| key));
+    }
+
+    /// The response to `query` from the negative cache, if any
+    fn negative_answer(&mut self, query: &DNSPacket) -> Option<DNSPacket> {
+        let question = query.questions.first()?;
+        let key = (question.qclass, question.qtype, question.qname.canonical());
+        let now = self.now();
+        let mut negative_cache = self.shared.negative_cache.lock().unwrap();
+        let entry = negative_cache.get(&key)?;
+        if now >= entry.expiration {
+            negative_cache.remove(&key);
+            return None;
+        }
+        let mut soa = entry.soa.clone();
+        soa.ttl = (entry.expiration - now).num_seconds() as u32;
+        let mut pkt = DNSPacket::new(query.id(), false);
+        pkt.header.rcode = entry.rcode;
+        pkt.questions = vec![question.clone()];
+        pkt.authorities = vec![soa];
+        Some(pkt)
+    }
+
+    /// Answers a query that could not be parsed with FORMERR, if at least
+    /// its header is there. Malformed responses are dropped
+    fn format_error(&mut self, buf: &[u8], client: &Client) -> Result<()> {
+        let header = match DNSHeader::peek(buf) {
+            Some(x) if x.is_query() => x,
+            _ => return Ok(()),
+        };
+        let mut pkt = DNSPacket::new(header.id, false);
+        pkt.header.opcode = header.opcode;
+        pkt.header.set_rcode(DNSRcode::FormatError);
+        let info = QueryInfo {
+            rd: header.rd,
+            edns: None,
+            keepalive: false,
+            dnssec_ok: false,
+            ad: false,
+        };
+        self.respond(client, info, &pkt);
+        Ok(())
+    }
+
+    /// Acknowledges a NOTIFY (RFC 1996) from a configured primary, and has
+    /// the local zone it is about brought up to date from it
+    fn handle_notify(&mut self, received: &DNSPacket, client: &Client) -> Result<()> {
+        if !received.header.is_query() {
+            return Ok(());
+        }
+        let mut pkt = DNSPacket::new(received.id(), false);
+        pkt.header.opcode = DNSOpcode::Notify as u8;
+        pkt.questions = received.questions.clone();
+        if !self.config.primaries.contains(&client.addr().ip()) {
+            pkt.header.set_rcode(DNSRcode::Refused);
+        } else if let Some(question) = received.questions.first() {
+            self.refresh_zone(&question.qname, client.addr().ip());
+        }
+        self.respond(client, QueryInfo::of(received), &pkt);
+        Ok(())
+    }
+
+    /// Transfers the changes to the local zone whose apex is `apex`, if
+    /// any, from `primary` on a thread of its own, so that the queries are
+    /// still answered meanwhile, from the version the zone was at
+    fn refresh_zone(&self, apex: &DomainName, primary: IpAddr) {
+        let soa = match self
+            .shared
+            .zones
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|x| x.apex().eq_ignore_case(apex))
+        {
+            Some(zone) => zone.soa().clone(),
+            None => return,
+        };
+        let zones = self.shared.zones.clone();
+        let primary = SocketAddr::new(primary, self.config.upstream_port);
+        thread::spawn(move || {
+            let res = transfer::request(primary, &soa).and_then(|transfer| {
+                let mut zones = zones.lock().unwrap();
+                // the zone is there as long as the resolver
+                let zone = zones
+                    .iter_mut()
+                    .find(|x| x.apex().eq_ignore_case(&soa.name))
+                    .unwrap();
+                zone.apply(transfer)?;
+                Ok(soa_serial(zone.soa()).unwrap())
+            });
+            match res {
+                Ok(serial) => info!("zone {} is at serial {}", soa.name.to_domain_name(), serial),
+                Err(e) => warn!(
+                    "failed to transfer zone {} from {}: {}",
+                    soa.name.to_domain_name(),
+                    primary,
+                    e
+                ),
+            }
+        });
+    }
+
+    /// Validates `received`, the answer to the query of `id`, if it is to
+    /// be, then caches it and goes on with the CNAME it may lead to or
+    /// answers the client. Bogus answers get SERVFAIL, those that need
+    /// the keys of a zone wait for them to be looked up
+    fn check_answer(&mut self, id: u16, received: DNSPacket) -> Result<()> {
+        let data = &self.id_map[&id];
+        if data.validate {
+            match self.validate_answer(id, &received.answers) {
+                Validation::Done(Verdict::Secure) => {}
+                Validation::Done(Verdict::Insecure) => {
+                    self.id_map.get_mut(&id).unwrap().secure = false;
+                }
+                Validation::Done(Verdict::Bogus(why)) => {
+                    info!("answer for {} is bogus: {}", describe(&received), why);
+                    return self.fail(id, DNSRcode::ServerFailure);
+                }
+                Validation::Fetch(zone, qtype) => {
+                    trace!("looking up {} {} to validate", zone.to_domain_name(), qtype);
+                    self.id_map.get_mut(&id).unwrap().unverified = Some(received);
+                    return self.query_for(id, &zone.to_domain_name(), qtype);
+                }
+            }
+        }
+        // answers a client chose not to have validated are not for the
+        // others, who expect them to be
+        if self.id_map[&id].validate || self.config.dnssec != Dnssec::Always {
+            self.cache_answers(&received);
+        }
+        if let Some(target) = cname_target(&received) {
+            return self.follow_cname(id, &received, target);
+        }
+        self.reply(id, &received)
+    }
+
+    /// Takes `records`, the answer to the lookup of DNSKEY or DS records
+    /// on top of the stack of `id`, and goes on validating the answer that
+    /// waits on them
+    fn keys_fetched(&mut self, id: u16, mut records: Vec<DNSResourceRecord>) -> Result<()> {
+        let data = self.id_map.get_mut(&id).unwrap();
+        let lookup = data.packet_stack.pop().unwrap();
+        let question = &lookup.packet.questions[0];
+        records.retain(|x| x.name.eq_ignore_case(&question.qname));
+        data.fetched
+            .insert((question.qname.canonical(), question.qtype), records);
+        match data.unverified.take() {
+            Some(received) => self.check_answer(id, received),
+            None => self.fail(id, DNSRcode::ServerFailure),
+        }
+    }
+
+    /// Validates `answers`, from the servers of the zone the query of `id`
+    /// is at. Every RRset must be signed by that zone or one below it,
+    /// whose keys are trusted, unless the zone is not signed. CNAMEs
+    /// synthesized from a DNAME are as good as it
+    fn validate_answer(&mut self, id: u16, answers: &[DNSResourceRecord]) -> Validation {
+        let zone = self.id_map[&id].packet_stack[0].zone.clone();
+        let now = self.now().timestamp() as u32;
+        let mut verdict = Verdict::Secure;
+        for (rrset, sigs) in dnssec::rrsets(answers) {
+            let owner = &rrset[0].name;
+            let synthesized = rrset[0].r#type == DNSType::CNAME as u16
+                && answers.iter().any(|x| {
+                    x.r#type == DNSType::DNAME as u16
+                        && owner.len() > x.name.len()
+                        && owner.is_subdomain_of(&x.name)
+                });
+            if synthesized && sigs.is_empty() {
+                continue;
+            }
+            // unsigned records of a signed zone are bogus as well
+            let signer = sigs
+                .iter()
+                .find_map(|x| match x.rdata.as_ref() {
+                    DNSRdata::Rrsig { signer, .. }
+                        if owner.is_subdomain_of(signer) && signer.is_subdomain_of(&zone) =>
+                    {
+                        Some(signer.clone())
+                    }
+                    _ => None,
+                })
+                .unwrap_or_else(|| zone.clone());
+            let keys = match self.zone_keys(id, &signer) {
+                Ok(x) => x,
+                Err(Validation::Done(Verdict::Insecure)) => {
+                    verdict = Verdict::Insecure;
+                    continue;
+                }
+                Err(x) => return x,
+            };
+            if !dnssec::verify_rrset(&rrset, &sigs, &keys, now) {
+                return Validation::Done(Verdict::Bogus(format!(
+                    "no valid signature over {} {}",
+                    owner.to_domain_name(),
+                    DNSType::from_num(rrset[0].r#type)
+                )));
+            }
+        }
+        Validation::Done(verdict)
+    }
+
+    /// The trusted DNSKEY records of `zone`, for validating an answer of
+    /// the query of `id`: those a trust anchor is the digest of, or else
+    /// those the parent zone has DS records for, signed with its own
+    /// trusted keys. A zone without either is not signed
+    fn zone_keys(
+        &mut self,
+        id: u16,
+        zone: &DomainName,
+    ) -> core::result::Result<Vec<DNSResourceRecord>, Validation> {
+        let now = self.now();
+        let key = zone.canonical();
+        if let Some((expiration, keys)) = self.keys.get(&key) {
+            if now < *expiration {
+                return Ok(keys.clone());
+            }
+        }
+        let secs = now.timestamp() as u32;
+        let anchors: Vec<DNSResourceRecord> = self
+            .config
+            .trust_anchors
+            .iter()
+            .filter(|x| x.name.eq_ignore_case(zone))
+            .cloned()
+            .collect();
+        let ds = match anchors.is_empty() {
+            false => anchors,
+            // a private root without an anchor
+            true if zone.is_empty() => return Err(Validation::Done(Verdict::Insecure)),
+            true => {
+                let (ds, sigs): (Vec<_>, Vec<_>) = self
+                    .delegation_ds(id, zone)
+                    .ok_or_else(|| Validation::Fetch(zone.clone(), DNSType::DS))?
+                    .into_iter()
+                    .partition(|x| x.r#type == DNSType::DS as u16);
+                if ds.is_empty() {
+                    return Err(Validation::Done(Verdict::Insecure));
+                }
+                let bogus = || {
+                    Validation::Done(Verdict::Bogus(format!(
+                        "DS of {} is not signed by its parent",
+                        zone.to_domain_name()
+                    )))
+                };
+                let parent = sigs
+                    .iter()
+                    .find_map(|x| match x.rdata.as_ref() {
+                        DNSRdata::Rrsig { signer, .. }
+                            if zone.len() > signer.len() && zone.is_subdomain_of(signer) =>
+                        {
+                            Some(signer.clone())
+                        }
+                        _ => None,
+                    })
+                    .ok_or_else(bogus)?;
+                let parent_keys = self.zone_keys(id, &parent)?;
+                if !dnssec::verify_rrset(&ds, &sigs, &parent_keys, secs) {
+                    return Err(bogus());
+                }
+                ds
+            }
+        };
+        let keyset = self.id_map[&id]
+            .fetched
+            .get(&(key.clone(), DNSType::DNSKEY as u16))
+            .cloned()
+            .ok_or_else(|| Validation::Fetch(zone.clone(), DNSType::DNSKEY))?;
+        let keys = dnssec::trusted_keys(zone, &keyset, &ds, secs).map_err(Validation::Done)?;
+        let ttl = keyset.iter().map(|x| x.ttl).min().unwrap_or(0);
+        let ttl = ttl.min(self.config.max_ttl);
+        self.keys
+            .insert(key, (now + Duration::seconds(ttl as i64), keys.clone()));
+        Ok(keys)
+    }
+
+    /// The DS records of `zone` and their signatures, from the referral
+    /// to it or looked up for the query of `id`. `None` if neither
+    /// happened yet
+    fn delegation_ds(&self, id: u16, zone: &DomainName) -> Option<Vec<DNSResourceRecord>> {
+        let now = self.now();
+        let key = (DNSClass::IN as u16, DNSType::NS as u16, zone.canonical());
+        if let Some(entry) = self.shared.ns_cache.lock().unwrap().peek(&key) {
+            if now < entry.expiration {
+                return Some(entry.ds.clone());
+            }
+        }
+        self.id_map[&id]
+            .fetched
+            .get(&(zone.canonical(), DNSType::DS as u16))
+            .cloned()
+    }
+
+    /// Resolves `target` in place of the name asked for, the answer to
+    /// which being `pkt`, a CNAME. The client gets both answers in the end
+    fn follow_cname(&mut self, id: u16, pkt: &DNSPacket, target: DomainName) -> Result<()> {
+        let (zone, server, candidates, ns_names) = self.start_point(&target)?;
+        let minimize = self.minimizes();
+        let data = self.id_map.get_mut(&id).unwrap();
+        data.chain.extend(pkt.answers.iter().cloned());
+        let cnames = data
+            .chain
+            .iter()
+            .filter(|x| x.r#type == DNSType::CNAME as u16);
+        if cnames.clone().count() > MAX_CNAME_CHAIN
+            || cnames.clone().any(|x| x.name.eq_ignore_case(&target))
+        {
+            return self.fail(id, DNSRcode::ServerFailure);
+        }
+        let pending = &mut data.packet_stack[0];
+        pending.packet.questions[0].qname = target;
+        pending.zone = zone;
+        pending.server = server;
+        pending.candidates = candidates;
+        pending.ns_names = ns_names;
+        pending.minimized = None;
+        pending.minimize = minimize;
+        self.send_pending(id)
+    }
+
+    /// Starts resolving `query` from a root server under an id of its own,
+    /// which is returned. `alias` is for a query for the target of an ALIAS
+    /// record, see `RdnsData::alias`
+    fn begin(
+        &mut self,
+        client: Option<Client>,
+        mut query: DNSPacket,
+        alias: Option<(DNSQuestion, u32)>,
+        started: time::Instant,
+    ) -> Result<u16> {
+        // the client's id is predictable and may clash with other clients'
+        let id = loop {
+            let id = (0..u16::MAX).rand();
+            if !self.id_map.contains_key(&id) && !self.resolved.contains_key(&id) {
+                break id;
+            }
+        };
+        let client_id = query.id();
+        let info = QueryInfo::of(&query);
+        query.header.id = id;
+        // the forwarder does the recursion
+        if self.config.forward.is_some() {
+            query.header.set_rd(true);
+        }
+        let options = self.passed_options(&query);
+        query
+            .additionals
+            .retain(|x| x.r#type != DNSType::OPT as u16);
+        // the records to validate with are asked for whether or not this
+        // answer is validated, the client may want them
+        let dnssec_ok = self.config.dnssec != Dnssec::Off;
+        query.additionals.push(opt_with(options, dnssec_ok));
+        let validate = !query.header.cd()
+            && match self.config.dnssec {
+                Dnssec::Off => false,
+                Dnssec::Requested => info.dnssec_ok || info.ad,
+                Dnssec::Always => true,
+            };
+        let minimize = self.minimizes();
+        let (zone, server, candidates, ns_names) = self.start_point(&query.questions[0].qname)?;
+        self.id_map.insert(
+            id,
+            RdnsData {
+                client,
+                client_id,
+                info,
+                packet_stack: vec![PendingQuery {
+                    packet: query,
+                    zone,
+                    server,
+                    candidates,
+                    ns_names,
+                    minimized: None,
+                    minimize,
+                    sent_qname: vec![],
+                    socket: None,
+                    tcp_server: None,
+                    conn: None,
+                    sent: time::Instant::now(),
+                    retries: 0,
+                }],
+                visited: HashSet::new(),
+                delegations: 0,
+                chain: vec![],
+                alias,
+                validate,
+                secure: true,
+                fetched: HashMap::new(),
+                unverified: None,
+                started,
+            },
+        );
+        self.send_pending(id)?;
+        Ok(id)
+    }
+
+    fn pending_mut(&mut self, id: u16) -> &mut PendingQuery {
+        self.id_map
+            .get_mut(&id)
+            .unwrap()
+            .packet_stack
+            .last_mut()
+            .unwrap()
+    }
+
+    /// Sends the innermost query of `id` to its current server
+    fn send_pending(&mut self, id: u16) -> Result<()> {
+        let randomize = self.config.case_randomization;
+        let pending = self.pending_mut(id);
+        pending.sent = time::Instant::now();
+        pending.retries = 0;
+        pending.socket = None;
+        pending.conn = None;
+        if pending.minimize {
+            // the zone's servers need one label more than the zone has
+            let labels = pending.packet.questions[0].qname.len();
+            let n = pending.minimized.unwrap_or(0).max(pending.zone.len() + 1);
+            pending.minimized = (n < labels).then_some(n);
+            pending.minimize = n < labels;
+        }
+        pending.sent_qname = match randomize {
+            true => randomize_case(&pending.qname()),
+            false => pending.qname(),
+        };
+        self.shared.metrics.lock().unwrap().upstream_queries += 1;
+        let pending = self.id_map[&id].packet_stack.last().unwrap();
+        let addr = self.server_addr(pending.server);
+        trace!("asking {} for {}", addr, describe(&pending.packet));
+        // the forwarder is only asked over TLS when so configured
+        let tls = match (&self.config.forward_tls, &self.forward_tls) {
+            (Some(name), Some(config)) if Some(addr) == self.config.forward => Some((name, config)),
+            _ => None,
+        };
+        if tls.is_some() || pending.tcp_server == Some(pending.server) {
+            let msg = match pending.outgoing().assemble() {
+                Ok(x) => x,
+                Err(e) => return self.unsendable(id, e),
+            };
+            let local = self.config.outbound_addr;
+            let conn = match tls {
+                Some((name, config)) => tls::connect(addr, name, config.clone(), local)
+                    .and_then(|x| UpstreamConn::new(x, addr, &msg)),
+                None => tcp::connect(addr, local).and_then(|x| UpstreamConn::new(x, addr, &msg)),
+            };
+            return match conn {
+                Ok(conn) => {
+                    self.pending_mut(id).conn = Some(conn);
+                    Ok(())
+                }
+                Err(e) => {
+                    info!("cannot reach {}: {}", addr, e);
+                    self.next_server(id)
+                }
+            };
+        }
+        match self.new_query(&pending.outgoing(), &addr) {
+            Ok(socket) => {
+                self.pending_mut(id).socket = socket;
+                Ok(())
+            }
+            // e.g. no route to an IPv6 server, try another one
+            Err(RdnsError::Io(e)) => {
+                info!("cannot reach {}: {}", addr, e);
+                self.next_server(id)
+            }
+            Err(e) => self.unsendable(id, e),
+        }
+    }
+
+    /// Gives up the query of `id` that cannot be put on the wire, such as
+    /// one for an invalid name, rather than have it sent again and again
+    fn unsendable(&mut self, id: u16, e: RdnsError) -> Result<()> {
+        let pending = self.id_map[&id].packet_stack.last().unwrap();
+        info!("cannot send {}: {}", describe(&pending.packet), e);
+        self.fail(id, DNSRcode::ServerFailure)
+    }
+
+    /// Whether new queries are to be minimized
+    fn minimizes(&self) -> bool {
+        self.config.qname_minimization && self.config.forward.is_none()
+    }
+
+    /// Goes on after a minimized query of `id` was answered with something
+    /// else than a referral: one label further down if the name exists
+    /// without being a zone of another server, otherwise with the full
+    /// question, as servers may mishandle the minimized one
+    fn descend(&mut self, id: u16, received: &DNSPacket) -> Result<()> {
+        let exists = received.header.rcode == DNSRcode::Normal as u8
+            && received
+                .answers
+                .iter()
+                .all(|x| x.r#type == DNSType::NS as u16);
+        let pending = self.pending_mut(id);
+        if exists {
+            pending.minimized = pending.minimized.map(|n| n + 1);
+        } else {
+            debug!("minimized query refused, sending the full question");
+            pending.minimized = None;
+            pending.minimize = false;
+        }
+        self.send_pending(id)
+    }
+
+    /// Where to start resolving `name`: the deepest zone above it whose
+    /// nameservers are cached, with the server to ask first, the others
+    /// and the names of those without a known address. The root, or the
+    /// forwarder, if there is none
+    fn start_point(
+        &self,
+        name: &DomainName,
+    ) -> Result<(DomainName, IpAddr, Vec<IpAddr>, Vec<String>)> {
+        if self.config.forward.is_some() {
+            return Ok((vec![], self.first_server()?, vec![], vec![]));
+        }
+        let now = self.now();
+        let ns_cache = self.shared.ns_cache.lock().unwrap();
+        for i in 0..name.len() {
+            let zone = name[i..].to_vec();
+            let entry =
+                match ns_cache.peek(&(DNSClass::IN as u16, DNSType::NS as u16, zone.canonical())) {
+                    Some(x) if now < x.expiration => x,
+                    _ => continue,
+                };
+            let mut addrs: Vec<IpAddr> = entry.addrs.clone();
+            let mut names = vec![];
+            for x in &entry.names {
+                match self.cached_ns_addr(x) {
+                    Some(ip) if !addrs.contains(&ip) => addrs.push(ip),
+                    Some(_) => {}
+                    None if entry.glueless.contains(x) => names.push(x.clone()),
+                    None => {}
+                }
+            }
+            addrs.retain(|x| self.is_usable_addr(x) && !self.is_lame(x, &zone));
+            if addrs.is_empty() {
+                continue;
+            }
+            let i = self.pick_server(&addrs);
+            let server = addrs.swap_remove(i);
+            return Ok((zone, server, addrs, names));
+        }
+        Ok((vec![], self.first_server()?, vec![], vec![]))
+    }
+
+    /// Caches the nameservers `received` refers to for `zone`, with their
+    /// addresses if it has glue for them, for as long as the shortest TTL
+    /// of its NS records
+    fn cache_referral(
+        &self,
+        zone: &DomainName,
+        received: &DNSPacket,
+        addrs: &Either<(Vec<IpAddr>, Vec<String>), Vec<String>>,
+    ) {
+        let ns: Vec<&DNSResourceRecord> = received
+            .authorities
+            .iter()
+            .filter(|x| x.r#type == DNSType::NS as u16 && x.name.eq_ignore_case(zone))
+            .collect();
+        let ttl = match ns.iter().map(|x| x.ttl).min() {
+            Some(0) | None => return,
+            Some(ttl) => ttl.max(self.config.min_ttl).min(self.config.max_ttl),
+        };
+        let names: Vec<String> = ns
+            .iter()
+            .filter_map(|x| match x.rdata.as_ref() {
+                DNSRdata::Ns(dn) => Some(dn.canonical()),
+                _ => None,
+            })
+            .collect();
+        let (addrs, glueless) = match addrs {
+            Left((ips, glueless)) => (ips.clone(), glueless.clone()),
+            Right(_) => (vec![], names.clone()),
+        };
+        let ds = received
+            .authorities
+            .iter()
+            .filter(|x| x.name.eq_ignore_case(zone))
+            .filter(|x| match x.rdata.as_ref() {
+                DNSRdata::Ds { .. } => true,
+                DNSRdata::Rrsig { type_covered, .. } => *type_covered == DNSType::DS as u16,
+                _ => false,
+            })
+            .cloned()
+            .collect();
+        let evicted = self.shared.ns_cache.lock().unwrap().insert(
+            (DNSClass::IN as u16, DNSType::NS as u16, zone.canonical()),
+            NsCacheEntry {
+                expiration: self.now() + Duration::seconds(ttl as i64),
+                names,
+                addrs,
+                glueless,
+                ds,
+            },
+        );
+        self.note_eviction("NS", evicted.map(|(key, _)| key));
+    }
+
+    fn first_server(&self) -> Result<IpAddr> {
+        match self.config.forward {
+            Some(addr) => Ok(addr.ip()),
+            None => self.root_server(),
+        }
+    }
+
+    /// A random root server of those reachable over `upstream_family`
+    fn root_server(&self) -> Result<IpAddr> {
+        let roots = match self.config.root_hints.is_empty() {
+            true => ROOT_SERVERS
+                .iter()
+                .chain(&ROOT_SERVERS_V6)
+                .map(|x| x.parse())
+                .collect::<core::result::Result<Vec<IpAddr>, _>>()?,
+            false => self.config.root_hints.clone(),
+        };
+        let roots: Vec<IpAddr> = roots
+            .into_iter()
+            .filter(|x| self.is_usable_addr(x))
+            .collect();
+        if roots.is_empty() {
+            return Err(RdnsError::Config(
+                "no root server is reachable over the upstream address family".into(),
+            ));
+        }
+        Ok(roots[(0..roots.len()).rand()])
+    }
+
+    /// Address of the DNS service on `server`
+    fn server_addr(&self, server: IpAddr) -> SocketAddr {
+        match self.config.forward {
+            Some(addr) if addr.ip() == server => addr,
+            _ => SocketAddr::new(server, self.config.upstream_port),
+        }
+    }
+
+    fn srtt(&self, server: &IpAddr) -> time::Duration {
+        // unmeasured servers go first so that they get measured
+        self.rtt
+            .get(server)
+            .map_or(time::Duration::ZERO, |x| x.srtt)
+    }
+
+    /// Index of the server to ask among `servers`: usually the fastest seen,
+    /// sometimes a random one so that a server which was slow once is not
+    /// avoided forever
+    fn pick_server(&self, servers: &[IpAddr]) -> usize {
+        if rand::random::<f64>() < RTT_PROBE_RATE {
+            return (0..servers.len()).rand();
+        }
+        (0..servers.len())
+            .min_by_key(|&i| self.srtt(&servers[i]))
+            .unwrap()
+    }
+
+    fn record_rtt(&mut self, server: IpAddr, rtt: time::Duration) {
+        let now = time::Instant::now();
+        self.rtt
+            .entry(server)
+            .and_modify(|x| {
+                x.srtt = (x.srtt * 7 + rtt) / 8;
+                x.updated = now;
+            })
+            .or_insert(RttEntry {
+                srtt: rtt,
+                updated: now,
+            });
+    }
+
+    fn is_lame(&self, server: &IpAddr, zone: &DomainName) -> bool {
+        self.lame
+            .get(&(*server, zone.canonical()))
+            .is_some_and(|expiration| self.now() < *expiration)
+    }
+
+    /// Marks the current server of `id` lame for its zone and retries with
+    /// another nameserver of the zone, failing if none is left
+    fn next_server(&mut self, id: u16) -> Result<()> {
+        let expiration = self.now() + Duration::seconds(LAME_TTL);
+        let pending = self.id_map[&id].packet_stack.last().unwrap();
+        let zone = pending.zone.clone();
+        info!("{} is lame for {}", pending.server, zone.to_domain_name());
+        self.lame
+            .insert((pending.server, zone.canonical()), expiration);
+        let mut candidates = std::mem::take(&mut self.pending_mut(id).candidates);
+        candidates.retain(|ip| !self.is_lame(ip, &zone));
+        if candidates.is_empty() {
+            return self.query_next_ns(id);
+        }
+        let server = candidates.swap_remove(self.pick_server(&candidates));
+        let pending = self.pending_mut(id);
+        pending.server = server;
+        pending.candidates = candidates;
+        self.send_pending(id)
+    }
+
+    pub fn new(host: &str, port: u16) -> Result<Rdns> {
+        RdnsBuilder::new(host, port).build()
+    }
+
+    pub fn builder(host: &str, port: u16) -> RdnsBuilder {
+        RdnsBuilder::new(host, port)
+    }
+
+    pub fn config(&self) -> &RdnsConfig {
+        &self.config
+    }
+
+    /// Leaves the queries of clients unanswered, only those started with
+    /// `resolve_full` are resolved
+    pub(crate) fn ignore_clients(&mut self) {
+        self.serve_clients = false;
+    }
+
+    /// Address clients send their UDP queries to
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn with_config(host: &str, port: u16, config: RdnsConfig) -> Result<Rdns> {
+        let shared = Shared::new(&config)?;
+        Self::with_shared(host, port, config, shared)
+    }
+
+    /// A resolver using the caches and other state of `shared`, which may
+    /// be used by others on other threads at the same time
+    pub fn with_shared(host: &str, port: u16, config: RdnsConfig, shared: Shared) -> Result<Rdns> {
+        let addr = SocketAddr::new(host.parse()?, port);
+        let datamap = HashMap::new();
+        let socket: UdpSocket = bind(addr, Type::DGRAM, config.reuse_port)?.into();
+        socket.set_read_timeout(config.read_timeout)?;
+        // the sockets of the upstream queries are polled along with it
+        if config.port_randomization {
+            socket.set_nonblocking(true)?;
+        }
+        let outbound = match config.outbound_addr {
+            Some(ip) => {
+                let outbound = UdpSocket::bind(SocketAddr::new(ip, 0))?;
+                outbound.set_nonblocking(true)?;
+                socket.set_nonblocking(true)?;
+                Some(outbound)
+            }
+            None => None,
+        };
+        let upstream_v4 = outbound.as_ref().unwrap_or(&socket).local_addr()?.is_ipv4();
+        let outbound6 = match (upstream_v4, config.upstream_family) {
+            (false, _) | (_, UpstreamFamily::V4) => None,
+            // not an error on a host without IPv6, IPv4 servers still work
+            _ => match UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)) {
+                Ok(outbound6) => {
+                    outbound6.set_nonblocking(true)?;
+                    socket.set_nonblocking(true)?;
+                    Some(outbound6)
+                }
+                Err(_) => None,
+            },
+        };
+        let tcp = match config.tcp {
+            true => {
+                let listener = bind(addr, Type::STREAM, config.reuse_port)?;
+                listener.listen(128)?;
+                listener.set_nonblocking(true)?;
+                socket.set_nonblocking(true)?;
+                Some(listener.into())
+            }
+            false => None,
+        };
+        let dot = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let listener = bind(
+                    SocketAddr::new(addr.ip(), config.dot_port),
+                    Type::STREAM,
+                    config.reuse_port,
+                )?;
+                listener.listen(128)?;
+                listener.set_nonblocking(true)?;
+                socket.set_nonblocking(true)?;
+                Some((listener.into(), tls::server_config(cert, key)?))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(RdnsError::Config(
+                    "DNS-over-TLS needs both a certificate and a key".into(),
+                ))
+            }
+        };
+        let forward_tls = match config.forward_tls {
+            Some(_) => Some(tls::client_config(config.forward_ca.as_deref())?),
+            None => None,
+        };
+        let doh = match config.doh_listen {
+            Some(addr) => {
+                let listener = bind(addr, Type::STREAM, config.reuse_port)?;
+                listener.listen(128)?;
+                listener.set_nonblocking(true)?;
+                socket.set_nonblocking(true)?;
+                let https = dot.as_ref().map(|(_, x)| {
+                    let mut https = ServerConfig::clone(x);
+                    https.alpn_protocols = vec![b"http/1.1".to_vec()];
+                    Arc::new(https)
+                });
+                Some((listener.into(), https))
+            }
+            None => None,
+        };
+        let metrics_listener = match config.metrics_listen {
+            Some(addr) => {
+                let listener = bind(addr, Type::STREAM, config.reuse_port)?;
+                listener.listen(128)?;
+                listener.set_nonblocking(true)?;
+                socket.set_nonblocking(true)?;
+                Some(listener.into())
+            }
+            None => None,
+        };
+        let hosts = match &config.hosts {
+            Some(path) => Hosts::load(path)?,
+            None => Hosts::default(),
+        };
+        let blocklist = match &config.blocklist {
+            Some(path) => Blocklist::load(path)?,
+            None => Blocklist::default(),
+        };
+        if config.block_sinkhole.is_empty()
+            && (config.block_response == BlockResponse::Sinkhole || blocklist.has_sinkhole())
+        {
+            return Err(RdnsError::Config(
+                "the sinkhole block response needs a sinkhole address".into(),
+            ));
+        }
+        if config.dnssec != Dnssec::Off && config.forward.is_some() {
+            return Err(RdnsError::Config(
+                "DNSSEC validation needs resolving from the root, not forwarding".into(),
+            ));
+        }
+        let r = Rdns {
+            socket,
+            outbound,
+            outbound6,
+            tcp,
+            dot,
+            tcp_conns: HashMap::new(),
+            forward_tls,
+            doh,
+            doh_conns: HashMap::new(),
+            metrics_listener,
+            metrics_conns: HashMap::new(),
+            next_conn: 0,
+            id_map: datamap,
+            shared,
+            hosts,
+            blocklist,
+            lame: HashMap::new(),
+            keys: HashMap::new(),
+            rtt: HashMap::new(),
+            last_tick: time::Instant::now(),
+            stats: Stats::default(),
+            last_stats: time::Instant::now(),
+            resolved: HashMap::new(),
+            serve_clients: true,
+            config,
+        };
+        Ok(r)
+    }
+
+    /// Lists the unexpired cache entries as (type, name, remaining TTL, rdata)
+    pub fn dump_cache(&self) -> Vec<(DNSType, String, u32, Arc<DNSRdata>)> {
+        let now = self.now();
+        self.shared
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| now < entry.expiration)
+            .flat_map(|((_, t, name), entry)| {
+                entry.records.iter().map(move |x| {
+                    (
+                        DNSType::from_num(*t),
+                        name.clone(),
+                        (entry.expiration - now).num_seconds() as u32,
+                        x.rdata.clone(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Looks up the address of the next nameserver in `ns_names` of the
+    /// current query of `id`, failing the query once none is left
+    fn query_next_ns(&mut self, id: u16) -> Result<()> {
+        while !self.pending_mut(id).ns_names.is_empty() {
+            let name = self.pending_mut(id).ns_names.remove(0);
+            // skip those already being looked up, the referrals go in circles
+            if self
+                .id_map
+                .get_mut(&id)
+                .unwrap()
+                .visited
+                .insert(name.clone())
+            {
+                // an IPv6-only resolver needs the nameserver's AAAA
+                let qtype = match self.config.upstream_family {
+                    UpstreamFamily::V6 => DNSType::AAAA,
+                    _ => DNSType::A,
+                };
+                return self.query_for(id, &name, qtype);
+            }
+        }
+        self.fail(id, DNSRcode::ServerFailure)
+    }
+
+    /// Looks up the `qtype` address of the nameserver `domain_name` for the
+    /// query `id`, which waits on it, or the DNSKEY or DS records of the
+    /// zone `domain_name` to validate its answer with
+    fn query_for(&mut self, id: u16, domain_name: &String, qtype: DNSType) -> Result<()> {
+        if !self.id_map.contains_key(&id) {
+            return Err(RdnsError::ResolutionFailed(
+                domain_name.clone(),
+                DNSRcode::ServerFailure as u8,
+            ));
+        }
+        let mut pkt = DNSPacket::new(id, true);
+        pkt.questions
+            .push(DNSQuestion::new(domain_name.to_domain_name(), qtype as u16));
+        pkt.additionals
+            .push(opt_with(vec![], self.config.dnssec != Dnssec::Off));
+        let (zone, server, candidates, ns_names) = self.start_point(&pkt.questions[0].qname)?;
+        let minimize = self.minimizes();
+        let data = self.id_map.get_mut(&id).unwrap();
+        data.packet_stack.push(PendingQuery {
+            packet: pkt,
+            zone,
+            server,
+            candidates,
+            ns_names,
+            minimized: None,
+            minimize,
+            sent_qname: vec![],
+            socket: None,
+            tcp_server: None,
+            conn: None,
+            sent: time::Instant::now(),
+            retries: 0,
+        });
+        self.send_pending(id)
+    }
+
+    fn upstream_socket(&self) -> &UdpSocket {
+        self.outbound.as_ref().unwrap_or(&self.socket)
+    }
+
+    /// The socket to query `server` from
+    fn socket_for(&self, server: &IpAddr) -> &UdpSocket {
+        match &self.outbound6 {
+            Some(socket) if server.is_ipv6() => socket,
+            _ => self.upstream_socket(),
+        }
+    }
+
+    /// Sends `pkt` to `to_addr`, from a socket of its own on a random port
+    /// if `port_randomization` is set, which is returned
+    fn new_query(&self, pkt: &DNSPacket, to_addr: &SocketAddr) -> Result<Option<UdpSocket>> {
+        if !self.config.port_randomization {
+            self.socket_for(&to_addr.ip())
+                .send_to(&pkt.assemble()?, to_addr)?;
+            return Ok(None);
+        }
+        let ip = match self.config.outbound_addr {
+            Some(ip) if ip.is_ipv6() == to_addr.is_ipv6() => ip,
+            _ if to_addr.is_ipv6() => Ipv6Addr::UNSPECIFIED.into(),
+            _ => Ipv4Addr::UNSPECIFIED.into(),
+        };
+        // the port is picked at random by the system
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0))?;
+        socket.set_nonblocking(true)?;
+        socket.send_to(&pkt.assemble()?, to_addr)?;
+        Ok(Some(socket))
+    }
+
+    /// Whether upstream queries may be sent to `ip`
+    fn is_usable_addr(&self, ip: &IpAddr) -> bool {
+        match self.config.upstream_family {
+            // IPv6 is only usable if we can send over it
+            UpstreamFamily::Auto => {
+                ip.is_ipv4()
+                    || self
+                        .socket_for(ip)
+                        .local_addr()
+                        .map(|a| a.is_ipv6())
+                        .unwrap_or(false)
+            }
+            UpstreamFamily::V4 => ip.is_ipv4(),
+            UpstreamFamily::V6 => ip.is_ipv6(),
+        }
+    }
+
+    fn cached_ns_addr(&self, name: &str) -> Option<IpAddr> {
+        let cache = self.shared.cache.lock().unwrap();
+        for t in [DNSType::A, DNSType::AAAA] {
+            let entry = match cache.peek(&(DNSClass::IN as u16, t as u16, name.to_string())) {
+                Some(x) if self.now() < x.expiration => x,
+                _ => continue,
+            };
+            for record in &entry.records {
+                let ip: IpAddr = match record.rdata.as_ref() {
+                    DNSRdata::A(ip) => (*ip).into(),
+                    DNSRdata::Aaaa(ip) => (*ip).into(),
+                    _ => continue,
+                };
+                if self.is_usable_addr(&ip) {
+                    return Some(ip);
+                }
+            }
+        }
+        None
+    }
+
+    /// The glue addresses of the nameservers `pkt` refers to, with the
+    /// names of those it has none for, or only their names without glue
+    fn check_for_ns_addr(
+        &self,
+        pkt: &DNSPacket,
+    ) -> Either<(Vec<IpAddr>, Vec<String>), Vec<String>> {
+        // in the order of the referral, the names are looked up in turn
+        let mut nameservs = Vec::new();
+        for x in &pkt.authorities {
+            if x.r#type == DNSType::NS as u16 {
+                if let DNSRdata::Ns(dn) = x.rdata.as_ref() {
+                    let name = dn.canonical();
+                    if !nameservs.contains(&name) {
+                        nameservs.push(name);
+                    }
+                }
+            }
+        }
+        let mut v = Vec::new();
+        let mut glued = HashSet::new();
+        for x in &pkt.additionals {
+            let ip: IpAddr = match x.rdata.as_ref() {
+                DNSRdata::A(ip) => (*ip).into(),
+                DNSRdata::Aaaa(ip) => (*ip).into(),
+                _ => continue,
+            };
+            let name = x.name.canonical();
+            if self.is_usable_addr(&ip) && nameservs.contains(&name) {
+                v.push(ip);
+                glued.insert(name);
+            }
+        }
+        if v.is_empty() {
+            return Right(nameservs);
+        }
+        nameservs.retain(|x| !glued.contains(x));
+        Left((v, nameservs))
+    }
+
+    fn error(&mut self, pkt: &mut DNSPacket, rcode: DNSRcode, client: &Client) -> Result<()> {
+        pkt.header.qr = 1;
+        pkt.header.set_rcode(rcode);
+        self.respond(client, QueryInfo::of(pkt), pkt);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn big_response(answers: usize) -> DNSPacket {
+        let name = "example.com".to_string().to_domain_name();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.questions
+            .push(DNSQuestion::new(name.clone(), DNSType::A as u16));
+        for i in 0..answers {
+            pkt.answers.push(DNSResourceRecord {
+                name: name.clone(),
+                r#type: DNSType::A as u16,
+                class: DNSClass::IN as u16,
+                ttl: 300,
+                rdata: Arc::new(DNSRdata::A(Ipv4Addr::from(i as u32))),
+            });
+        }
+        pkt.additionals
+            .push(DNSResourceRecord::opt(EDNS_UDP_PAYLOAD_SIZE));
+        pkt
+    }
+
+    #[test]
+    fn encode_truncates_to_limit() {
+        let pkt = big_response(100);
+        let msg = encode(&pkt, 512).unwrap();
+        assert!(msg.len() <= 512);
+        let truncated = DNSPacket::from_raw(&msg).unwrap();
+        assert_eq!(truncated.header.tc, 1);
+        assert_eq!(truncated.questions.len(), 1);
+        assert!(truncated.answers.is_empty());
+        assert_eq!(truncated.additionals.len(), 1);
+    }
+
+    #[test]
+    fn encode_keeps_fitting_response() {
+        let pkt = big_response(2);
+        let msg = encode(&pkt, 512).unwrap();
+        assert_eq!(msg, pkt.assemble().unwrap());
+        assert_eq!(DNSPacket::from_raw(&msg).unwrap().header.tc, 0);
+    }
+
+    /// The response `rdns` sends a UDP client to `query`
+    fn respond_udp(rdns: &mut Rdns, query: &DNSPacket, pkt: &DNSPacket) -> DNSPacket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+        rdns.respond(&Client::Udp(addr), QueryInfo::of(query), pkt);
+        let mut buf = [0u8; 4096];
+        let n = client.recv(&mut buf).unwrap();
+        DNSPacket::from_raw(&buf[..n]).unwrap()
+    }
+
+    #[test]
+    fn respond_sends_own_opt_only_if_asked() {
+        let mut rdns = Rdns::new("127.0.0.1", 0).unwrap();
+        let mut upstream = big_response(1);
+        upstream.additionals[0] = DNSResourceRecord::opt(1400);
+        let mut query = DNSPacket::new(1, true);
+        let res = respond_udp(&mut rdns, &query, &upstream);
+        assert!(res.additionals.is_empty());
+        query.additionals.push(DNSResourceRecord::opt(1232));
+        let res = respond_udp(&mut rdns, &query, &upstream);
+        assert_eq!(res.additionals.len(), 1);
+        assert_eq!(res.additionals[0].class, EDNS_UDP_PAYLOAD_SIZE);
+    }
+
+    fn record(name: &str, r#type: DNSType, rdata: DNSRdata) -> DNSResourceRecord {
+        DNSResourceRecord {
+            name: name.to_string().to_domain_name(),
+            r#type: r#type as u16,
+            class: DNSClass::IN as u16,
+            ttl: 300,
+            rdata: Arc::new(rdata),
+        }
+    }
+
+    #[test]
+    fn glue_matches_its_nameserver_whatever_the_case() {
+        let rdns = Rdns::new("127.0.0.1", 0).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        for ns in ["NS1.Example.COM", "ns2.example.com", "ns3.example.com"] {
+            pkt.authorities.push(record(
+                "example.com",
+                DNSType::NS,
+                DNSRdata::Ns(ns.to_string().to_domain_name()),
+            ));
+        }
+        for (name, ip) in [("ns1.example.com", 1), ("NS2.EXAMPLE.COM", 2)] {
+            pkt.additionals.push(record(
+                name,
+                DNSType::A,
+                DNSRdata::A(Ipv4Addr::new(192, 0, 2, ip)),
+            ));
+        }
+        match rdns.check_for_ns_addr(&pkt) {
+            Left((ips, glueless)) => {
+                let expected: [IpAddr; 2] = [
+                    Ipv4Addr::new(192, 0, 2, 1).into(),
+                    Ipv4Addr::new(192, 0, 2, 2).into(),
+                ];
+                assert_eq!(ips, expected);
+                assert_eq!(glueless, ["ns3.example.com"]);
+            }
+            Right(x) => panic!("no glue matched {:?}", x),
+        }
+    }
+
+    #[test]
+    fn cached_cname_chain_is_replayed_in_order() {
+        let rdns = Rdns::new("127.0.0.1", 0).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![
+            record(
+                "www.example.com",
+                DNSType::CNAME,
+                DNSRdata::Cname("cdn.example.net".to_string().to_domain_name()),
+            ),
+            record(
+                "cdn.example.net",
+                DNSType::A,
+                DNSRdata::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ),
+            record(
+                "cdn.example.net",
+                DNSType::A,
+                DNSRdata::A(Ipv4Addr::new(192, 0, 2, 2)),
+            ),
+        ];
+        rdns.cache_answers(&pkt);
+        let question = DNSQuestion::new(
+            "WWW.example.com".to_string().to_domain_name(),
+            DNSType::A as u16,
+        );
+        let answers = rdns.cached_answer(&question).unwrap();
+        let types: Vec<u16> = answers.iter().map(|x| x.r#type).collect();
+        assert_eq!(
+            types,
+            [DNSType::CNAME as u16, DNSType::A as u16, DNSType::A as u16]
+        );
+        assert_eq!(answers[1].rdata.to_string(), "192.0.2.1");
+        assert_eq!(answers[2].rdata.to_string(), "192.0.2.2");
+        // without the records at the end of the chain it is a cache miss
+        let question = DNSQuestion::new(
+            "www.example.com".to_string().to_domain_name(),
+            DNSType::AAAA as u16,
+        );
+        assert!(rdns.cached_answer(&question).is_none());
+    }
+
+    #[test]
+    fn answers_are_counted_by_source() {
+        let mut rdns = Rdns::new("127.0.0.1", 0).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )];
+        rdns.cache_answers(&pkt);
+        let mut query = DNSPacket::new(2, true);
+        query.questions.push(DNSQuestion::new(
+            "example.com".to_string().to_domain_name(),
+            DNSType::A as u16,
+        ));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = client.local_addr().unwrap();
+        rdns.handle(&query.assemble().unwrap(), Client::Udp(addr))
+            .unwrap();
+        let started = time::Instant::now();
+        rdns.log_query(&addr, &query, 0, Source::Hosts, started);
+        let metrics = rdns.shared.metrics.lock().unwrap();
+        assert_eq!(metrics.queries, 2);
+        assert_eq!(metrics.cache_hits, 1);
+        assert_eq!(metrics.hosts_answers, 1);
+        assert_eq!(metrics.cache_misses, 0);
+        assert!(metrics.render().contains("rdns_hosts_answers_total 1\n"));
+    }
+
+    fn query(name: &str, qtype: DNSType) -> DNSPacket {
+        let mut query = DNSPacket::new(2, true);
+        query.questions.push(DNSQuestion::new(
+            name.to_string().to_domain_name(),
+            qtype as u16,
+        ));
+        query
+    }
+
+    #[test]
+    fn blocked_names_get_their_response() {
+        let path = std::env::temp_dir().join(format!("rdns-blocklist-{}", std::process::id()));
+        std::fs::write(&path, "sink.example\nrefused.example refused\n").unwrap();
+        let config = RdnsConfig {
+            blocklist: Some(path.clone()),
+            block_response: BlockResponse::Sinkhole,
+            block_sinkhole: vec![Ipv4Addr::new(192, 0, 2, 53).into()],
+            block_ttl: 60,
+            ..Default::default()
+        };
+        let rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pkt = rdns
+            .blocked_answer(&query("sink.example", DNSType::A))
+            .unwrap();
+        assert_eq!(pkt.answers[0].rdata.to_string(), "192.0.2.53");
+        assert_eq!(pkt.answers[0].ttl, 60);
+        // no IPv6 sinkhole, so NODATA
+        let pkt = rdns
+            .blocked_answer(&query("sink.example", DNSType::AAAA))
+            .unwrap();
+        assert!(pkt.answers.is_empty() && pkt.is_negative());
+        assert_eq!(pkt.header.rcode, DNSRcode::Normal as u8);
+        let pkt = rdns
+            .blocked_answer(&query("refused.example", DNSType::MX))
+            .unwrap();
+        assert_eq!(pkt.header.rcode, DNSRcode::Refused as u8);
+        assert!(rdns
+            .blocked_answer(&query("example.com", DNSType::A))
+            .is_none());
+    }
+
+    fn nxdomain(name: &str, soa_ttl: u32) -> DNSPacket {
+        let mut pkt = query(name, DNSType::A);
+        pkt.header.qr = 1;
+        pkt.header.set_rcode(DNSRcode::NameError);
+        pkt.authorities.push(zone::synthesize_soa(
+            &"example".to_string().to_domain_name(),
+            soa_ttl,
+            soa_ttl,
+        ));
+        pkt
+    }
+
+    #[test]
+    fn negative_ttl_is_capped() {
+        let config = RdnsConfig {
+            negative_max_ttl: 600,
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        rdns.cache_negative(&nxdomain("gone.example", 86400));
+        let pkt = rdns
+            .negative_answer(&query("GONE.example", DNSType::A))
+            .unwrap();
+        assert_eq!(pkt.header.rcode, DNSRcode::NameError as u8);
+        assert_eq!(pkt.authorities.len(), 1);
+        assert_eq!(pkt.authorities[0].r#type, DNSType::SOA as u16);
+        assert!(pkt.authorities[0].ttl <= 600);
+        // a shorter SOA minimum still wins
+        rdns.cache_negative(&nxdomain("brief.example", 30));
+        let pkt = rdns
+            .negative_answer(&query("brief.example", DNSType::A))
+            .unwrap();
+        assert!(pkt.authorities[0].ttl <= 30);
+        assert!(rdns
+            .negative_answer(&query("gone.example", DNSType::AAAA))
+            .is_none());
+    }
+
+    /// A clock that only moves when told to
+    #[derive(Debug)]
+    struct FakeClock(Mutex<DateTime<Local>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Local> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    impl FakeClock {
+        fn advance(&self, secs: i64) {
+            *self.0.lock().unwrap() += Duration::seconds(secs);
+        }
+    }
+
+    #[test]
+    fn negative_answers_expire() {
+        let clock = Arc::new(FakeClock(Mutex::new(Local::now())));
+        let config = RdnsConfig {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        rdns.cache_negative(&nxdomain("gone.example", 60));
+        clock.advance(45);
+        let pkt = rdns
+            .negative_answer(&query("gone.example", DNSType::A))
+            .unwrap();
+        assert_eq!(pkt.authorities[0].ttl, 15);
+        clock.advance(15);
+        assert!(rdns
+            .negative_answer(&query("gone.example", DNSType::A))
+            .is_none());
+        assert!(rdns.shared.negative_cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cached_ttls_are_kept_within_bounds() {
+        let clock = Arc::new(FakeClock(Mutex::new(Local::now())));
+        let config = RdnsConfig {
+            clock: clock.clone(),
+            min_ttl: 30,
+            max_ttl: 86400,
+            ..Default::default()
+        };
+        let rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        for (name, ttl) in [("brief.example", 5), ("long.example", 999999)] {
+            let mut rr = record(name, DNSType::A, DNSRdata::A(Ipv4Addr::LOCALHOST));
+            rr.ttl = ttl;
+            pkt.answers.push(rr);
+        }
+        rdns.cache_answers(&pkt);
+        let ttl = |name: &str| {
+            let question = DNSQuestion::new(name.to_string().to_domain_name(), 1);
+            rdns.cached_answer(&question).map(|x| x[0].ttl)
+        };
+        assert_eq!(ttl("brief.example"), Some(30));
+        assert_eq!(ttl("long.example"), Some(86400));
+        clock.advance(30);
+        assert_eq!(ttl("brief.example"), None);
+        assert_eq!(ttl("long.example"), Some(86370));
+    }
+
+    #[test]
+    fn negative_answers_are_not_cached_with_zero_cap() {
+        let config = RdnsConfig {
+            negative_max_ttl: 0,
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        rdns.cache_negative(&nxdomain("gone.example", 86400));
+        assert!(rdns
+            .negative_answer(&query("gone.example", DNSType::A))
+            .is_none());
+    }
+
+    #[test]
+    fn alias_is_answered_with_target_addresses() {
+        let path = std::env::temp_dir().join(format!("rdns-alias-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "example. 3600 IN NS ns.example.\n\
+             example. 60 IN ALIAS target.example.org.\n",
+        )
+        .unwrap();
+        let mut rdns = Rdns::builder("127.0.0.1", 0)
+            .zone_file(&path)
+            .build()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![record(
+            "target.example.org",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )];
+        rdns.cache_answers(&pkt);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+        let query = query("Example", DNSType::A);
+        rdns.handle(&query.assemble().unwrap(), Client::Udp(addr))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.header.aa, 1);
+        assert_eq!(res.questions[0].qname, query.questions[0].qname);
+        assert_eq!(res.answers.len(), 1);
+        assert_eq!(res.answers[0].name, "Example".to_string().to_domain_name());
+        assert_eq!(res.answers[0].ttl, 60);
+        assert_eq!(res.answers[0].rdata.to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn notify_has_the_zone_transferred_from_the_primary() {
+        let path = std::env::temp_dir().join(format!("rdns-secondary-{}", std::process::id()));
+        let version = |serial: u32| {
+            format!(
+                "example. 3600 IN SOA ns.example. hostmaster.example. {} 3600 600 86400 300\n\
+                 www.example. 300 IN A 192.0.2.{}\n",
+                serial, serial
+            )
+        };
+        std::fs::write(&path, version(1)).unwrap();
+        let primary = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = RdnsConfig {
+            zones: vec![path.clone()],
+            primaries: vec![Ipv4Addr::LOCALHOST.into()],
+            upstream_port: primary.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = primary.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut msg).unwrap();
+            let query = DNSPacket::from_raw(&msg).unwrap();
+            let asked = (
+                query.questions[0].qtype,
+                query.authorities[0].rdata.to_string(),
+            );
+            let www = DNSQuestion::new("www.example".to_string().to_domain_name(), 1);
+            let (old, new) = (
+                Zone::parse(&version(1)).unwrap(),
+                Zone::parse(&version(2)).unwrap(),
+            );
+            let mut pkt = DNSPacket::new(query.id(), false);
+            pkt.questions = query.questions;
+            pkt.answers = vec![new.soa().clone(), old.soa().clone()];
+            pkt.answers.extend(old.answer(0, &www).answers);
+            pkt.answers.push(new.soa().clone());
+            pkt.answers.extend(new.answer(0, &www).answers);
+            pkt.answers.push(new.soa().clone());
+            let msg = pkt.assemble().unwrap();
+            stream.write_all(&(msg.len() as u16).to_be_bytes()).unwrap();
+            stream.write_all(&msg).unwrap();
+            asked
+        });
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut notify = query("example", DNSType::SOA);
+        notify.header.opcode = DNSOpcode::Notify as u8;
+        notify.header.set_rd(false);
+        rdns.handle(
+            &notify.assemble().unwrap(),
+            Client::Udp(client.local_addr().unwrap()),
+        )
+        .unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.header.rcode, 0);
+        let (qtype, serial) = server.join().unwrap();
+        assert_eq!(qtype, DNSType::IXFR as u16);
+        assert!(serial.contains(" 1 "), "{}", serial);
+        // the transfer is applied right after it is read
+        let www = query("www.example", DNSType::A);
+        for _ in 0..100 {
+            let answers = rdns.zone_answer(&www).unwrap().answers;
+            if answers[0].rdata.to_string() == "192.0.2.2" {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        panic!("the zone was not transferred");
+    }
+
+    #[test]
+    fn evictions_and_expirations_are_counted() {
+        let config = RdnsConfig {
+            cache_size: 1,
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![
+            record("a.example", DNSType::A, DNSRdata::A(Ipv4Addr::LOCALHOST)),
+            record("b.example", DNSType::A, DNSRdata::A(Ipv4Addr::LOCALHOST)),
+        ];
+        rdns.cache_answers(&pkt);
+        rdns.shared.negative_cache.lock().unwrap().insert(
+            (
+                DNSClass::IN as u16,
+                DNSType::A as u16,
+                "gone.example".into(),
+            ),
+            NegativeCacheEntry {
+                expiration: rdns.now() - Duration::seconds(1),
+                rcode: DNSRcode::NameError as u8,
+                soa: zone::synthesize_soa(&vec!["example".into()], 60, 60),
+            },
+        );
+        rdns.tick().unwrap();
+        let metrics = rdns.shared.metrics.lock().unwrap();
+        assert_eq!(metrics.cache_evictions, 1);
+        assert_eq!(metrics.cache_expirations, 1);
+        assert!(metrics.render().contains("rdns_cache_evictions_total 1\n"));
+        assert_eq!(rdns.shared.cache.lock().unwrap().len(), 1);
+        assert!(rdns.shared.negative_cache.lock().unwrap().is_empty());
+    }
+
+    fn options(pkt: &DNSPacket) -> Vec<u16> {
+        match pkt.additionals.last().map(|x| x.rdata.as_ref()) {
+            Some(DNSRdata::Opt { options, .. }) => options.iter().map(|x| x.code).collect(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn edns_options_pass_through_the_forwarder() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let config = RdnsConfig {
+            forward: Some(upstream.local_addr().unwrap()),
+            edns_passthrough: true,
+            port_randomization: false,
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut query = query("example.com", DNSType::A);
+        query.additionals.push(opt_with(
+            vec![EdnsOption {
+                code: 65001,
+                data: b"client".to_vec(),
+            }],
+            false,
+        ));
+        let addr = client.local_addr().unwrap();
+        rdns.handle(&query.assemble().unwrap(), Client::Udp(addr))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        let (n, from) = upstream.recv_from(&mut buf).unwrap();
+        let mut sent = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(options(&sent), [65001]);
+        sent.header.qr = 1;
+        sent.additionals = vec![opt_with(
+            vec![EdnsOption {
+                code: 65002,
+                data: vec![],
+            }],
+            false,
+        )];
+        let upstream_addr = upstream.local_addr().unwrap();
+        assert_eq!(from.port(), rdns.socket.local_addr().unwrap().port());
+        rdns.handle(&sent.assemble().unwrap(), Client::Udp(upstream_addr))
+            .unwrap();
+        let n = client.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.header.id, query.header.id);
+        assert_eq!(options(&res), [65002]);
+    }
+
+    #[test]
+    fn queries_are_answered_over_tls() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/rdns/testdata");
+        let config = RdnsConfig {
+            tls_cert: Some(dir.join("localhost.pem")),
+            tls_key: Some(dir.join("localhost.key")),
+            dot_port: 0,
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        )];
+        rdns.cache_answers(&pkt);
+        let addr = rdns.dot.as_ref().unwrap().0.local_addr().unwrap();
+        // the self-signed certificate is trusted as its own authority
+        let client = thread::spawn(move || {
+            let config = tls::client_config(Some(&dir.join("localhost.pem"))).unwrap();
+            let name = "localhost".try_into().unwrap();
+            let conn = rustls::ClientConnection::new(config, name).unwrap();
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(time::Duration::from_secs(5)))
+                .unwrap();
+            let mut stream = StreamOwned::new(conn, stream);
+            let msg = query("example.com", DNSType::A).assemble().unwrap();
+            stream.write_all(&(msg.len() as u16).to_be_bytes()).unwrap();
+            stream.write_all(&msg).unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut msg).unwrap();
+            DNSPacket::from_raw(&msg).unwrap()
+        });
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        while !client.is_finished() {
+            rdns.poll(&mut buf).unwrap();
+        }
+        let res = client.join().unwrap();
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.1");
+    }
+
+    #[test]
+    fn metrics_count_cache_hits_and_misses() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut rdns, client) = forwarding_to(&upstream, |config| {
+            config.metrics_listen = Some("127.0.0.1:0".parse().unwrap())
+        });
+        let addr = client.local_addr().unwrap();
+        let msg = query("example.com", DNSType::A).assemble().unwrap();
+        rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let n = upstream.recv(&mut buf).unwrap();
+        let mut sent = DNSPacket::from_raw(&buf[..n]).unwrap();
+        sent.header.qr = 1;
+        sent.answers.push(record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        ));
+        let upstream_addr = upstream.local_addr().unwrap();
+        rdns.handle(&sent.assemble().unwrap(), Client::Udp(upstream_addr))
+            .unwrap();
+        rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        let listen = rdns
+            .metrics_listener
+            .as_ref()
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let scrape = thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(listen).unwrap();
+            stream
+                .set_read_timeout(Some(time::Duration::from_secs(5)))
+                .unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut res = String::new();
+            let _ = stream.read_to_string(&mut res);
+            res
+        });
+        while !scrape.is_finished() {
+            rdns.poll(&mut buf).unwrap();
+        }
+        let res = scrape.join().unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(res.contains("\nrdns_queries_total 2\n"));
+        assert!(res.contains("\nrdns_cache_hits_total 1\n"));
+        assert!(res.contains("\nrdns_cache_misses_total 1\n"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn queries_are_answered_when_the_log_cannot_be_written() {
+        let config = RdnsConfig {
+            query_log: Some("/dev/full".into()),
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        )];
+        rdns.cache_answers(&pkt);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+        let msg = query("example.com", DNSType::A).assemble().unwrap();
+        // a whole buffer of lines, for writing to fail before the flush
+        for _ in 0..200 {
+            rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        }
+        rdns.shared.reopen_log.store(true, Ordering::Relaxed);
+        rdns.tick().unwrap();
+        rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        let mut buf = [0u8; 512];
+        for _ in 0..201 {
+            let n = client.recv(&mut buf).unwrap();
+            assert_eq!(DNSPacket::from_raw(&buf[..n]).unwrap().answers.len(), 1);
+        }
+    }
+
+    #[test]
+    fn keepalive_is_answered_over_tcp_only() {
+        let config = RdnsConfig {
+            tcp_idle_timeout: time::Duration::from_secs(30),
+            ..Default::default()
+        };
+        let mut rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers = vec![record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        )];
+        rdns.cache_answers(&pkt);
+        let mut query = query("example.com", DNSType::A);
+        query.additionals.push(opt_with(
+            vec![EdnsOption {
+                code: EDNS_TCP_KEEPALIVE,
+                data: vec![],
+            }],
+            false,
+        ));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        rdns.tcp_conns
+            .insert(1, TcpConn::new(stream, peer).unwrap());
+        rdns.handle(&query.assemble().unwrap(), Client::Tcp(1, peer))
+            .unwrap();
+        let mut len = [0u8; 2];
+        client.read_exact(&mut len).unwrap();
+        let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+        client.read_exact(&mut msg).unwrap();
+        let res = DNSPacket::from_raw(&msg).unwrap();
+        assert_eq!(res.answers.len(), 1);
+        assert_eq!(keepalive_option(&res).unwrap().data, 300u16.to_be_bytes());
+        // over UDP the option is an error
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let addr = udp.local_addr().unwrap();
+        rdns.handle(&query.assemble().unwrap(), Client::Udp(addr))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        let n = udp.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.header.rcode, DNSRcode::FormatError as u8);
+        assert!(keepalive_option(&res).is_none());
+    }
+
+    /// A resolver forwarding to `upstream`, and a client socket
+    fn forwarding_to(
+        upstream: &UdpSocket,
+        tweak: impl FnOnce(&mut RdnsConfig),
+    ) -> (Rdns, UdpSocket) {
+        upstream
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut config = RdnsConfig {
+            forward: Some(upstream.local_addr().unwrap()),
+            port_randomization: false,
+            ..Default::default()
+        };
+        tweak(&mut config);
+        let rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        (rdns, client)
+    }
+
+    #[test]
+    fn unknown_class_is_forwarded_unchanged() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut rdns, client) = forwarding_to(&upstream, |_| {});
+        let mut query = query("example.com", DNSType::TXT);
+        query.questions[0].qclass = 42;
+        let addr = client.local_addr().unwrap();
+        rdns.handle(&query.assemble().unwrap(), Client::Udp(addr))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        let n = upstream.recv(&mut buf).unwrap();
+        let mut sent = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(sent.questions[0].qclass, 42);
+        sent.header.qr = 1;
+        sent.additionals.clear();
+        let upstream_addr = upstream.local_addr().unwrap();
+        rdns.handle(&sent.assemble().unwrap(), Client::Udp(upstream_addr))
+            .unwrap();
+        let n = client.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.questions[0].qclass, 42);
+        assert_eq!(res.header.rcode, DNSRcode::Normal as u8);
+    }
+
+    #[test]
+    fn responses_must_echo_the_casing_sent() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut rdns, client) =
+            forwarding_to(&upstream, |config| config.case_randomization = true);
+        client
+            .set_read_timeout(Some(time::Duration::from_millis(200)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+        let msg = query("www.example.com", DNSType::A).assemble().unwrap();
+        rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        let mut buf = [0u8; 512];
+        let n = upstream.recv(&mut buf).unwrap();
+        let mut sent = DNSPacket::from_raw(&buf[..n]).unwrap();
+        sent.header.qr = 1;
+        sent.answers.push(record(
+            "www.example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        ));
+        let upstream_addr = upstream.local_addr().unwrap();
+        // with every letter's case flipped, the response is ignored
+        let mut flipped = sent.clone();
+        for label in &mut flipped.questions[0].qname {
+            *label = label
+                .chars()
+                .map(|c| match c.is_ascii_lowercase() {
+                    true => c.to_ascii_uppercase(),
+                    false => c.to_ascii_lowercase(),
+                })
+                .collect();
+        }
+        rdns.handle(&flipped.assemble().unwrap(), Client::Udp(upstream_addr))
+            .unwrap();
+        assert!(client.recv(&mut buf).is_err());
+        // and the one echoing it is still accepted
+        rdns.handle(&sent.assemble().unwrap(), Client::Udp(upstream_addr))
+            .unwrap();
+        let n = client.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.answers.len(), 1);
+    }
+
+    #[test]
+    fn upstream_queries_go_out_from_their_own_ports() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut rdns, client) =
+            forwarding_to(&upstream, |config| config.port_randomization = true);
+        let addr = client.local_addr().unwrap();
+        let mut ports = vec![];
+        for name in ["a.example.com", "b.example.com"] {
+            let msg = query(name, DNSType::A).assemble().unwrap();
+            rdns.handle(&msg, Client::Udp(addr)).unwrap();
+            let mut buf = [0u8; 512];
+            let (_, from) = upstream.recv_from(&mut buf).unwrap();
+            ports.push(from.port());
+        }
+        assert_ne!(ports[0], ports[1]);
+        assert!(!ports.contains(&rdns.local_addr().unwrap().port()));
+    }
+
+    #[test]
+    fn builder_sets_the_config() {
+        let forwarder: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+        let rdns = Rdns::builder("127.0.0.1", 0)
+            .cache_capacity(100)
+            .timeout(time::Duration::from_millis(500))
+            .retries(5)
+            .ttl_bounds(60, 3600)
+            .negative_max_ttl(0)
+            .forwarder(forwarder)
+            .upstream_family(UpstreamFamily::V4)
+            .tcp(false)
+            .build()
+            .unwrap();
+        let config = rdns.config();
+        assert_eq!(config.cache_size, 100);
+        assert_eq!(config.query_timeout, time::Duration::from_millis(500));
+        assert_eq!(config.retries, 5);
+        assert_eq!((config.min_ttl, config.max_ttl), (60, 3600));
+        assert_eq!(config.negative_max_ttl, 0);
+        assert_eq!(config.forward, Some(forwarder));
+        assert_eq!(config.upstream_family, UpstreamFamily::V4);
+        assert!(!config.tcp);
+        // the rest is left alone
+        let defaults = RdnsConfig::default();
+        assert_eq!(config.max_delegations, defaults.max_delegations);
+        assert_eq!(config.min_answer_ttl, defaults.min_answer_ttl);
+    }
+
+    #[test]
+    fn roots_are_picked_of_the_upstream_family() {
+        for (family, v6) in [(UpstreamFamily::V4, false), (UpstreamFamily::V6, true)] {
+            let rdns = Rdns::builder("127.0.0.1", 0)
+                .upstream_family(family)
+                .build()
+                .unwrap();
+            for _ in 0..20 {
+                assert_eq!(rdns.root_server().unwrap().is_ipv6(), v6);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_queries_get_formerr() {
+        let mut rdns = Rdns::new("127.0.0.1", 0).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_millis(200)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+        let msg = query("example.com", DNSType::A).assemble().unwrap();
+        rdns.handle(&msg[..14], Client::Udp(addr)).unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).unwrap();
+        let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+        assert_eq!(res.header.id, 2);
+        assert_eq!(res.header.rcode, DNSRcode::FormatError as u8);
+        assert!(res.questions.is_empty());
+        // without a whole header, or as a response, nothing is sent
+        rdns.handle(&msg[..10], Client::Udp(addr)).unwrap();
+        let mut response = msg.clone();
+        response[2] |= 0x80;
+        rdns.handle(&response[..14], Client::Udp(addr)).unwrap();
+        assert!(client.recv(&mut buf).is_err());
+    }
+
+    #[test]
+    fn queries_need_exactly_one_question() {
+        let mut rdns = Rdns::new("127.0.0.1", 0).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_millis(200)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+        let mut none = query("example.com", DNSType::A);
+        none.questions.clear();
+        let mut two = query("example.com", DNSType::A);
+        two.questions.push(DNSQuestion::new(
+            "example.org".to_string().to_domain_name(),
+            DNSType::AAAA as u16,
+        ));
+        for (pkt, questions) in [(none, 0), (two, 2)] {
+            rdns.handle(&pkt.assemble().unwrap(), Client::Udp(addr))
+                .unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).unwrap();
+            let res = DNSPacket::from_raw(&buf[..n]).unwrap();
+            assert_eq!(res.header.id, 2);
+            assert_eq!(res.header.qr, 1);
+            assert_eq!(res.header.rcode, DNSRcode::FormatError as u8);
+            assert_eq!(res.questions.len(), questions);
+            assert!(res.answers.is_empty());
+        }
+    }
+
+    /// Keeps the messages logged, with the thread they were logged from
+    struct CapturingLogger(Mutex<Vec<(thread::ThreadId, String)>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let msg = record.args().to_string();
+            self.0.lock().unwrap().push((thread::current().id(), msg));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(vec![]));
+
+    /// What this thread logged so far
+    fn logged() -> Vec<String> {
+        let id = thread::current().id();
+        let logs = LOGGER.0.lock().unwrap();
+        logs.iter()
+            .filter(|(x, _)| *x == id)
+            .map(|(_, msg)| msg.clone())
+            .collect()
+    }
+
+    #[test]
+    fn cache_hits_are_logged() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut rdns, client) = forwarding_to(&upstream, |_| {});
+        let addr = client.local_addr().unwrap();
+        let msg = query("example.com", DNSType::A).assemble().unwrap();
+        rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        let mut buf = [0u8; 512];
+        let n = upstream.recv(&mut buf).unwrap();
+        let mut sent = DNSPacket::from_raw(&buf[..n]).unwrap();
+        sent.header.qr = 1;
+        sent.answers.push(record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        ));
+        let upstream_addr = upstream.local_addr().unwrap();
+        rdns.handle(&sent.assemble().unwrap(), Client::Udp(upstream_addr))
+            .unwrap();
+        assert!(logged().contains(&"cache miss for example.com A, resolving".to_string()));
+        assert!(!logged().iter().any(|x| x.starts_with("cache hit")));
+        rdns.handle(&msg, Client::Udp(addr)).unwrap();
+        assert!(logged().contains(&"cache hit for example.com A".to_string()));
+    }
+
+    #[test]
+    fn udp_limit_follows_edns() {
+        let mut query = DNSPacket::new(1, true);
+        assert_eq!(QueryInfo::of(&query).udp_limit(), 512);
+        query.additionals.push(DNSResourceRecord::opt(1232));
+        assert_eq!(QueryInfo::of(&query).udp_limit(), 1232);
+        query.additionals[0] = DNSResourceRecord::opt(100);
+        assert_eq!(QueryInfo::of(&query).udp_limit(), 512);
     }
 }
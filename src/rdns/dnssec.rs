@@ -0,0 +1,430 @@
+use crate::rdns::domain_name::{
+    DomainName, DomainNameExt, DomainNameToBytes, ToDomainName, ToReadableName,
+};
+use crate::rdns::records::{DNSClass, DNSRdata, DNSResourceRecord, DNSType};
+use crate::rdns::transfer::is_newer;
+use byteorder::{BigEndian, WriteBytesExt};
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey, VerificationAlgorithm};
+use std::sync::Arc;
+
+/// DS records of the key signing keys of the root, KSK-2017 and KSK-2024
+const ROOT_ANCHORS: [&str; 2] = [
+    ". 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBB683457104237C7F8EC8D",
+    ". 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+/// Flag of the DNSKEY records whose keys sign the records of the zone
+const ZONE_KEY: u16 = 0x0100;
+
+/// What validating an answer came to (RFC 4035 section 4.3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Signed by keys leading up to a trust anchor
+    Secure,
+    /// From a zone that is not signed, as its parent has no DS for it
+    Insecure,
+    /// Signatures that should be there are missing or wrong, for the
+    /// reason given
+    Bogus(String),
+}
+
+/// The trust anchors of the root zone
+pub fn root_anchors() -> Vec<DNSResourceRecord> {
+    ROOT_ANCHORS
+        .iter()
+        .map(|x| parse_anchor(x).unwrap())
+        .collect()
+}
+
+/// A trust anchor given as the owner and rdata of a DS record, such as
+/// `. 20326 8 2 E06D44B8...`. The digest may be split by spaces
+pub fn parse_anchor(s: &str) -> Result<DNSResourceRecord, String> {
+    let invalid = || {
+        format!(
+            "invalid trust anchor `{}`, expected `<zone> <key tag> <algorithm> <digest type> <digest>`",
+            s
+        )
+    };
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    if fields.len() < 5 {
+        return Err(invalid());
+    }
+    let hex = fields[4..].concat();
+    if hex.len() % 2 != 0 || !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let digest = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    Ok(DNSResourceRecord {
+        name: fields[0].to_string().to_domain_name(),
+        r#type: DNSType::DS as u16,
+        class: DNSClass::IN as u16,
+        ttl: 0,
+        rdata: Arc::new(DNSRdata::Ds {
+            key_tag: fields[1].parse().map_err(|_| invalid())?,
+            algorithm: fields[2].parse().map_err(|_| invalid())?,
+            digest_type: fields[3].parse().map_err(|_| invalid())?,
+            digest,
+        }),
+    })
+}
+
+/// Whether signatures made with `algorithm` can be checked: RSA with SHA-1
+/// or SHA-2, ECDSA and Ed25519
+pub fn is_supported(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+/// Tag of the key of a DNSKEY record, a checksum of its rdata
+/// (RFC 4034 appendix B)
+pub fn key_tag(key: &DNSRdata) -> u16 {
+    let rdata = key.canonical_bytes().unwrap_or_default();
+    let mut acc: u32 = 0;
+    for (i, x) in rdata.iter().enumerate() {
+        acc += match i % 2 {
+            0 => (*x as u32) << 8,
+            _ => *x as u32,
+        };
+    }
+    acc += acc >> 16;
+    acc as u16
+}
+
+/// Whether `ds` is the digest of `key`, a DNSKEY record (RFC 4034
+/// section 5.1.4)
+pub fn ds_matches(ds: &DNSRdata, key: &DNSResourceRecord) -> bool {
+    let (key_tag_ds, algorithm_ds, digest_type, digest) = match ds {
+        DNSRdata::Ds {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+        } => (key_tag, algorithm, digest_type, digest),
+        _ => return false,
+    };
+    match key.rdata.as_ref() {
+        DNSRdata::Dnskey { algorithm, .. } if algorithm == algorithm_ds => {}
+        _ => return false,
+    }
+    if key_tag(&key.rdata) != *key_tag_ds {
+        return false;
+    }
+    let algorithm = match digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return false,
+    };
+    let mut data = match lowercase(&key.name).to_bytes() {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    data.extend(key.rdata.canonical_bytes().unwrap_or_default());
+    digest::digest(algorithm, &data).as_ref() == digest.as_slice()
+}
+
+fn lowercase(name: &DomainName) -> DomainName {
+    name.iter().map(|x| x.to_ascii_lowercase()).collect()
+}
+
+/// What `sig`, an RRSIG record, signs over `rrset`: its own rdata but the
+/// signature, then the records in canonical form and order (RFC 4034
+/// section 3.1.8.1). `None` if the records cannot be covered by it
+pub fn signed_data(sig: &DNSResourceRecord, rrset: &[DNSResourceRecord]) -> Option<Vec<u8>> {
+    let (labels, original_ttl, signature) = match sig.rdata.as_ref() {
+        DNSRdata::Rrsig {
+            labels,
+            original_ttl,
+            signature,
+            ..
+        } => (*labels as usize, *original_ttl, signature),
+        _ => return None,
+    };
+    let mut data = sig.rdata.canonical_bytes().ok()?;
+    data.truncate(data.len() - signature.len());
+    // a record synthesized from a wildcard is signed as the wildcard
+    let owner = lowercase(&rrset.first()?.name);
+    let owner = match owner.len().checked_sub(labels)? {
+        0 => owner,
+        n => [vec![String::from("*")], owner[n..].to_vec()].concat(),
+    };
+    let owner = owner.to_bytes().ok()?;
+    let mut rdatas = rrset
+        .iter()
+        .map(|x| x.rdata.canonical_bytes())
+        .collect::<crate::rdns::util::Result<Vec<_>>>()
+        .ok()?;
+    rdatas.sort();
+    rdatas.dedup();
+    for rdata in rdatas {
+        data.extend_from_slice(&owner);
+        data.write_u16::<BigEndian>(rrset[0].r#type).ok()?;
+        data.write_u16::<BigEndian>(rrset[0].class).ok()?;
+        data.write_u32::<BigEndian>(original_ttl).ok()?;
+        data.write_u16::<BigEndian>(u16::try_from(rdata.len()).ok()?)
+            .ok()?;
+        data.extend(rdata);
+    }
+    Some(data)
+}
+
+/// Whether `signature` over `data` was made with `public_key`, the key of
+/// a DNSKEY record of `algorithm`
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let rsa = match algorithm {
+        5 | 7 => Some(&signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY),
+        8 => Some(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY),
+        10 => Some(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY),
+        _ => None,
+    };
+    if let Some(params) = rsa {
+        // the length of the exponent in one octet, or in two after a zero
+        // one, then the exponent and the modulus (RFC 3110 section 2)
+        let (len, rest) = match public_key {
+            [0, a, b, rest @ ..] => (u16::from_be_bytes([*a, *b]) as usize, rest),
+            [len, rest @ ..] => (*len as usize, rest),
+            [] => return false,
+        };
+        if len == 0 || rest.len() <= len {
+            return false;
+        }
+        let (e, n) = rest.split_at(len);
+        return RsaPublicKeyComponents { n, e }
+            .verify(params, data, signature)
+            .is_ok();
+    }
+    // ECDSA keys are the coordinates of the point, without the prefix
+    // telling they are not compressed (RFC 6605 section 4)
+    let (algorithm, key): (&'static dyn VerificationAlgorithm, Vec<u8>) = match algorithm {
+        13 => (
+            &signature::ECDSA_P256_SHA256_FIXED,
+            [&[4], public_key].concat(),
+        ),
+        14 => (
+            &signature::ECDSA_P384_SHA384_FIXED,
+            [&[4], public_key].concat(),
+        ),
+        15 => (&signature::ED25519, public_key.to_vec()),
+        _ => return false,
+    };
+    UnparsedPublicKey::new(algorithm, key)
+        .verify(data, signature)
+        .is_ok()
+}
+
+/// Whether one of `sigs` is a signature over `rrset`, valid at `now`
+/// (seconds since the epoch), made with one of `keys`, the DNSKEY records
+/// of its signer (RFC 4035 section 5.3)
+pub fn verify_rrset(
+    rrset: &[DNSResourceRecord],
+    sigs: &[DNSResourceRecord],
+    keys: &[DNSResourceRecord],
+    now: u32,
+) -> bool {
+    let r#type = match rrset.first() {
+        Some(x) => x.r#type,
+        None => return false,
+    };
+    sigs.iter().any(|sig| {
+        let (algorithm, tag, signer, signature) = match sig.rdata.as_ref() {
+            DNSRdata::Rrsig {
+                type_covered,
+                algorithm,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } if *type_covered == r#type
+                && !is_newer(now, *expiration)
+                && !is_newer(*inception, now) =>
+            {
+                (*algorithm, *key_tag, signer, signature)
+            }
+            _ => return false,
+        };
+        let data = match signed_data(sig, rrset) {
+            Some(x) => x,
+            None => return false,
+        };
+        keys.iter().any(|key| match key.rdata.as_ref() {
+            DNSRdata::Dnskey {
+                flags,
+                protocol: 3,
+                algorithm: key_algorithm,
+                public_key,
+            } => {
+                *key_algorithm == algorithm
+                    && flags & ZONE_KEY != 0
+                    && key.name.eq_ignore_case(signer)
+                    && key_tag(&key.rdata) == tag
+                    && verify_signature(algorithm, public_key, &data, signature)
+            }
+            _ => false,
+        })
+    })
+}
+
+/// The RRsets of `records` in the order they come in, each with the RRSIG
+/// records covering it
+pub fn rrsets(
+    records: &[DNSResourceRecord],
+) -> Vec<(Vec<DNSResourceRecord>, Vec<DNSResourceRecord>)> {
+    let mut rrsets: Vec<(Vec<DNSResourceRecord>, Vec<DNSResourceRecord>)> = vec![];
+    for x in records.iter().filter(|x| x.r#type != DNSType::RRSIG as u16) {
+        match rrsets
+            .iter_mut()
+            .find(|(rrset, _)| rrset[0].r#type == x.r#type && rrset[0].name.eq_ignore_case(&x.name))
+        {
+            Some((rrset, _)) => rrset.push(x.clone()),
+            None => rrsets.push((vec![x.clone()], vec![])),
+        }
+    }
+    for (rrset, sigs) in &mut rrsets {
+        sigs.extend(
+            records
+                .iter()
+                .filter(|x| x.name.eq_ignore_case(&rrset[0].name))
+                .filter(|x| {
+                    matches!(x.rdata.as_ref(), DNSRdata::Rrsig { type_covered, .. }
+                        if *type_covered == rrset[0].r#type)
+                })
+                .cloned(),
+        );
+    }
+    rrsets
+}
+
+/// The DNSKEY records of `zone` to check its signatures with, from
+/// `keyset`, its DNSKEY RRset with the signatures over it, given `ds`, the
+/// DS records of the zone vouched for by its parent or trusted as anchors.
+/// The keyset must be signed by a key one of them is the digest of
+/// (RFC 4035 section 5.2)
+pub fn trusted_keys(
+    zone: &DomainName,
+    keyset: &[DNSResourceRecord],
+    ds: &[DNSResourceRecord],
+    now: u32,
+) -> Result<Vec<DNSResourceRecord>, Verdict> {
+    let ds: Vec<&DNSRdata> = ds
+        .iter()
+        .filter(|x| x.name.eq_ignore_case(zone))
+        .map(|x| x.rdata.as_ref())
+        .filter(|x| {
+            matches!(x, DNSRdata::Ds { algorithm, digest_type, .. }
+                if is_supported(*algorithm) && matches!(digest_type, 1 | 2 | 4))
+        })
+        .collect();
+    // none the zone could be checked with is the same as none at all
+    if ds.is_empty() {
+        return Err(Verdict::Insecure);
+    }
+    let (keys, sigs): (Vec<_>, Vec<_>) = keyset
+        .iter()
+        .filter(|x| x.name.eq_ignore_case(zone))
+        .cloned()
+        .partition(|x| x.r#type == DNSType::DNSKEY as u16);
+    let entry: Vec<DNSResourceRecord> = keys
+        .iter()
+        .filter(|key| ds.iter().any(|ds| ds_matches(ds, key)))
+        .cloned()
+        .collect();
+    let name = zone.to_domain_name();
+    if entry.is_empty() {
+        return Err(Verdict::Bogus(format!(
+            "no DNSKEY of {} matches its DS",
+            name
+        )));
+    }
+    if !verify_rrset(&keys, &sigs, &entry, now) {
+        return Err(Verdict::Bogus(format!(
+            "DNSKEY of {} is not signed by a key matching its DS",
+            name
+        )));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::scenario::ZoneKey;
+
+    const NOW: u32 = 1_700_000_000;
+
+    fn a(name: &str, ip: [u8; 4]) -> DNSResourceRecord {
+        DNSResourceRecord {
+            name: name.to_string().to_domain_name(),
+            r#type: DNSType::A as u16,
+            class: DNSClass::IN as u16,
+            ttl: 300,
+            rdata: Arc::new(DNSRdata::A(ip.into())),
+        }
+    }
+
+    #[test]
+    fn root_anchors_match_their_key_tags() {
+        let anchors = root_anchors();
+        let tags: Vec<_> = anchors
+            .iter()
+            .map(|x| match x.rdata.as_ref() {
+                DNSRdata::Ds {
+                    key_tag, digest, ..
+                } => (*key_tag, digest.len()),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(tags, [(20326, 32), (38696, 32)]);
+        assert!(parse_anchor(". 20326 8 2 E06D").is_ok());
+        assert!(parse_anchor(". 20326 8 2 E06").is_err());
+        assert!(parse_anchor("20326 8 2 E06D").is_err());
+    }
+
+    #[test]
+    fn signatures_are_checked_against_the_keys() {
+        let key = ZoneKey::new("example.com", NOW);
+        let rrset = vec![
+            a("www.example.com", [192, 0, 2, 2]),
+            a("www.example.com", [192, 0, 2, 1]),
+        ];
+        let sigs = [key.sign(&rrset)];
+        let keys = [key.dnskey()];
+        assert!(verify_rrset(&rrset, &sigs, &keys, NOW));
+        // whatever the case and order of the records
+        let mut reordered = vec![rrset[1].clone(), rrset[0].clone()];
+        reordered[0].name = "WWW.Example.com".to_string().to_domain_name();
+        assert!(verify_rrset(&reordered, &sigs, &keys, NOW));
+        // but not once they changed
+        let tampered = vec![rrset[0].clone(), a("www.example.com", [192, 0, 2, 3])];
+        assert!(!verify_rrset(&tampered, &sigs, &keys, NOW));
+        // nor outside the validity period
+        assert!(!verify_rrset(&rrset, &sigs, &keys, NOW + 100_000));
+        // nor with another key
+        let other = ZoneKey::new("example.com", NOW);
+        assert!(!verify_rrset(&rrset, &sigs, &[other.dnskey()], NOW));
+    }
+
+    #[test]
+    fn keysets_are_trusted_through_their_ds() {
+        let key = ZoneKey::new("example.com", NOW);
+        let zone = "example.com".to_string().to_domain_name();
+        let keyset = vec![key.dnskey(), key.sign(&[key.dnskey()])];
+        let keys = trusted_keys(&zone, &keyset, &[key.ds()], NOW).unwrap();
+        assert_eq!(keys.len(), 1);
+        // another zone's DS does not vouch for them
+        let other = ZoneKey::new("example.com", NOW);
+        assert!(matches!(
+            trusted_keys(&zone, &keyset, &[other.ds()], NOW),
+            Err(Verdict::Bogus(_))
+        ));
+        // and without a DS the zone is not signed as far as we know
+        assert!(matches!(
+            trusted_keys(&zone, &keyset, &[], NOW),
+            Err(Verdict::Insecure)
+        ));
+    }
+}
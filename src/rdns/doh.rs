@@ -0,0 +1,117 @@
+use crate::rdns::http::{HttpConn, HttpRequest};
+use crate::rdns::records::{DNSPacket, DNSRdata, DNSType};
+use crate::rdns::util::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+/// Path queries are accepted on (RFC 8484 section 3)
+const DOH_PATH: &str = "/dns-query";
+
+const CONTENT_TYPE: &str = "application/dns-message";
+
+/// The DNS message in wire format a DNS-over-HTTPS request (RFC 8484)
+/// carries, or the status to reject the request with
+pub fn message(req: &HttpRequest) -> core::result::Result<Vec<u8>, u16> {
+    if req.path != DOH_PATH {
+        return Err(404);
+    }
+    match req.method.as_str() {
+        "GET" => req
+            .query
+            .split('&')
+            .find_map(|x| x.strip_prefix("dns="))
+            .and_then(|x| URL_SAFE_NO_PAD.decode(x.trim_end_matches('=')).ok())
+            .ok_or(400),
+        "POST" if !req.content_type.as_deref().is_some_and(is_dns_message) => Err(415),
+        "POST" => Ok(req.body.clone()),
+        _ => Err(405),
+    }
+}
+
+/// Whether the Content-Type header `value` is that of a DNS message. The
+/// media type is case-insensitive and may be followed by parameters
+/// (RFC 9110 section 8.3.1)
+fn is_dns_message(value: &str) -> bool {
+    let media_type = value.split(';').next().unwrap_or_default();
+    media_type.trim().eq_ignore_ascii_case(CONTENT_TYPE)
+}
+
+/// Sends `pkt` as the response, cacheable for as long as its records
+pub fn respond(conn: &mut HttpConn, pkt: &DNSPacket) -> Result<()> {
+    let headers = [
+        ("Content-Type", CONTENT_TYPE.to_string()),
+        ("Cache-Control", format!("max-age={}", max_age(pkt))),
+    ];
+    conn.write_response(200, &headers, &pkt.assemble()?)
+}
+
+/// The freshness lifetime of a response: the lowest TTL of its answers,
+/// or that of the SOA of a negative answer (RFC 8484 section 5.1)
+fn max_age(pkt: &DNSPacket) -> u32 {
+    if let Some(ttl) = pkt.answers.iter().map(|x| x.ttl).min() {
+        return ttl;
+    }
+    pkt.authorities
+        .iter()
+        .find(|x| x.r#type == DNSType::SOA as u16)
+        .map_or(0, |soa| match soa.rdata.as_ref() {
+            DNSRdata::Soa { minimum, .. } => soa.ttl.min(*minimum),
+            _ => soa.ttl,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::records::DNSQuestion;
+
+    fn query() -> Vec<u8> {
+        let mut pkt = DNSPacket::new(0, true);
+        pkt.questions.push(DNSQuestion::new(
+            vec!["example".into(), "com".into()],
+            DNSType::A as u16,
+        ));
+        pkt.assemble().unwrap()
+    }
+
+    fn request(
+        method: &str,
+        query: String,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+    ) -> HttpRequest {
+        HttpRequest {
+            method: method.into(),
+            path: DOH_PATH.into(),
+            query,
+            content_type: content_type.map(|x| x.into()),
+            body,
+        }
+    }
+
+    #[test]
+    fn get_carries_base64url_message() {
+        let msg = query();
+        let req = request(
+            "GET",
+            format!("dns={}", URL_SAFE_NO_PAD.encode(&msg)),
+            None,
+            vec![],
+        );
+        let res = message(&req).unwrap();
+        assert_eq!(res, msg);
+        assert_eq!(DNSPacket::from_raw(&res).unwrap().questions.len(), 1);
+    }
+
+    #[test]
+    fn post_carries_binary_message() {
+        let msg = query();
+        for content_type in [CONTENT_TYPE, "Application/DNS-Message; charset=binary"] {
+            let req = request("POST", String::new(), Some(content_type), msg.clone());
+            let res = message(&req).unwrap();
+            assert_eq!(DNSPacket::from_raw(&res).unwrap().questions.len(), 1);
+        }
+        let req = request("POST", String::new(), Some("application/dns-json"), msg);
+        assert_eq!(message(&req), Err(415));
+    }
+}
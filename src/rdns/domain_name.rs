@@ -1,21 +1,46 @@
+use super::error::RdnsError;
 use super::util::Result;
-use byteorder::WriteBytesExt;
+use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::HashMap;
 use std::io::Write;
 
 pub type DomainName = Vec<String>;
 
+/// Longest label, in octets
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Longest name on the wire, in octets, labels and their lengths included
+pub const MAX_NAME_LEN: usize = 255;
+
 pub trait ToDomainName {
+    /// Splits a dotted name into labels. The trailing dot of a fully
+    /// qualified name is optional, `.` alone is the root
     fn to_domain_name(&self) -> DomainName;
+    /// The name with its Unicode labels converted to A-labels (`xn--`)
+    /// by the IDNA rules (UTS #46), as they must be on the wire
+    fn to_ascii_domain_name(&self) -> Result<DomainName>;
 }
 
 impl ToDomainName for String {
     fn to_domain_name(&self) -> DomainName {
-        self.split(".").map(|x| x.to_string()).collect()
+        match self.strip_suffix('.').unwrap_or(self) {
+            "" => vec![],
+            x => x.split('.').map(|x| x.to_string()).collect(),
+        }
+    }
+
+    fn to_ascii_domain_name(&self) -> Result<DomainName> {
+        let ascii = idna::domain_to_ascii(self)
+            .map_err(|e| RdnsError::InvalidDomainName(format!("{}: {}", self, e)))?;
+        Ok(ascii.to_domain_name())
     }
 }
 
 pub trait ToReadableName {
     fn to_domain_name(&self) -> String;
+    /// Like `to_domain_name`, with the A-labels shown in Unicode. Those
+    /// that are not valid Punycode are left as they are
+    fn to_unicode_name(&self) -> String;
 }
 
 impl ToReadableName for DomainName {
@@ -31,20 +56,222 @@ impl ToReadableName for DomainName {
         res.remove(res.len() - 1);
         res
     }
+
+    fn to_unicode_name(&self) -> String {
+        let labels: DomainName = self
+            .iter()
+            .map(|x| match x.get(..4) {
+                Some(prefix) if prefix.eq_ignore_ascii_case("xn--") => {
+                    match idna::domain_to_unicode(x) {
+                        (unicode, Ok(())) => unicode,
+                        _ => x.clone(),
+                    }
+                }
+                _ => x.clone(),
+            })
+            .collect();
+        labels.to_domain_name()
+    }
+}
+
+/// Names compare without regard to the case of ASCII letters (RFC 4343)
+pub trait DomainNameExt {
+    /// Whether this name is `parent` itself or below it
+    fn is_subdomain_of(&self, parent: &DomainName) -> bool;
+    fn eq_ignore_case(&self, other: &DomainName) -> bool;
+    /// The dotted name in lowercase, as a key for maps and sets of names
+    fn canonical(&self) -> String;
+    /// Length of the name in uncompressed wire format
+    fn wire_len(&self) -> usize;
+}
+
+impl DomainNameExt for DomainName {
+    fn is_subdomain_of(&self, parent: &DomainName) -> bool {
+        self.len() >= parent.len()
+            && self
+                .iter()
+                .rev()
+                .zip(parent.iter().rev())
+                .all(|(x, y)| x.eq_ignore_ascii_case(y))
+    }
+
+    fn eq_ignore_case(&self, other: &DomainName) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .zip(other)
+                .all(|(x, y)| x.eq_ignore_ascii_case(y))
+    }
+
+    fn canonical(&self) -> String {
+        self.to_domain_name().to_ascii_lowercase()
+    }
+
+    fn wire_len(&self) -> usize {
+        self.iter().map(|x| x.len() + 1).sum::<usize>() + 1
+    }
+}
+
+/// Where the names, and their suffixes, already written to a message
+/// start, for compression
+pub type NameOffsets = HashMap<DomainName, u16>;
+
+/// Fails unless every label is 1 to 63 octets and the whole name at most
+/// 255 (RFC 1035 section 2.3.4)
+pub fn validate(name: &DomainName) -> Result<()> {
+    if let Some(label) = name
+        .iter()
+        .find(|x| x.is_empty() || x.len() > MAX_LABEL_LEN)
+    {
+        return Err(RdnsError::InvalidDomainName(format!(
+            "label `{}` is not 1 to 63 octets",
+            label
+        )));
+    }
+    if name.wire_len() > MAX_NAME_LEN {
+        return Err(RdnsError::InvalidDomainName("exceeds 255 octets".into()));
+    }
+    Ok(())
 }
 
 pub trait DomainNameToBytes {
     fn to_bytes(&self) -> Result<Vec<u8>>;
+    /// Appends the name to the message in `writer`, replacing the longest
+    /// suffix already written with a pointer to it
+    fn write_compressed(&self, writer: &mut Vec<u8>, offsets: &mut NameOffsets) -> Result<()>;
 }
 
 impl DomainNameToBytes for DomainName {
     fn to_bytes(&self) -> Result<Vec<u8>> {
+        validate(self)?;
         let mut res = Vec::new();
         for d in self {
             res.write_u8(d.len() as u8)?;
-            res.write(d.as_bytes())?;
+            res.write_all(d.as_bytes())?;
         }
         res.write_u8(0)?;
         Ok(res)
     }
+
+    fn write_compressed(&self, writer: &mut Vec<u8>, offsets: &mut NameOffsets) -> Result<()> {
+        validate(self)?;
+        for i in 0..self.len() {
+            let suffix = &self[i..];
+            if let Some(ptr) = offsets.get(suffix) {
+                writer.write_u16::<BigEndian>(0xC000 | ptr)?;
+                return Ok(());
+            }
+            // pointers only have 14 bits
+            if writer.len() <= 0x3FFF {
+                offsets.insert(suffix.to_vec(), writer.len() as u16);
+            }
+            writer.write_u8(self[i].len() as u8)?;
+            writer.write_all(self[i].as_bytes())?;
+        }
+        writer.write_u8(0)?;
+        Ok(())
+    }
+}
+
+/// (De)serializes a name as its dotted form, `.` for the root
+#[cfg(feature = "serde")]
+pub mod dotted {
+    use super::{DomainName, ToDomainName, ToReadableName};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(name: &DomainName, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&name.to_domain_name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DomainName, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(name.to_domain_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::records::ReadDomainName;
+    use std::io::Cursor;
+
+    #[test]
+    fn dotted_names_split_into_labels() {
+        assert!(".".to_string().to_domain_name().is_empty());
+        assert!("".to_string().to_domain_name().is_empty());
+        for name in ["example.com", "example.com."] {
+            assert_eq!(name.to_string().to_domain_name(), ["example", "com"]);
+        }
+        // and back, without the trailing dot
+        let name = "example.com.".to_string().to_domain_name();
+        assert_eq!(ToReadableName::to_domain_name(&name), "example.com");
+        assert_eq!(ToReadableName::to_domain_name(&vec![]), ".");
+    }
+
+    #[test]
+    fn labels_are_at_most_63_octets() {
+        for (len, ok) in [(63, true), (64, false)] {
+            let name = vec!["a".repeat(len), "example".to_string()];
+            assert_eq!(validate(&name).is_ok(), ok, "{}", len);
+            assert_eq!(name.to_bytes().is_ok(), ok, "{}", len);
+            let mut msg = vec![];
+            let written = name.write_compressed(&mut msg, &mut NameOffsets::new());
+            assert_eq!(written.is_ok(), ok, "{}", len);
+            // as another implementation would write it
+            let mut msg = vec![len as u8];
+            msg.extend("a".repeat(len).bytes());
+            msg.extend(b"\x07example\x00");
+            let read = Cursor::new(&msg[..]).read_domain_name();
+            match ok {
+                true => assert_eq!(read.unwrap(), name),
+                false => assert!(matches!(read, Err(RdnsError::InvalidDomainName(_)))),
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_names_read_back() {
+        let names = [
+            "example.com",
+            "www.example.com",
+            "WWW.example.com",
+            "mail.example.org",
+        ];
+        let mut msg = vec![];
+        let mut offsets = NameOffsets::new();
+        let mut starts = vec![];
+        for name in names {
+            starts.push(msg.len());
+            name.to_string()
+                .to_domain_name()
+                .write_compressed(&mut msg, &mut offsets)
+                .unwrap();
+        }
+        // www then a pointer to example.com, a label that differs in case
+        // is not the same suffix
+        assert_eq!(msg[starts[1]..starts[2]], [3, b'w', b'w', b'w', 0xC0, 0]);
+        assert_eq!(msg[starts[2] + 4..starts[3]], [0xC0, 0]);
+        let mut rdr = Cursor::new(&msg[..]);
+        for name in names {
+            assert_eq!(
+                rdr.read_domain_name().unwrap(),
+                name.to_string().to_domain_name()
+            );
+        }
+        assert_eq!(rdr.position() as usize, msg.len());
+    }
+
+    #[test]
+    fn unicode_names_go_on_the_wire_as_a_labels() {
+        let name = "bücher.example".to_string().to_ascii_domain_name().unwrap();
+        assert_eq!(name, ["xn--bcher-kva", "example"]);
+        assert_eq!(name.to_unicode_name(), "bücher.example");
+        assert_eq!(name.to_domain_name(), "xn--bcher-kva.example");
+        // ASCII names are only lowercased
+        let name = "WWW.Example.com"
+            .to_string()
+            .to_ascii_domain_name()
+            .unwrap();
+        assert_eq!(name, ["www", "example", "com"]);
+    }
 }
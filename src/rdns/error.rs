@@ -0,0 +1,79 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::AddrParseError;
+use std::string::FromUtf8Error;
+
+#[derive(Debug)]
+pub enum RdnsError {
+    Io(io::Error),
+    /// A malformed message
+    Parse(String),
+    /// A message that cannot be put on the wire, a field being too long
+    Encode(String),
+    InvalidDomainName(String),
+    /// Name whose resolution took too long
+    UpstreamTimeout(String),
+    /// Name whose resolution ended with an error, and the response code
+    ResolutionFailed(String, u8),
+    Tls(rustls::Error),
+    /// Invalid settings, or hosts, zone or certificate files
+    Config(String),
+}
+
+impl Display for RdnsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Parse(msg) => write!(f, "malformed message: {}", msg),
+            Self::Encode(msg) => write!(f, "cannot encode message: {}", msg),
+            Self::InvalidDomainName(msg) => write!(f, "invalid domain name: {}", msg),
+            Self::UpstreamTimeout(name) => write!(f, "resolving {} timed out", name),
+            Self::ResolutionFailed(name, rcode) => {
+                write!(f, "resolving {} failed with rcode {}", name, rcode)
+            }
+            Self::Tls(e) => write!(f, "{}", e),
+            Self::Config(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for RdnsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RdnsError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<FromUtf8Error> for RdnsError {
+    fn from(e: FromUtf8Error) -> Self {
+        Self::Parse(e.to_string())
+    }
+}
+
+impl From<AddrParseError> for RdnsError {
+    fn from(e: AddrParseError) -> Self {
+        Self::Config(e.to_string())
+    }
+}
+
+impl From<rustls::Error> for RdnsError {
+    fn from(e: rustls::Error) -> Self {
+        Self::Tls(e)
+    }
+}
+
+impl From<rustls::pki_types::InvalidDnsNameError> for RdnsError {
+    fn from(e: rustls::pki_types::InvalidDnsNameError) -> Self {
+        Self::InvalidDomainName(e.to_string())
+    }
+}
@@ -0,0 +1,174 @@
+use crate::rdns::domain_name::{ToDomainName, ToReadableName};
+use crate::rdns::error::RdnsError;
+use crate::rdns::util::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// Static addresses read from a file in the format of `/etc/hosts`: an
+/// address followed by one or more names per line, `#` starting a comment.
+/// Unicode names are kept as their A-labels, as they are asked for
+#[derive(Default)]
+pub struct Hosts {
+    /// Lowercase names without the trailing dot
+    addrs: HashMap<String, Vec<IpAddr>>,
+    /// Names of every address, as written and in the order listed, for
+    /// reverse lookups
+    names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl Hosts {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut hosts = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let ip: IpAddr = match fields.next() {
+                Some(x) => x.parse().map_err(|_| {
+                    RdnsError::Config(format!("line {}: invalid address `{}`", i + 1, x))
+                })?,
+                None => continue,
+            };
+            for name in fields {
+                let name = match name.is_ascii() {
+                    true => name.to_string(),
+                    false => name
+                        .to_string()
+                        .to_ascii_domain_name()
+                        .map_err(|e| RdnsError::Config(format!("line {}: {}", i + 1, e)))?
+                        .to_domain_name(),
+                };
+                let names = hosts.names.entry(ip).or_default();
+                let written = name.trim_end_matches('.');
+                if !names.iter().any(|x| x.eq_ignore_ascii_case(written)) {
+                    names.push(written.to_string());
+                }
+                let addrs = hosts.addrs.entry(normalize(&name)).or_default();
+                if !addrs.contains(&ip) {
+                    addrs.push(ip);
+                }
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// Addresses of `name`, of both families, if it is listed
+    pub fn lookup(&self, name: &str) -> Option<&[IpAddr]> {
+        self.addrs.get(&normalize(name)).map(|x| x.as_slice())
+    }
+
+    /// Names of the address a name under `in-addr.arpa` or `ip6.arpa`
+    /// stands for, if it is listed
+    pub fn reverse_lookup(&self, name: &str) -> Option<&[String]> {
+        self.names.get(&reverse_addr(name)?).map(|x| x.as_slice())
+    }
+}
+
+/// The address `name` is the reverse lookup name of (RFC 1035 section 3.5,
+/// RFC 3596 section 2.5)
+fn reverse_addr(name: &str) -> Option<IpAddr> {
+    let name = normalize(name);
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0u8; 4];
+        let labels: Vec<&str> = labels.split('.').collect();
+        if labels.len() != 4 {
+            return None;
+        }
+        for (octet, label) in octets.iter_mut().rev().zip(labels) {
+            if label.is_empty() || (label.len() > 1 && label.starts_with('0')) {
+                return None;
+            }
+            *octet = label.parse().ok()?;
+        }
+        return Some(Ipv4Addr::from(octets).into());
+    }
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let labels: Vec<&str> = labels.split('.').collect();
+    if labels.len() != 32 {
+        return None;
+    }
+    let mut addr = 0u128;
+    for label in labels.iter().rev() {
+        if label.len() != 1 {
+            return None;
+        }
+        addr = addr << 4 | u128::from_str_radix(label, 16).ok()?;
+    }
+    Some(Ipv6Addr::from(addr).into())
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_map_to_their_addresses() {
+        let hosts = Hosts::parse(
+            "# static hosts\n\
+             127.0.0.1 localhost\n\
+             \n\
+             192.168.1.10  MyHost.local myhost alias.local # the NAS\n\
+             fe80::10\tmyhost.local\n\
+             #192.168.1.99 commented.local\n",
+        )
+        .unwrap();
+        let v4: IpAddr = "192.168.1.10".parse().unwrap();
+        let v6: IpAddr = "fe80::10".parse().unwrap();
+        // of both lines, whatever the case
+        for name in ["myhost.local", "MYHOST.LOCAL."] {
+            assert_eq!(hosts.lookup(name), Some(&[v4, v6][..]));
+        }
+        assert_eq!(hosts.lookup("myhost"), Some(&[v4][..]));
+        assert_eq!(hosts.lookup("Alias.Local"), Some(&[v4][..]));
+        assert_eq!(
+            hosts.lookup("localhost"),
+            Some(&[IpAddr::from(Ipv4Addr::LOCALHOST)][..])
+        );
+        assert_eq!(hosts.lookup("commented.local"), None);
+        assert_eq!(hosts.lookup("the"), None);
+        assert!(Hosts::parse("192.168.1.300 bad.local\n").is_err());
+    }
+
+    #[test]
+    fn addresses_map_back_to_their_names() {
+        let hosts = Hosts::parse(
+            "192.168.1.10 myhost.local. alias.local\n\
+             192.168.1.10 MyHost.local\n\
+             2001:db8::1 six.local\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hosts.reverse_lookup("10.1.168.192.IN-ADDR.ARPA."),
+            Some(&["myhost.local".to_string(), "alias.local".to_string()][..])
+        );
+        let v6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        assert_eq!(
+            hosts.reverse_lookup(v6),
+            Some(&["six.local".to_string()][..])
+        );
+        assert_eq!(hosts.reverse_lookup("11.1.168.192.in-addr.arpa"), None);
+        assert_eq!(hosts.reverse_lookup("1.168.192.in-addr.arpa"), None);
+        assert_eq!(hosts.reverse_lookup("010.1.168.192.in-addr.arpa"), None);
+        assert_eq!(hosts.reverse_lookup("myhost.local"), None);
+    }
+
+    #[test]
+    fn unicode_names_are_listed_as_a_labels() {
+        let hosts = Hosts::parse("192.168.1.20 bücher.example\n").unwrap();
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(hosts.lookup("xn--bcher-kva.example"), Some(&[ip][..]));
+        assert_eq!(
+            hosts.reverse_lookup("20.1.168.192.in-addr.arpa"),
+            Some(&["xn--bcher-kva.example".to_string()][..])
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::rdns::tcp::Transport;
+use crate::rdns::util::Result;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time;
+
+/// Longest request line and headers accepted
+const MAX_HEADER_SIZE: usize = 8192;
+
+/// Longest body accepted
+const MAX_BODY_SIZE: usize = 65535;
+
+/// How long writing a response to a client may block the resolver
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// The part of the target after `?`, if any
+    pub query: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// A request read off a connection, or the status to reject it with
+pub type Request = core::result::Result<HttpRequest, u16>;
+
+/// An HTTP/1.1 client connection, plain or over TLS. One request is
+/// served per connection, which is closed after the response
+pub struct HttpConn {
+    stream: Box<dyn Transport>,
+    pub peer: SocketAddr,
+    /// Bytes received but not yet taken as a request
+    buf: Vec<u8>,
+    /// The request has been read, only the response is left to write
+    taken: bool,
+    pub last_active: time::Instant,
+}
+
+impl HttpConn {
+    pub fn new(stream: impl Transport + 'static, peer: SocketAddr) -> Result<Self> {
+        stream.socket().set_nonblocking(true)?;
+        Ok(Self {
+            stream: Box::new(stream),
+            peer,
+            buf: Vec::new(),
+            taken: false,
+            last_active: time::Instant::now(),
+        })
+    }
+
+    /// Whether the request is still to be read, what `read_request`
+    /// waits for
+    pub fn wants_read(&self) -> bool {
+        !self.taken
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.stream.wants_write()
+    }
+
+    /// Reads what has arrived without blocking and returns the request
+    /// once it is complete
+    pub fn read_request(&mut self) -> Result<Option<Request>> {
+        if self.taken {
+            return Ok(None);
+        }
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    self.last_active = time::Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let request = match self.parse() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.taken = true;
+        Ok(Some(request))
+    }
+
+    /// The request in `buf`, `None` if it is not complete yet
+    fn parse(&self) -> Option<Request> {
+        let header_end = match self.buf.windows(4).position(|x| x == b"\r\n\r\n") {
+            Some(x) => x,
+            None if self.buf.len() > MAX_HEADER_SIZE => return Some(Err(431)),
+            None => return None,
+        };
+        let head = match std::str::from_utf8(&self.buf[..header_end]) {
+            Ok(x) => x,
+            Err(_) => return Some(Err(400)),
+        };
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap().split(' ');
+        let (method, target) = match (request_line.next(), request_line.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => return Some(Err(400)),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut content_type = None;
+        let mut content_length = None;
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Some(Err(400)),
+            };
+            if name.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("content-length") {
+                content_length = match value.parse::<usize>() {
+                    Ok(x) if x > MAX_BODY_SIZE => return Some(Err(413)),
+                    Ok(x) => Some(x),
+                    Err(_) => return Some(Err(400)),
+                };
+            }
+        }
+        let content_length = match content_length {
+            Some(x) => x,
+            None if method == "POST" => return Some(Err(411)),
+            None => 0,
+        };
+        let body = header_end + 4;
+        if self.buf.len() < body + content_length {
+            return None;
+        }
+        Some(Ok(HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            content_type,
+            body: self.buf[body..body + content_length].to_vec(),
+        }))
+    }
+
+    /// Sends a response with `headers` besides those describing the body
+    pub fn write_response(
+        &mut self,
+        status: u16,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+        // block for the write, a large response may not fit the send buffer
+        let socket = self.stream.socket();
+        socket.set_nonblocking(false)?;
+        socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        self.stream.write_all(&[head.as_bytes(), body].concat())?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    pub fn write_error(&mut self, status: u16) -> Result<()> {
+        self.write_response(status, &[], &[])
+    }
+}
+
+impl AsRawFd for HttpConn {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.socket().as_raw_fd()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        _ => "Error",
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt::Write;
+use std::time;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Counters kept since startup, exposed in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    pub queries: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub hosts_answers: u64,
+    pub zone_answers: u64,
+    /// Queries for names of the blocklist
+    pub blocked: u64,
+    /// Queries sent to other servers while resolving
+    pub upstream_queries: u64,
+    pub servfails: u64,
+    pub nxdomains: u64,
+    /// Cache entries dropped for room before they expired
+    pub cache_evictions: u64,
+    /// Expired cache entries dropped by the periodic sweep
+    pub cache_expirations: u64,
+    latency: Histogram,
+}
+
+/// Cumulative counts of observations at or below each bucket's bound
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// Records the time taken to answer a query
+    pub fn observe_latency(&mut self, latency: time::Duration) {
+        let secs = latency.as_secs_f64();
+        let h = &mut self.latency;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(h.buckets.iter_mut()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += secs;
+    }
+
+    /// The metrics in the text exposition format, version 0.0.4
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("queries", "Queries answered", self.queries),
+            (
+                "cache_hits",
+                "Queries answered from the cache",
+                self.cache_hits,
+            ),
+            (
+                "cache_misses",
+                "Queries that needed resolving",
+                self.cache_misses,
+            ),
+            (
+                "hosts_answers",
+                "Queries answered from the hosts file",
+                self.hosts_answers,
+            ),
+            (
+                "zone_answers",
+                "Queries answered from local zones",
+                self.zone_answers,
+            ),
+            ("blocked", "Queries for blocked names", self.blocked),
+            (
+                "upstream_queries",
+                "Queries sent to other servers",
+                self.upstream_queries,
+            ),
+            (
+                "servfails",
+                "Queries answered with SERVFAIL",
+                self.servfails,
+            ),
+            (
+                "nxdomains",
+                "Queries answered with NXDOMAIN",
+                self.nxdomains,
+            ),
+            (
+                "cache_evictions",
+                "Cache entries evicted to make room",
+                self.cache_evictions,
+            ),
+            (
+                "cache_expirations",
+                "Expired cache entries swept",
+                self.cache_expirations,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP rdns_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE rdns_{}_total counter", name);
+            let _ = writeln!(out, "rdns_{}_total {}", name, value);
+        }
+        let name = "rdns_resolution_latency_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to answer queries", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.latency.buckets) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.latency.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.latency.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.latency.count);
+        out
+    }
+}
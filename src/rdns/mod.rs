@@ -1,4 +1,21 @@
+pub mod blocklist;
+pub mod cache;
 pub mod dns;
+pub mod dnssec;
+pub mod doh;
 pub mod domain_name;
+pub mod error;
+pub mod hosts;
+pub mod http;
+pub mod metrics;
+pub mod query_log;
 pub mod records;
-mod util;
+pub mod resolver;
+#[cfg(test)]
+mod scenario;
+pub mod tcp;
+pub mod tls;
+pub mod transfer;
+pub mod util;
+pub mod worker;
+pub mod zone;
@@ -0,0 +1,130 @@
+use crate::rdns::domain_name::DomainName;
+use crate::rdns::records::{DNSQuestion, DNSRcode, DNSType};
+use crate::rdns::util::Result;
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Where the answer to a query came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Cache,
+    Hosts,
+    Zone,
+    Blocklist,
+    /// Resolved by asking other servers
+    Upstream,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cache => "cache",
+            Self::Hosts => "hosts",
+            Self::Zone => "zone",
+            Self::Blocklist => "blocklist",
+            Self::Upstream => "upstream",
+        })
+    }
+}
+
+/// Append-only log with one line per answered query, in the form of
+///
+/// `<time> <client ip> <name> <type> <rcode> <source> <elapsed>ms`
+///
+/// where the source is one of `cache`, `hosts`, `zone`, `blocklist` and
+/// `upstream`
+pub struct QueryLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl QueryLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(Self::open_file(path)?),
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<File> {
+        Ok(OpenOptions::new().create(true).append(true).open(path)?)
+    }
+
+    /// Reopens the file at the same path, e.g. after it has been rotated
+    pub fn reopen(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer = BufWriter::new(Self::open_file(&self.path)?);
+        Ok(())
+    }
+
+    pub fn log(
+        &mut self,
+        time: DateTime<Local>,
+        client: IpAddr,
+        question: &DNSQuestion,
+        rcode: u8,
+        source: Source,
+        elapsed_ms: u128,
+    ) -> Result<()> {
+        writeln!(
+            self.writer,
+            "{} {} {} {} {:?} {} {}ms",
+            time.to_rfc3339(),
+            client,
+            escaped(&question.qname),
+            DNSType::from_num(question.qtype),
+            DNSRcode::from_num(rcode as u16),
+            source,
+            elapsed_ms
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `name` in presentation format, with the octets other than printable
+/// ASCII as `\DDD`, and dots and backslashes within labels escaped, so that
+/// a name cannot break up the line or forge another (RFC 4343 section 2.1)
+fn escaped(name: &DomainName) -> String {
+    if name.is_empty() {
+        return ".".into();
+    }
+    let labels: Vec<String> = name
+        .iter()
+        .map(|label| {
+            let mut res = String::new();
+            for &b in label.as_bytes() {
+                match b {
+                    b'.' | b'\\' => {
+                        res.push('\\');
+                        res.push(b as char);
+                    }
+                    _ if b.is_ascii_graphic() => res.push(b as char),
+                    _ => res.push_str(&format!("\\{:03}", b)),
+                }
+            }
+            res
+        })
+        .collect();
+    labels.join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_escaped() {
+        let name = vec!["a b\n".to_string(), "c.d\\".to_string(), "é".to_string()];
+        assert_eq!(escaped(&name), "a\\032b\\010.c\\.d\\\\.\\195\\169");
+        assert_eq!(escaped(&vec![]), ".");
+    }
+}
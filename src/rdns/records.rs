@@ -1,14 +1,24 @@
-use crate::rdns::domain_name::{DomainName, DomainNameToBytes, ToReadableName};
+#[cfg(feature = "serde")]
+use crate::rdns::domain_name::dotted;
+use crate::rdns::domain_name::{
+    DomainName, DomainNameExt, DomainNameToBytes, NameOffsets, ToReadableName, MAX_LABEL_LEN,
+    MAX_NAME_LEN,
+};
+use crate::rdns::error::RdnsError;
 use crate::rdns::util::{ReadExt, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::fmt::Debug;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
 use std::io::{Cursor, Write};
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DNSPacket {
     /// DNS packet header part
     pub header: DNSHeader,
@@ -36,8 +46,26 @@ impl DNSRcode {
     }
 }
 
+#[derive(FromPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DNSOpcode {
+    Query = 0,
+    IQuery = 1,
+    Status = 2,
+    Notify = 4,
+    Update = 5,
+
+    Unknown = -1,
+}
+
+impl DNSOpcode {
+    pub fn from_num(n: u8) -> Self {
+        FromPrimitive::from_u8(n).unwrap_or(Self::Unknown)
+    }
+}
+
 /// DNS packet header part
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DNSHeader {
     /// id
     pub id: u16,
@@ -68,38 +96,287 @@ pub struct DNSHeader {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DNSQuestion {
+    #[cfg_attr(feature = "serde", serde(with = "dotted"))]
     pub qname: DomainName,
     pub qtype: u16,
     pub qclass: u16,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DNSResourceRecord {
+    #[cfg_attr(feature = "serde", serde(with = "dotted"))]
     pub name: DomainName,
     pub r#type: u16,
     pub class: u16,
     /// the time interval (in seconds) that the resource record
     /// may be cached before it should be discarded
     pub ttl: u32,
-    pub rdlength: u16,
-    pub rdata: Rc<DNSRdata>,
+    pub rdata: Arc<DNSRdata>,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DNSRdata {
     A(Ipv4Addr),
     Aaaa(Ipv6Addr),
-    Cname(DomainName),
-    Mx(u16, DomainName),
-    Ns(DomainName),
-    Txt(String),
+    Cname(#[cfg_attr(feature = "serde", serde(with = "dotted"))] DomainName),
+    Mx(
+        u16,
+        #[cfg_attr(feature = "serde", serde(with = "dotted"))] DomainName,
+    ),
+    Ns(#[cfg_attr(feature = "serde", serde(with = "dotted"))] DomainName),
+    Ptr(#[cfg_attr(feature = "serde", serde(with = "dotted"))] DomainName),
+    /// Target the names below the owner are aliases to (RFC 6672)
+    Dname(#[cfg_attr(feature = "serde", serde(with = "dotted"))] DomainName),
+    /// Character-strings, each at most 255 bytes. They are arbitrary
+    /// octets, not necessarily text
+    Txt(Vec<Vec<u8>>),
+    Apl(Vec<AplItem>),
+    Soa {
+        /// Primary nameserver of the zone
+        #[cfg_attr(feature = "serde", serde(with = "dotted"))]
+        mname: DomainName,
+        /// Mailbox of the person responsible, with the `@` as a dot
+        #[cfg_attr(feature = "serde", serde(with = "dotted"))]
+        rname: DomainName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        /// TTL of negative answers from the zone (RFC 2308)
+        minimum: u32,
+    },
+    Tlsa {
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        /// Certificate association data
+        data: Vec<u8>,
+    },
+    Sshfp {
+        algorithm: u8,
+        fp_type: u8,
+        fingerprint: Vec<u8>,
+    },
+    Cert {
+        cert_type: u16,
+        key_tag: u16,
+        algorithm: u8,
+        certificate: Vec<u8>,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        #[cfg_attr(feature = "serde", serde(with = "dotted"))]
+        target: DomainName,
+    },
+    Uri {
+        priority: u16,
+        weight: u16,
+        target: String,
+    },
+    Caa {
+        /// Bit 7 is the issuer critical flag
+        flags: u8,
+        /// Property, e.g. `issue`
+        tag: String,
+        /// Arbitrary octets, whose meaning depends on the tag
+        value: Vec<u8>,
+    },
+    /// Signature over an RRset (RFC 4034 section 3)
+    Rrsig {
+        type_covered: u16,
+        algorithm: u8,
+        /// Labels of the owner of the RRset, less a leading `*`
+        labels: u8,
+        original_ttl: u32,
+        /// Validity period, in seconds since the epoch modulo 2^32
+        expiration: u32,
+        inception: u32,
+        /// Tag of the DNSKEY the signature was made with
+        key_tag: u16,
+        /// Zone of that DNSKEY
+        #[cfg_attr(feature = "serde", serde(with = "dotted"))]
+        signer: DomainName,
+        signature: Vec<u8>,
+    },
+    /// Public key of a zone (RFC 4034 section 2)
+    Dnskey {
+        /// Bit 7 marks a zone key, bit 15 a secure entry point
+        flags: u16,
+        /// Always 3
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    /// Digest of a DNSKEY of the zone it is the owner of, held by the
+    /// parent zone (RFC 4034 section 5)
+    Ds {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    /// SVCB and HTTPS records (RFC 9460), which share their format
+    Svcb {
+        /// 0 for alias mode, otherwise the order to try the services in
+        priority: u16,
+        #[cfg_attr(feature = "serde", serde(with = "dotted"))]
+        target: DomainName,
+        /// SvcParams as key and raw value, in the order received
+        params: Vec<(u16, Vec<u8>)>,
+    },
+    /// EDNS0 pseudo-record (RFC 6891), whose parameters live in the class
+    /// and TTL fields of the record
+    Opt {
+        /// Largest UDP payload the sender can receive, the record's class
+        udp_payload_size: u16,
+        /// Upper 8 bits of the 12-bit rcode
+        extended_rcode: u8,
+        version: u8,
+        /// DNSSEC OK
+        dnssec_ok: bool,
+        /// Remaining flag bits, zero unless set by the sender
+        z: u16,
+        options: Vec<EdnsOption>,
+    },
     Other(Vec<u8>),
 }
 
+/// An APL address prefix item (RFC 3123)
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AplItem {
+    /// Address family, 1 for IPv4 and 2 for IPv6
+    pub family: u16,
+    pub prefix: u8,
+    /// Negation flag
+    pub negation: bool,
+    /// Address part, with trailing zero octets omitted
+    pub afdpart: Vec<u8>,
+}
+
+/// An option in the rdata of an OPT record
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
 impl DNSRdata {
-    fn to_bytes(&self, writer: &mut Vec<u8>) -> Result<()> {
-        let buf: Vec<u8> = match self {
+    /// Writes the rdata preceded by its length as written. With `offsets`,
+    /// the names in the rdata of the types from RFC 1035 are compressed, as
+    /// only those may be
+    fn to_bytes(&self, writer: &mut Vec<u8>, offsets: Option<&mut NameOffsets>) -> Result<()> {
+        let start = writer.len();
+        writer.write_u16::<BigEndian>(0)?;
+        match (self, offsets) {
+            (Self::Cname(dn) | Self::Ns(dn) | Self::Ptr(dn), Some(offsets)) => {
+                dn.write_compressed(writer, offsets)?
+            }
+            (Self::Mx(pref, dn), Some(offsets)) => {
+                writer.write_u16::<BigEndian>(*pref)?;
+                dn.write_compressed(writer, offsets)?;
+            }
+            (
+                Self::Soa {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                },
+                Some(offsets),
+            ) => {
+                mname.write_compressed(writer, offsets)?;
+                rname.write_compressed(writer, offsets)?;
+                for x in [serial, refresh, retry, expire, minimum] {
+                    writer.write_u32::<BigEndian>(*x)?;
+                }
+            }
+            _ => writer.write_all(&self.rdata_bytes()?)?,
+        }
+        let rdlength = u16::try_from(writer.len() - start - 2)
+            .map_err(|_| RdnsError::Encode("rdata exceeds 65535 bytes".into()))?;
+        writer[start..start + 2].copy_from_slice(&rdlength.to_be_bytes());
+        Ok(())
+    }
+
+    /// The rdata in the form signatures are made over, uncompressed with
+    /// the names in it in lowercase (RFC 4034 section 6.2)
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        let lower =
+            |dn: &DomainName| -> DomainName { dn.iter().map(|x| x.to_ascii_lowercase()).collect() };
+        let rdata = match self {
+            Self::Cname(dn) => Self::Cname(lower(dn)),
+            Self::Ns(dn) => Self::Ns(lower(dn)),
+            Self::Ptr(dn) => Self::Ptr(lower(dn)),
+            Self::Dname(dn) => Self::Dname(lower(dn)),
+            Self::Mx(pref, dn) => Self::Mx(*pref, lower(dn)),
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => Self::Soa {
+                mname: lower(mname),
+                rname: lower(rname),
+                serial: *serial,
+                refresh: *refresh,
+                retry: *retry,
+                expire: *expire,
+                minimum: *minimum,
+            },
+            Self::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => Self::Srv {
+                priority: *priority,
+                weight: *weight,
+                port: *port,
+                target: lower(target),
+            },
+            Self::Rrsig {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => Self::Rrsig {
+                type_covered: *type_covered,
+                algorithm: *algorithm,
+                labels: *labels,
+                original_ttl: *original_ttl,
+                expiration: *expiration,
+                inception: *inception,
+                key_tag: *key_tag,
+                signer: lower(signer),
+                signature: signature.clone(),
+            },
+            _ => return self.rdata_bytes(),
+        };
+        rdata.rdata_bytes()
+    }
+
+    /// The rdata on the wire, uncompressed and without the rdlength prefix
+    fn rdata_bytes(&self) -> Result<Vec<u8>> {
+        Ok(match self {
             Self::A(ip) => Vec::from(ip.octets()),
             Self::Aaaa(ip) => Vec::from(ip.octets()),
             Self::Cname(dn) => dn.to_bytes()?,
@@ -110,12 +387,186 @@ impl DNSRdata {
                 v
             }
             Self::Ns(dn) => dn.to_bytes()?,
-            Self::Txt(s) => Vec::from(s.as_bytes()),
+            Self::Ptr(dn) => dn.to_bytes()?,
+            Self::Dname(dn) => dn.to_bytes()?,
+            Self::Txt(strings) => {
+                let mut v = Vec::new();
+                for s in strings {
+                    let len = u8::try_from(s.len())
+                        .map_err(|_| RdnsError::Encode("TXT string exceeds 255 bytes".into()))?;
+                    v.write_u8(len)?;
+                    v.extend_from_slice(s);
+                }
+                v
+            }
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                let mut v = mname.to_bytes()?;
+                v.append(&mut rname.to_bytes()?);
+                for x in [serial, refresh, retry, expire, minimum] {
+                    v.write_u32::<BigEndian>(*x)?;
+                }
+                v
+            }
+            Self::Apl(items) => {
+                let mut v = Vec::new();
+                for item in items {
+                    item.to_bytes(&mut v)?;
+                }
+                v
+            }
+            Self::Tlsa {
+                usage,
+                selector,
+                matching_type,
+                data,
+            } => {
+                let mut v = vec![*usage, *selector, *matching_type];
+                v.extend_from_slice(data);
+                v
+            }
+            Self::Sshfp {
+                algorithm,
+                fp_type,
+                fingerprint,
+            } => {
+                let mut v = vec![*algorithm, *fp_type];
+                v.extend_from_slice(fingerprint);
+                v
+            }
+            Self::Cert {
+                cert_type,
+                key_tag,
+                algorithm,
+                certificate,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*cert_type)?;
+                v.write_u16::<BigEndian>(*key_tag)?;
+                v.write_u8(*algorithm)?;
+                v.extend_from_slice(certificate);
+                v
+            }
+            Self::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*priority)?;
+                v.write_u16::<BigEndian>(*weight)?;
+                v.write_u16::<BigEndian>(*port)?;
+                // must not be compressed (RFC 2782)
+                v.append(&mut target.to_bytes()?);
+                v
+            }
+            Self::Uri {
+                priority,
+                weight,
+                target,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*priority)?;
+                v.write_u16::<BigEndian>(*weight)?;
+                v.extend_from_slice(target.as_bytes());
+                v
+            }
+            Self::Caa { flags, tag, value } => {
+                let tag_len = u8::try_from(tag.len())
+                    .map_err(|_| RdnsError::Encode("CAA tag too long".into()))?;
+                let mut v = vec![*flags, tag_len];
+                v.extend_from_slice(tag.as_bytes());
+                v.extend_from_slice(value);
+                v
+            }
+            Self::Rrsig {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*type_covered)?;
+                v.write_u8(*algorithm)?;
+                v.write_u8(*labels)?;
+                for x in [original_ttl, expiration, inception] {
+                    v.write_u32::<BigEndian>(*x)?;
+                }
+                v.write_u16::<BigEndian>(*key_tag)?;
+                // must not be compressed (RFC 4034 section 3.1.7)
+                v.append(&mut signer.to_bytes()?);
+                v.extend_from_slice(signature);
+                v
+            }
+            Self::Dnskey {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*flags)?;
+                v.write_u8(*protocol)?;
+                v.write_u8(*algorithm)?;
+                v.extend_from_slice(public_key);
+                v
+            }
+            Self::Ds {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*key_tag)?;
+                v.write_u8(*algorithm)?;
+                v.write_u8(*digest_type)?;
+                v.extend_from_slice(digest);
+                v
+            }
+            Self::Svcb {
+                priority,
+                target,
+                params,
+            } => {
+                let mut v = Vec::new();
+                v.write_u16::<BigEndian>(*priority)?;
+                v.extend_from_slice(&target.to_bytes()?);
+                for (key, value) in params {
+                    let len = u16::try_from(value.len())
+                        .map_err(|_| RdnsError::Encode("SvcParam value too long".into()))?;
+                    v.write_u16::<BigEndian>(*key)?;
+                    v.write_u16::<BigEndian>(len)?;
+                    v.extend_from_slice(value);
+                }
+                v
+            }
+            Self::Opt { options, .. } => {
+                let mut v = Vec::new();
+                for opt in options {
+                    let len = u16::try_from(opt.data.len())
+                        .map_err(|_| RdnsError::Encode("EDNS option too long".into()))?;
+                    v.write_u16::<BigEndian>(opt.code)?;
+                    v.write_u16::<BigEndian>(len)?;
+                    v.extend_from_slice(&opt.data);
+                }
+                v
+            }
             Self::Other(raw) => raw.to_vec(),
-        };
-        writer.write_u16::<BigEndian>(buf.len() as u16)?;
-        writer.write(&buf)?;
-        Ok(())
+        })
     }
 
     fn get_type(&self) -> Option<DNSType> {
@@ -125,8 +576,23 @@ impl DNSRdata {
             Self::Cname(_) => DNSType::CNAME,
             Self::Mx(_, _) => DNSType::MX,
             Self::Ns(_) => DNSType::NS,
+            Self::Ptr(_) => DNSType::PTR,
+            Self::Dname(_) => DNSType::DNAME,
             Self::Txt(_) => DNSType::TXT,
-            Self::Other(_) => DNSType::NotImplemented,
+            Self::Apl(_) => DNSType::APL,
+            Self::Soa { .. } => DNSType::SOA,
+            Self::Tlsa { .. } => DNSType::TLSA,
+            Self::Sshfp { .. } => DNSType::SSHFP,
+            Self::Cert { .. } => DNSType::CERT,
+            Self::Srv { .. } => DNSType::SRV,
+            Self::Uri { .. } => DNSType::URI,
+            Self::Caa { .. } => DNSType::CAA,
+            Self::Rrsig { .. } => DNSType::RRSIG,
+            Self::Dnskey { .. } => DNSType::DNSKEY,
+            Self::Ds { .. } => DNSType::DS,
+            Self::Opt { .. } => DNSType::OPT,
+            // SVCB or HTTPS, the record's own type tells which
+            Self::Svcb { .. } | Self::Other(_) => DNSType::NotImplemented,
         };
         if t != DNSType::NotImplemented {
             Some(t)
@@ -136,6 +602,119 @@ impl DNSRdata {
     }
 }
 
+impl EdnsOption {
+    pub fn from_raw_multi(rdr: &mut Cursor<&[u8]>, rdlength: u16) -> Result<Vec<Self>> {
+        let end = rdr.position() + rdlength as u64;
+        let mut res = Vec::new();
+        while rdr.position() < end {
+            let code = rdr.read_u16::<BigEndian>()?;
+            let len = rdr.read_u16::<BigEndian>()?;
+            res.push(Self {
+                code,
+                data: rdr.read_raw(len as usize)?,
+            });
+        }
+        if rdr.position() != end {
+            return Err(RdnsError::Parse("EDNS option exceeds rdata".into()));
+        }
+        Ok(res)
+    }
+}
+
+impl AplItem {
+    /// Longest address part of an address family (RFC 3123 section 4)
+    fn max_afd_len(family: u16) -> usize {
+        match family {
+            1 => 4,
+            2 => 16,
+            _ => 0x7F,
+        }
+    }
+
+    pub fn from_raw_multi(rdr: &mut Cursor<&[u8]>, rdlength: u16) -> Result<Vec<Self>> {
+        let end = rdr.position() + rdlength as u64;
+        let mut res = Vec::new();
+        while rdr.position() < end {
+            let family = rdr.read_u16::<BigEndian>()?;
+            let prefix = rdr.read_u8()?;
+            // N(1 bit) | AFDLENGTH(7 bits)
+            let tmp = rdr.read_u8()?;
+            let len = (tmp & 0x7F) as usize;
+            if len > Self::max_afd_len(family) {
+                return Err(RdnsError::Parse("APL address part too long".into()));
+            }
+            let afdpart = rdr.read_raw(len)?;
+            res.push(Self {
+                family,
+                prefix,
+                negation: tmp >> 7 == 1,
+                afdpart,
+            });
+        }
+        if rdr.position() != end {
+            return Err(RdnsError::Parse("APL item exceeds rdata".into()));
+        }
+        Ok(res)
+    }
+
+    pub fn to_bytes(&self, writer: &mut Vec<u8>) -> Result<()> {
+        // trailing zero octets must not be sent
+        let len = self
+            .afdpart
+            .iter()
+            .rposition(|x| *x != 0)
+            .map_or(0, |x| x + 1);
+        if len > Self::max_afd_len(self.family) {
+            return Err(RdnsError::Encode("APL address part too long".into()));
+        }
+        writer.write_u16::<BigEndian>(self.family)?;
+        writer.write_u8(self.prefix)?;
+        writer.write_u8((self.negation as u8) << 7 | len as u8)?;
+        writer.write_all(&self.afdpart[..len])?;
+        Ok(())
+    }
+}
+
+/// Reads length-prefixed character-strings until `rdlength` is consumed
+fn read_character_strings(rdr: &mut Cursor<&[u8]>, rdlength: u16) -> Result<Vec<Vec<u8>>> {
+    let end = rdr.position() + rdlength as u64;
+    let mut res = Vec::new();
+    while rdr.position() < end {
+        let len = rdr.read_u8()?;
+        res.push(rdr.read_raw(len as usize)?);
+    }
+    Ok(res)
+}
+
+/// Smallest rdata of a type, counting names as the root name.
+/// A shorter rdlength would make parsing read into the next record.
+/// Address records are only parsed, and so only checked, in class IN
+fn min_rdlength(r#type: DNSType, is_in: bool) -> u16 {
+    match r#type {
+        DNSType::A if is_in => 4,
+        DNSType::AAAA if is_in => 16,
+        DNSType::CNAME | DNSType::NS | DNSType::PTR | DNSType::DNAME => 1,
+        DNSType::MX => 3,
+        DNSType::SOA => 22,
+        DNSType::TLSA => 3,
+        DNSType::SSHFP => 2,
+        DNSType::CERT => 5,
+        DNSType::SRV => 7,
+        DNSType::URI => 4,
+        DNSType::CAA => 2,
+        DNSType::SVCB | DNSType::HTTPS => 3,
+        DNSType::RRSIG => 19,
+        DNSType::DNSKEY | DNSType::DS => 4,
+        _ => 0,
+    }
+}
+
+/// More pointers than this in one name can only come from a loop
+const MAX_POINTERS: usize = 128;
+
+/// A name of at most 255 octets cannot hold more labels than this
+const MAX_LABELS: usize = 127;
+
 pub trait ReadDomainName {
     fn read_domain_name(&mut self) -> Result<DomainName>;
 }
@@ -143,6 +722,11 @@ pub trait ReadDomainName {
 impl ReadDomainName for Cursor<&[u8]> {
     fn read_domain_name(&mut self) -> Result<DomainName> {
         let mut res: DomainName = Vec::new();
+        // the terminating root label
+        let mut len = 1;
+        let mut pointers = 0;
+        // where to continue after the name, once a pointer is followed
+        let mut end = None;
         loop {
             let cnt = self.read_u8()?;
             if cnt == 0 {
@@ -152,16 +736,39 @@ impl ReadDomainName for Cursor<&[u8]> {
             if cnt >> 6 == 0x3 {
                 self.set_position(self.position() - 1);
                 let ptr = self.read_u16::<BigEndian>()? & 0x3FFFu16;
-                let cur_pos = self.position();
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(RdnsError::Parse(
+                        "compression pointer loop in domain name".into(),
+                    ));
+                }
+                end.get_or_insert(self.position());
                 self.set_position(ptr as u64);
-                let dn = self.read_domain_name()?;
-                self.set_position(cur_pos);
-                res.extend(dn);
-                return Ok(res);
+                continue;
+            }
+            // 01 and 10 in the top bits mark label types that RFC 6891
+            // deprecated
+            if cnt as usize > MAX_LABEL_LEN {
+                return Err(RdnsError::InvalidDomainName(
+                    "label exceeds 63 octets".into(),
+                ));
+            }
+            // checked on its own, the octet limit only implies it
+            if res.len() == MAX_LABELS {
+                return Err(RdnsError::Parse(
+                    "more than 127 labels in domain name".into(),
+                ));
+            }
+            len += cnt as usize + 1;
+            if len > MAX_NAME_LEN {
+                return Err(RdnsError::InvalidDomainName("exceeds 255 octets".into()));
             }
             let d = self.read_string_exact(cnt as usize)?;
             res.push(d);
         }
+        if let Some(end) = end {
+            self.set_position(end);
+        }
         Ok(res)
     }
 }
@@ -184,16 +791,75 @@ pub enum DNSType {
     MINFO = 14,
     MX = 15,
     TXT = 16,
+    RP = 17,
     AFSDB = 18,
+    X25 = 19,
+    ISDN = 20,
+    RT = 21,
+    NSAP = 22,
+    NSAPPTR = 23,
+    SIG = 24,
+    KEY = 25,
+    PX = 26,
+    GPOS = 27,
     AAAA = 28,
+    LOC = 29,
+    NXT = 30,
+    EID = 31,
+    NIMLOC = 32,
+    SRV = 33,
+    ATMA = 34,
+    NAPTR = 35,
+    KX = 36,
+    CERT = 37,
+    A6 = 38,
+    DNAME = 39,
+    SINK = 40,
     OPT = 41,
     APL = 42,
+    DS = 43,
+    SSHFP = 44,
     IPSECKEY = 45,
     RRSIG = 46,
+    NSEC = 47,
+    DNSKEY = 48,
+    DHCID = 49,
+    NSEC3 = 50,
+    NSEC3PARAM = 51,
+    TLSA = 52,
+    SMIMEA = 53,
+    HIP = 55,
+    NINFO = 56,
+    RKEY = 57,
+    TALINK = 58,
+    CDS = 59,
+    CDNSKEY = 60,
+    OPENPGPKEY = 61,
+    CSYNC = 62,
+    ZONEMD = 63,
+    SVCB = 64,
+    HTTPS = 65,
+    SPF = 99,
+    NID = 104,
+    L32 = 105,
+    L64 = 106,
+    LP = 107,
+    EUI48 = 108,
+    EUI64 = 109,
+    TKEY = 249,
+    TSIG = 250,
+    IXFR = 251,
     AXFR = 252,
     MAILB = 253,
     MAILA = 254,
     ALL = 255,
+    URI = 256,
+    CAA = 257,
+    AVC = 258,
+    DOA = 259,
+    AMTRELAY = 260,
+    TA = 32768,
+    DLV = 32769,
 
     NotImplemented = -1,
 }
@@ -210,6 +876,17 @@ impl From<u16> for DNSType {
     }
 }
 
+impl Display for DNSType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NSAPPTR => write!(f, "NSAP-PTR"),
+            Self::ALL => write!(f, "ANY"),
+            Self::NotImplemented => write!(f, "UNKNOWN"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
 #[derive(FromPrimitive, Debug, Copy, Clone)]
 pub enum DNSClass {
     IN = 1,
@@ -232,7 +909,41 @@ impl DNSPacket {
         self.header.id
     }
 
+    /// Whether this is a negative answer: NXDOMAIN, or NODATA with the
+    /// SOA of the zone in the authority section
+    pub fn is_negative(&self) -> bool {
+        self.answers.is_empty()
+            && (self.header.rcode == DNSRcode::NameError as u8
+                || self
+                    .authorities
+                    .iter()
+                    .any(|x| x.r#type == DNSType::SOA as u16))
+    }
+
+    /// Gives all records of an RRset in the answer section the minimum
+    /// TTL of the set, since RFC 2181 requires them to share one
+    pub fn normalize_ttls(&mut self) {
+        let mut min_ttls: HashMap<(String, u16, u16), u32> = HashMap::new();
+        for rr in &self.answers {
+            let ttl = min_ttls
+                .entry((rr.name.canonical(), rr.r#type, rr.class))
+                .or_insert(rr.ttl);
+            *ttl = (*ttl).min(rr.ttl);
+        }
+        for rr in &mut self.answers {
+            rr.ttl = min_ttls[&(rr.name.canonical(), rr.r#type, rr.class)];
+        }
+    }
+
     pub fn from_raw(buf: &[u8]) -> Result<DNSPacket> {
+        Self::read(buf).map_err(|e| match e {
+            // reading from memory only fails at the end of the message
+            RdnsError::Io(e) => RdnsError::Parse(e.to_string()),
+            e => e,
+        })
+    }
+
+    fn read(buf: &[u8]) -> Result<DNSPacket> {
         let mut rdr = Cursor::new(buf);
         let header = DNSHeader::from_raw(&mut rdr)?;
         let questions = DNSQuestion::from_raw(&mut rdr, header.qdcount)?;
@@ -257,21 +968,53 @@ impl DNSPacket {
             self.authorities.len() as u16,
             self.additionals.len() as u16,
         )?;
+        let mut offsets = NameOffsets::new();
         for q in &self.questions {
-            q.to_bytes(&mut writer)?;
+            q.to_bytes_in(&mut writer, Some(&mut offsets))?;
         }
         for rr in &self.answers {
-            rr.to_bytes(&mut writer)?;
+            rr.to_bytes_in(&mut writer, Some(&mut offsets))?;
         }
         for rr in &self.authorities {
-            rr.to_bytes(&mut writer)?;
+            rr.to_bytes_in(&mut writer, Some(&mut offsets))?;
         }
         for rr in &self.additionals {
-            rr.to_bytes(&mut writer)?;
+            rr.to_bytes_in(&mut writer, Some(&mut offsets))?;
         }
         Ok(writer)
     }
 
+    /// Length of the packet with no name compressed, an upper bound of
+    /// what `assemble` produces
+    pub fn wire_len(&self) -> Result<usize> {
+        let mut len = 12;
+        for q in &self.questions {
+            len += q.qname.wire_len() + 4;
+        }
+        for rr in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            let name_len = match rr.rdata.as_ref() {
+                DNSRdata::Opt { .. } => 1,
+                _ => rr.name.wire_len(),
+            };
+            len += name_len + 10 + rr.rdata.rdata_bytes()?.len();
+        }
+        Ok(len)
+    }
+
+    /// Builds a query for a single question with recursion desired set,
+    /// use `header.set_rd(false)` for an iterative query
+    pub fn query(id: u16, question: DNSQuestion) -> Self {
+        let mut pkt = Self::new(id, true);
+        pkt.header.set_rd(true);
+        pkt.questions.push(question);
+        pkt
+    }
+
     pub fn new(id: u16, is_query: bool) -> Self {
         Self {
             header: DNSHeader::new(id, is_query),
@@ -284,6 +1027,12 @@ impl DNSPacket {
 }
 
 impl DNSHeader {
+    /// Parses only the header of the message in `buf`, which may be
+    /// malformed past it
+    pub fn peek(buf: &[u8]) -> Option<Self> {
+        Self::from_raw(&mut Cursor::new(buf)).ok()
+    }
+
     pub fn from_raw(rdr: &mut Cursor<&[u8]>) -> Result<Self> {
         let id = rdr.read_u16::<BigEndian>()?;
         let tmp1 = rdr.read_u8()?;
@@ -325,6 +1074,26 @@ impl DNSHeader {
         self.rcode = rcode as u8;
     }
 
+    pub fn set_rd(&mut self, rd: bool) {
+        self.rd = rd as u8;
+    }
+
+    /// Authentic Data, set on answers validated with DNSSEC (RFC 4035
+    /// section 3.2.3). It is the middle bit of `reserved`
+    pub fn ad(&self) -> bool {
+        self.reserved & 0b010 != 0
+    }
+
+    pub fn set_ad(&mut self, ad: bool) {
+        self.reserved = (self.reserved & !0b010) | (ad as u8) << 1;
+    }
+
+    /// Checking Disabled, set by clients validating answers themselves.
+    /// It is the last bit of `reserved`
+    pub fn cd(&self) -> bool {
+        self.reserved & 0b001 != 0
+    }
+
     pub fn to_bytes(
         &self,
         writer: &mut Vec<u8>,
@@ -334,7 +1103,9 @@ impl DNSHeader {
         arcount: u16,
     ) -> Result<()> {
         writer.write_u16::<BigEndian>(self.id)?;
-        writer.write_u8((self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.rd))?;
+        writer.write_u8(
+            (self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.tc << 1) | (self.rd),
+        )?;
         writer.write_u8((self.ra << 7) | (self.reserved << 4) | self.rcode)?;
         writer.write_u16::<BigEndian>(qdcount)?;
         writer.write_u16::<BigEndian>(ancount)?;
@@ -383,7 +1154,15 @@ impl DNSQuestion {
     }
 
     pub fn to_bytes(&self, writer: &mut Vec<u8>) -> Result<()> {
-        writer.write(&self.qname.to_bytes()?)?;
+        self.to_bytes_in(writer, None)
+    }
+
+    /// Serializes the question, compressing its name against `offsets`
+    fn to_bytes_in(&self, writer: &mut Vec<u8>, offsets: Option<&mut NameOffsets>) -> Result<()> {
+        match offsets {
+            Some(offsets) => self.qname.write_compressed(writer, offsets)?,
+            None => writer.write_all(&self.qname.to_bytes()?)?,
+        }
         writer.write_u16::<BigEndian>(self.qtype)?;
         writer.write_u16::<BigEndian>(self.qclass)?;
         Ok(())
@@ -399,18 +1178,175 @@ impl DNSQuestion {
 }
 
 impl DNSResourceRecord {
-    pub fn rdata_from_raw(rdr: &mut Cursor<&[u8]>, rtype: u16) -> Result<(u16, Rc<DNSRdata>)> {
+    /// Address records are only decoded for class IN, their layout in other
+    /// classes differs and is kept raw so it is relayed unchanged
+    pub fn rdata_from_raw(
+        rdr: &mut Cursor<&[u8]>,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+    ) -> Result<Arc<DNSRdata>> {
         let rdlength = rdr.read_u16::<BigEndian>()?;
-        let rdata: Rc<DNSRdata> = Rc::new(match DNSType::from_num(rtype) {
-            DNSType::A => DNSRdata::A(rdr.read_ipv4()?),
-            DNSType::AAAA => DNSRdata::Aaaa(rdr.read_ipv6()?),
+        let is_in = class == DNSClass::IN as u16;
+        let r#type = DNSType::from_num(rtype);
+        if rdlength < min_rdlength(r#type, is_in) {
+            return Err(RdnsError::Parse(format!("{} rdata too short", r#type)));
+        }
+        let start = rdr.position();
+        let rdata: Arc<DNSRdata> = Arc::new(match r#type {
+            DNSType::A if is_in => DNSRdata::A(rdr.read_ipv4()?),
+            DNSType::AAAA if is_in => DNSRdata::Aaaa(rdr.read_ipv6()?),
             DNSType::CNAME => DNSRdata::Cname(rdr.read_domain_name()?),
+            DNSType::DNAME => DNSRdata::Dname(rdr.read_domain_name()?),
             DNSType::MX => DNSRdata::Mx(rdr.read_u16::<BigEndian>()?, rdr.read_domain_name()?),
             DNSType::NS => DNSRdata::Ns(rdr.read_domain_name()?),
-            DNSType::TXT => DNSRdata::Txt(rdr.read_string_exact(rdlength as usize)?),
+            DNSType::PTR => DNSRdata::Ptr(rdr.read_domain_name()?),
+            DNSType::TXT => DNSRdata::Txt(read_character_strings(rdr, rdlength)?),
+            DNSType::SOA => DNSRdata::Soa {
+                mname: rdr.read_domain_name()?,
+                rname: rdr.read_domain_name()?,
+                serial: rdr.read_u32::<BigEndian>()?,
+                refresh: rdr.read_u32::<BigEndian>()?,
+                retry: rdr.read_u32::<BigEndian>()?,
+                expire: rdr.read_u32::<BigEndian>()?,
+                minimum: rdr.read_u32::<BigEndian>()?,
+            },
+            DNSType::APL if is_in => DNSRdata::Apl(AplItem::from_raw_multi(rdr, rdlength)?),
+            DNSType::TLSA => DNSRdata::Tlsa {
+                usage: rdr.read_u8()?,
+                selector: rdr.read_u8()?,
+                matching_type: rdr.read_u8()?,
+                data: rdr.read_raw(
+                    rdlength
+                        .checked_sub(3)
+                        .ok_or_else(|| RdnsError::Parse("TLSA rdata too short".into()))?
+                        as usize,
+                )?,
+            },
+            DNSType::SSHFP => DNSRdata::Sshfp {
+                algorithm: rdr.read_u8()?,
+                fp_type: rdr.read_u8()?,
+                fingerprint: rdr.read_raw(
+                    rdlength
+                        .checked_sub(2)
+                        .ok_or_else(|| RdnsError::Parse("SSHFP rdata too short".into()))?
+                        as usize,
+                )?,
+            },
+            DNSType::CERT => DNSRdata::Cert {
+                cert_type: rdr.read_u16::<BigEndian>()?,
+                key_tag: rdr.read_u16::<BigEndian>()?,
+                algorithm: rdr.read_u8()?,
+                certificate: rdr.read_raw(
+                    rdlength
+                        .checked_sub(5)
+                        .ok_or_else(|| RdnsError::Parse("CERT rdata too short".into()))?
+                        as usize,
+                )?,
+            },
+            DNSType::SRV => DNSRdata::Srv {
+                priority: rdr.read_u16::<BigEndian>()?,
+                weight: rdr.read_u16::<BigEndian>()?,
+                port: rdr.read_u16::<BigEndian>()?,
+                target: rdr.read_domain_name()?,
+            },
+            DNSType::URI => DNSRdata::Uri {
+                priority: rdr.read_u16::<BigEndian>()?,
+                weight: rdr.read_u16::<BigEndian>()?,
+                target: rdr.read_string_exact(
+                    rdlength
+                        .checked_sub(4)
+                        .ok_or_else(|| RdnsError::Parse("URI rdata too short".into()))?
+                        as usize,
+                )?,
+            },
+            DNSType::CAA => {
+                let flags = rdr.read_u8()?;
+                let tag_len = rdr.read_u8()?;
+                let value_len = (rdlength - 2)
+                    .checked_sub(tag_len as u16)
+                    .ok_or_else(|| RdnsError::Parse("CAA tag exceeds rdata".into()))?;
+                DNSRdata::Caa {
+                    flags,
+                    tag: rdr.read_string_exact(tag_len as usize)?,
+                    value: rdr.read_raw(value_len as usize)?,
+                }
+            }
+            DNSType::RRSIG => {
+                let end = rdr.position() + rdlength as u64;
+                let type_covered = rdr.read_u16::<BigEndian>()?;
+                let algorithm = rdr.read_u8()?;
+                let labels = rdr.read_u8()?;
+                let original_ttl = rdr.read_u32::<BigEndian>()?;
+                let expiration = rdr.read_u32::<BigEndian>()?;
+                let inception = rdr.read_u32::<BigEndian>()?;
+                let key_tag = rdr.read_u16::<BigEndian>()?;
+                let signer = rdr.read_domain_name()?;
+                let len = end
+                    .checked_sub(rdr.position())
+                    .ok_or_else(|| RdnsError::Parse("RRSIG signer exceeds rdata".into()))?;
+                DNSRdata::Rrsig {
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    signature: rdr.read_raw(len as usize)?,
+                }
+            }
+            DNSType::DNSKEY => DNSRdata::Dnskey {
+                flags: rdr.read_u16::<BigEndian>()?,
+                protocol: rdr.read_u8()?,
+                algorithm: rdr.read_u8()?,
+                public_key: rdr.read_raw(rdlength as usize - 4)?,
+            },
+            DNSType::DS => DNSRdata::Ds {
+                key_tag: rdr.read_u16::<BigEndian>()?,
+                algorithm: rdr.read_u8()?,
+                digest_type: rdr.read_u8()?,
+                digest: rdr.read_raw(rdlength as usize - 4)?,
+            },
+            DNSType::SVCB | DNSType::HTTPS => {
+                let end = rdr.position() + rdlength as u64;
+                let priority = rdr.read_u16::<BigEndian>()?;
+                let target = rdr.read_domain_name()?;
+                let mut params = Vec::new();
+                while rdr.position() < end {
+                    let key = rdr.read_u16::<BigEndian>()?;
+                    let len = rdr.read_u16::<BigEndian>()?;
+                    params.push((key, rdr.read_raw(len as usize)?));
+                }
+                if rdr.position() != end {
+                    return Err(RdnsError::Parse("SvcParam exceeds rdata".into()));
+                }
+                DNSRdata::Svcb {
+                    priority,
+                    target,
+                    params,
+                }
+            }
+            DNSType::OPT => DNSRdata::Opt {
+                udp_payload_size: class,
+                extended_rcode: (ttl >> 24) as u8,
+                version: (ttl >> 16) as u8,
+                dnssec_ok: ttl & 0x8000 != 0,
+                z: ttl as u16 & 0x7FFF,
+                options: EdnsOption::from_raw_multi(rdr, rdlength)?,
+            },
             _ => DNSRdata::Other(rdr.read_raw(rdlength as usize)?),
         });
-        Ok((rdlength, rdata))
+        // a compressed name can end anywhere, make sure it ended where the
+        // record does, or the records after it would be misparsed
+        if rdr.position() != start + rdlength as u64 {
+            return Err(RdnsError::Parse(format!(
+                "{} rdata does not match its rdlength",
+                r#type
+            )));
+        }
+        Ok(rdata)
     }
 
     pub fn from_raw_multi(rdr: &mut Cursor<&[u8]>, count: u16) -> Result<Vec<Self>> {
@@ -426,19 +1362,58 @@ impl DNSResourceRecord {
         let r#type = rdr.read_u16::<BigEndian>()?;
         let class = rdr.read_u16::<BigEndian>()?;
         let ttl = rdr.read_u32::<BigEndian>()?;
-        let (rdlength, rdata) = Self::rdata_from_raw(rdr, r#type)?;
+        let rdata = Self::rdata_from_raw(rdr, r#type, class, ttl)?;
         Ok(Self {
             name,
             r#type,
             class,
             ttl,
-            rdlength,
             rdata,
         })
     }
 
     pub fn to_bytes(&self, writer: &mut Vec<u8>) -> Result<()> {
-        writer.write(&self.name.to_bytes()?)?;
+        self.to_bytes_in(writer, None)
+    }
+
+    /// Whether `other` is the same record, whatever its TTL and the case of
+    /// its owner
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.name.eq_ignore_case(&other.name)
+            && self.r#type == other.r#type
+            && self.class == other.class
+            && matches!(
+                (self.rdata.rdata_bytes(), other.rdata.rdata_bytes()),
+                (Ok(a), Ok(b)) if a == b
+            )
+    }
+
+    /// Serializes the record, compressing its names against `offsets`, the
+    /// names already written to the message
+    fn to_bytes_in(&self, writer: &mut Vec<u8>, offsets: Option<&mut NameOffsets>) -> Result<()> {
+        if let DNSRdata::Opt {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok,
+            z,
+            ..
+        } = self.rdata.as_ref()
+        {
+            // always owned by the root
+            writer.write_u8(0)?;
+            writer.write_u16::<BigEndian>(DNSType::OPT as u16)?;
+            writer.write_u16::<BigEndian>(*udp_payload_size)?;
+            writer.write_u8(*extended_rcode)?;
+            writer.write_u8(*version)?;
+            writer.write_u16::<BigEndian>((*dnssec_ok as u16) << 15 | *z)?;
+            return self.rdata.to_bytes(writer, offsets);
+        }
+        let mut offsets = offsets;
+        match offsets.as_deref_mut() {
+            Some(offsets) => self.name.write_compressed(writer, offsets)?,
+            None => writer.write_all(&self.name.to_bytes()?)?,
+        }
         // use `rdata` type first, if is type "other",
         // use the `type` field
         writer.write_u16::<BigEndian>(
@@ -449,7 +1424,928 @@ impl DNSResourceRecord {
         )?;
         writer.write_u16::<BigEndian>(self.class)?;
         writer.write_u32::<BigEndian>(self.ttl)?;
-        self.rdata.to_bytes(writer)?;
+        self.rdata.to_bytes(writer, offsets)?;
         Ok(())
     }
+
+    /// An OPT record advertising `udp_payload_size` and no options
+    pub fn opt(udp_payload_size: u16) -> Self {
+        Self {
+            name: vec![],
+            r#type: DNSType::OPT as u16,
+            class: udp_payload_size,
+            ttl: 0,
+            rdata: Arc::new(DNSRdata::Opt {
+                udp_payload_size,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+                z: 0,
+                options: vec![],
+            }),
+        }
+    }
+}
+
+/// `name` as an absolute name, with the trailing dot. With `unicode`, its
+/// A-labels are shown in Unicode
+fn fqdn(name: &DomainName, unicode: bool) -> String {
+    let name = match unicode {
+        true => name.to_unicode_name(),
+        false => name.to_domain_name(),
+    };
+    match name {
+        x if x == "." => x,
+        x => x + ".",
+    }
+}
+
+fn type_mnemonic(r#type: u16) -> String {
+    match DNSType::from_num(r#type) {
+        DNSType::NotImplemented => format!("TYPE{}", r#type),
+        x => x.to_string(),
+    }
+}
+
+fn class_mnemonic(class: u16) -> String {
+    match DNSClass::from_num(class) {
+        DNSClass::NotImplemented => format!("CLASS{}", class),
+        x => format!("{:?}", x),
+    }
+}
+
+/// An RRSIG validity time as YYYYMMDDHHmmSS in UTC (RFC 4034 section 3.2)
+fn timestamp(secs: u32) -> String {
+    match chrono::DateTime::from_timestamp(secs as i64, 0) {
+        Some(x) => x.format("%Y%m%d%H%M%S").to_string(),
+        None => secs.to_string(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02X}", x)).collect()
+}
+
+/// A character-string in quotes, with quotes and backslashes escaped
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A SvcParam in presentation format, `key=value`, with the values of
+/// unknown keys, or that do not parse, in quotes
+fn svc_param(key: u16, value: &[u8]) -> String {
+    let ips = |len: usize| {
+        (!value.is_empty() && value.len().is_multiple_of(len)).then(|| {
+            value
+                .chunks(len)
+                .map(|x| match <[u8; 4]>::try_from(x) {
+                    Ok(v4) => Ipv4Addr::from(v4).to_string(),
+                    Err(_) => Ipv6Addr::from(<[u8; 16]>::try_from(x).unwrap()).to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+    };
+    let parsed = match key {
+        0 => value.len().is_multiple_of(2).then(|| {
+            value
+                .chunks(2)
+                .map(|x| svc_key_name(u16::from_be_bytes([x[0], x[1]])))
+                .collect::<Vec<_>>()
+                .join(",")
+        }),
+        1 => alpn_ids(value),
+        3 => <[u8; 2]>::try_from(value)
+            .ok()
+            .map(|x| u16::from_be_bytes(x).to_string()),
+        4 => ips(4),
+        5 => Some(STANDARD.encode(value)),
+        6 => ips(16),
+        _ => None,
+    };
+    let name = svc_key_name(key);
+    match parsed {
+        Some(x) => format!("{}={}", name, x),
+        None if value.is_empty() => name,
+        None => format!("{}={}", name, quoted(&String::from_utf8_lossy(value))),
+    }
+}
+
+fn svc_key_name(key: u16) -> String {
+    match key {
+        0 => "mandatory".into(),
+        1 => "alpn".into(),
+        2 => "no-default-alpn".into(),
+        3 => "port".into(),
+        4 => "ipv4hint".into(),
+        5 => "ech".into(),
+        6 => "ipv6hint".into(),
+        x => format!("key{}", x),
+    }
+}
+
+/// The protocol ids of an `alpn` value, each preceded by its length
+fn alpn_ids(value: &[u8]) -> Option<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some((&len, tail)) = rest.split_first() {
+        if len == 0 || tail.len() < len as usize {
+            return None;
+        }
+        let (id, tail) = tail.split_at(len as usize);
+        ids.push(String::from_utf8_lossy(id).replace(',', "\\,"));
+        rest = tail;
+    }
+    (!ids.is_empty()).then(|| ids.join(","))
+}
+
+/// The header lines of `dig`, with the section counts given
+fn fmt_header(f: &mut Formatter<'_>, header: &DNSHeader, counts: [usize; 4]) -> fmt::Result {
+    let opcode = match DNSOpcode::from_num(header.opcode) {
+        DNSOpcode::Unknown => header.opcode.to_string(),
+        x => format!("{:?}", x).to_uppercase(),
+    };
+    let status = match DNSRcode::from_num(header.rcode as u16) {
+        DNSRcode::Normal => "NOERROR".to_string(),
+        DNSRcode::FormatError => "FORMERR".to_string(),
+        DNSRcode::ServerFailure => "SERVFAIL".to_string(),
+        DNSRcode::NameError => "NXDOMAIN".to_string(),
+        DNSRcode::NotImplemented => "NOTIMP".to_string(),
+        DNSRcode::Refused => "REFUSED".to_string(),
+        DNSRcode::Unknown => format!("RCODE{}", header.rcode),
+    };
+    writeln!(
+        f,
+        ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+        opcode, status, header.id
+    )?;
+    let flags = [
+        ("qr", header.qr),
+        ("aa", header.aa),
+        ("tc", header.tc),
+        ("rd", header.rd),
+        ("ra", header.ra),
+    ];
+    let flags: Vec<_> = flags.iter().filter(|x| x.1 != 0).map(|x| x.0).collect();
+    write!(
+        f,
+        ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        flags.join(" "),
+        counts[0],
+        counts[1],
+        counts[2],
+        counts[3]
+    )
+}
+
+impl Display for DNSHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let counts = [self.qdcount, self.ancount, self.nscount, self.arcount];
+        fmt_header(f, self, counts.map(|x| x as usize))
+    }
+}
+
+impl Display for DNSQuestion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            fqdn(&self.qname, f.alternate()),
+            class_mnemonic(self.qclass),
+            type_mnemonic(self.qtype)
+        )
+    }
+}
+
+impl Display for DNSResourceRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t",
+            fqdn(&self.name, f.alternate()),
+            self.ttl,
+            class_mnemonic(self.class),
+            type_mnemonic(self.r#type),
+        )?;
+        match f.alternate() {
+            true => write!(f, "{:#}", self.rdata),
+            false => write!(f, "{}", self.rdata),
+        }
+    }
+}
+
+/// The presentation format of the rdata, as in zone files. Unknown rdata
+/// is shown in the generic format of RFC 3597
+impl Display for DNSRdata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(ip) => write!(f, "{}", ip),
+            Self::Aaaa(ip) => write!(f, "{}", ip),
+            Self::Cname(dn) | Self::Ns(dn) | Self::Ptr(dn) | Self::Dname(dn) => {
+                write!(f, "{}", fqdn(dn, f.alternate()))
+            }
+            Self::Mx(pref, dn) => write!(f, "{} {}", pref, fqdn(dn, f.alternate())),
+            Self::Txt(strings) => {
+                let strings: Vec<_> = strings
+                    .iter()
+                    .map(|x| quoted(&String::from_utf8_lossy(x)))
+                    .collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            Self::Apl(items) => {
+                let items: Vec<_> = items.iter().map(|x| x.to_string()).collect();
+                write!(f, "{}", items.join(" "))
+            }
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname, f.alternate()),
+                fqdn(rname, f.alternate()),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            Self::Tlsa {
+                usage,
+                selector,
+                matching_type,
+                data,
+            } => write!(f, "{} {} {} {}", usage, selector, matching_type, hex(data)),
+            Self::Sshfp {
+                algorithm,
+                fp_type,
+                fingerprint,
+            } => write!(f, "{} {} {}", algorithm, fp_type, hex(fingerprint)),
+            Self::Cert {
+                cert_type,
+                key_tag,
+                algorithm,
+                certificate,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                cert_type,
+                key_tag,
+                algorithm,
+                STANDARD.encode(certificate)
+            ),
+            Self::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                priority,
+                weight,
+                port,
+                fqdn(target, f.alternate())
+            ),
+            Self::Uri {
+                priority,
+                weight,
+                target,
+            } => write!(f, "{} {} {}", priority, weight, quoted(target)),
+            Self::Caa { flags, tag, value } => {
+                let value = String::from_utf8_lossy(value);
+                write!(f, "{} {} {}", flags, tag, quoted(&value))
+            }
+            Self::Rrsig {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                DNSType::from_num(*type_covered),
+                algorithm,
+                labels,
+                original_ttl,
+                timestamp(*expiration),
+                timestamp(*inception),
+                key_tag,
+                fqdn(signer, f.alternate()),
+                STANDARD.encode(signature)
+            ),
+            Self::Dnskey {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                STANDARD.encode(public_key)
+            ),
+            Self::Ds {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex(digest)
+            ),
+            Self::Svcb {
+                priority,
+                target,
+                params,
+            } => {
+                write!(f, "{} {}", priority, fqdn(target, f.alternate()))?;
+                for (key, value) in params {
+                    write!(f, " {}", svc_param(*key, value))?;
+                }
+                Ok(())
+            }
+            Self::Opt {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                options,
+                ..
+            } => {
+                write!(
+                    f,
+                    "EDNS: version: {}, flags:{}; udp: {}",
+                    version,
+                    if *dnssec_ok { " do" } else { "" },
+                    udp_payload_size
+                )?;
+                if *extended_rcode != 0 {
+                    write!(f, "; extended rcode: {}", extended_rcode)?;
+                }
+                for option in options {
+                    write!(f, "; option {}: {}", option.code, hex(&option.data))?;
+                }
+                Ok(())
+            }
+            Self::Other(bytes) if bytes.is_empty() => write!(f, "\\# 0"),
+            Self::Other(bytes) => write!(f, "\\# {} {}", bytes.len(), hex(bytes)),
+        }
+    }
+}
+
+/// As in RFC 3123 section 5: `[!]family:address/prefix`
+impl Display for AplItem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut octets = self.afdpart.clone();
+        let address = match self.family {
+            1 if octets.len() <= 4 => {
+                octets.resize(4, 0);
+                Ipv4Addr::from(<[u8; 4]>::try_from(octets).unwrap()).to_string()
+            }
+            2 if octets.len() <= 16 => {
+                octets.resize(16, 0);
+                Ipv6Addr::from(<[u8; 16]>::try_from(octets).unwrap()).to_string()
+            }
+            _ => hex(&octets),
+        };
+        let negation = if self.negation { "!" } else { "" };
+        write!(f, "{}{}:{}/{}", negation, self.family, address, self.prefix)
+    }
+}
+
+/// Like the output of `dig`, with the OPT record in a pseudosection of its
+/// own rather than among the additional records. The alternate form
+/// (`{:#}`) shows internationalized names in Unicode
+impl Display for DNSPacket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (opt, additionals): (Vec<_>, Vec<_>) = self
+            .additionals
+            .iter()
+            .partition(|x| x.r#type == DNSType::OPT as u16);
+        let counts = [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ];
+        fmt_header(f, &self.header, counts)?;
+        writeln!(f)?;
+        if let Some(opt) = opt.first() {
+            write!(f, "\n;; OPT PSEUDOSECTION:\n; {}\n", opt.rdata)?;
+        }
+        if !self.questions.is_empty() {
+            write!(f, "\n;; QUESTION SECTION:\n")?;
+            for question in &self.questions {
+                match f.alternate() {
+                    true => writeln!(f, "{:#}", question)?,
+                    false => writeln!(f, "{}", question)?,
+                }
+            }
+        }
+        let sections = [
+            ("ANSWER", self.answers.iter().collect::<Vec<_>>()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additionals),
+        ];
+        for (name, records) in sections {
+            if records.is_empty() {
+                continue;
+            }
+            write!(f, "\n;; {} SECTION:\n", name)?;
+            for record in records {
+                match f.alternate() {
+                    true => writeln!(f, "{:#}", record)?,
+                    false => writeln!(f, "{}", record)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::domain_name::ToDomainName;
+
+    #[test]
+    fn txt_keeps_arbitrary_octets() {
+        let raw: &[u8] = &[0, 4, 3, b'a', 0xff, b'"'];
+        let rdata = DNSResourceRecord::rdata_from_raw(
+            &mut Cursor::new(raw),
+            DNSType::TXT as u16,
+            DNSClass::IN as u16,
+            300,
+        )
+        .unwrap();
+        assert!(matches!(rdata.as_ref(), DNSRdata::Txt(x) if x[0] == raw[3..]));
+        assert_eq!(rdata.to_string(), "\"a\u{fffd}\\\"\"");
+    }
+
+    #[test]
+    fn txt_strings_survive_a_round_trip() {
+        let long: Vec<u8> = (0..300).map(|x| b'a' + (x % 26) as u8).collect();
+        let (first, rest) = long.split_at(255);
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::TXT,
+            DNSRdata::Txt(vec![b"v=spf1".to_vec(), b"-all".to_vec()]),
+        ));
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::TXT,
+            DNSRdata::Txt(vec![first.to_vec(), rest.to_vec()]),
+        ));
+        let msg = pkt.assemble().unwrap();
+        // each string after its length
+        let at = 12 + 13 + 8;
+        assert_eq!(msg[at..at + 2], [0, 12]);
+        assert_eq!(msg[at + 2..at + 14], *b"\x06v=spf1\x04-all");
+        let mut pkt = DNSPacket::from_raw(&msg).unwrap();
+        assert_eq!(pkt.answers[0].rdata.to_string(), "\"v=spf1\" \"-all\"");
+        match pkt.answers[1].rdata.as_ref() {
+            DNSRdata::Txt(x) => {
+                assert_eq!((x.len(), x[0].len(), x[1].len()), (2, 255, 45));
+                assert_eq!(x.concat(), long);
+            }
+            x => panic!("{:?}", x),
+        }
+        // a string of more than 255 bytes cannot be written
+        pkt.answers[1].rdata = Arc::new(DNSRdata::Txt(vec![long]));
+        assert!(matches!(pkt.assemble(), Err(RdnsError::Encode(_))));
+    }
+
+    fn record(name: &str, r#type: DNSType, rdata: DNSRdata) -> DNSResourceRecord {
+        DNSResourceRecord {
+            name: name.to_string().to_domain_name(),
+            r#type: r#type as u16,
+            class: DNSClass::IN as u16,
+            ttl: 300,
+            rdata: Arc::new(rdata),
+        }
+    }
+
+    #[test]
+    fn soa_survives_a_round_trip() {
+        // the answer of an authoritative server for example.com SOA, its
+        // rname compressed against its mname
+        let msg: &[u8] = &[
+            0x1a, 0x2b, 0x84, 0x00, 0, 1, 0, 1, 0, 0, 0, 0, //
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 6, 0, 1, 0xc0,
+            12, 0, 6, 0, 1, 0, 0, 0x0e, 0x10, 0, 44, //
+            2, b'n', b's', 5, b'i', b'c', b'a', b'n', b'n', 3, b'o', b'r', b'g', 0, //
+            3, b'n', b'o', b'c', 3, b'd', b'n', b's', 0xc0, 44, //
+            0x78, 0xa5, 0x08, 0x32, 0, 0, 0x1c, 0x20, 0, 0, 0x0e, 0x10, 0, 0x12, 0x75, 0, 0, 0,
+            0x0e, 0x10,
+        ];
+        let pkt = DNSPacket::from_raw(msg).unwrap();
+        match pkt.answers[0].rdata.as_ref() {
+            DNSRdata::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                assert_eq!(mname.canonical(), "ns.icann.org");
+                assert_eq!(rname.canonical(), "noc.dns.icann.org");
+                assert_eq!(
+                    [*serial, *refresh, *retry, *expire, *minimum],
+                    [2024081458, 7200, 3600, 1209600, 3600]
+                );
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(pkt.assemble().unwrap(), msg);
+    }
+
+    #[test]
+    fn opt_survives_a_round_trip() {
+        // a query with DO set and a client cookie, as dig sends it
+        let msg: &[u8] = &[
+            0x5c, 0x01, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1, //
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1, 0, 0,
+            0x29, 0x04, 0xd0, 0, 0, 0x80, 0, 0, 12, //
+            0, 10, 0, 8, 0x9a, 0x4f, 0x1b, 0x2c, 0xd3, 0x07, 0x66, 0xe1,
+        ];
+        let pkt = DNSPacket::from_raw(msg).unwrap();
+        match pkt.additionals[0].rdata.as_ref() {
+            DNSRdata::Opt {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                z,
+                options,
+            } => {
+                assert_eq!(*udp_payload_size, 1232);
+                assert_eq!((*extended_rcode, *version, *dnssec_ok, *z), (0, 0, true, 0));
+                assert_eq!(options.len(), 1);
+                assert_eq!(options[0].code, 10);
+                assert_eq!(options[0].data, msg[msg.len() - 8..]);
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(pkt.assemble().unwrap(), msg);
+    }
+
+    #[test]
+    fn srv_survives_a_round_trip() {
+        let name = "_sip._tcp.example.com";
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.questions.push(DNSQuestion::new(
+            name.to_string().to_domain_name(),
+            DNSType::SRV as u16,
+        ));
+        pkt.answers.push(record(
+            name,
+            DNSType::SRV,
+            DNSRdata::Srv {
+                priority: 10,
+                weight: 60,
+                port: 5060,
+                target: "sipserver.example.com".to_string().to_domain_name(),
+            },
+        ));
+        let msg = pkt.assemble().unwrap();
+        let pkt = DNSPacket::from_raw(&msg).unwrap();
+        assert_eq!(pkt.answers[0].r#type, DNSType::SRV as u16);
+        match pkt.answers[0].rdata.as_ref() {
+            DNSRdata::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                assert_eq!((*priority, *weight, *port), (10, 60, 5060));
+                assert_eq!(target.canonical(), "sipserver.example.com");
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(
+            pkt.answers[0].rdata.to_string(),
+            "10 60 5060 sipserver.example.com."
+        );
+        assert_eq!(pkt.assemble().unwrap(), msg);
+    }
+
+    #[test]
+    fn caa_survives_a_round_trip() {
+        let mut raw = vec![0, 22, 0, 5];
+        raw.extend(b"issueletsencrypt.org");
+        let rdata = DNSResourceRecord::rdata_from_raw(
+            &mut Cursor::new(&raw[..]),
+            DNSType::CAA as u16,
+            DNSClass::IN as u16,
+            300,
+        )
+        .unwrap();
+        match rdata.as_ref() {
+            DNSRdata::Caa { flags, tag, value } => {
+                assert_eq!((*flags, tag.as_str()), (0, "issue"));
+                assert_eq!(value, b"letsencrypt.org");
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(rdata.to_string(), "0 issue \"letsencrypt.org\"");
+        // preceded by its rdlength again
+        let mut out = vec![];
+        rdata.to_bytes(&mut out, None).unwrap();
+        assert_eq!(out, raw);
+    }
+
+    #[test]
+    fn https_survives_a_round_trip() {
+        let msg: &[u8] = &[
+            0x2b, 0x1c, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, //
+            10, b'c', b'l', b'o', b'u', b'd', b'f', b'l', b'a', b'r', b'e', 3, b'c', b'o', b'm', 0,
+            0, 65, 0, 1, //
+            0xc0, 12, 0, 65, 0, 1, 0, 0, 1, 0x2c, 0, 21, //
+            0, 1, 0, //
+            0, 1, 0, 6, 2, b'h', b'2', 2, b'h', b'3', //
+            0, 4, 0, 4, 104, 16, 132, 229,
+        ];
+        let pkt = DNSPacket::from_raw(msg).unwrap();
+        assert_eq!(pkt.answers[0].r#type, DNSType::HTTPS as u16);
+        match pkt.answers[0].rdata.as_ref() {
+            DNSRdata::Svcb {
+                priority,
+                target,
+                params,
+            } => {
+                assert_eq!((*priority, target.is_empty()), (1, true));
+                let keys: Vec<_> = params.iter().map(|x| x.0).collect();
+                assert_eq!(keys, [1, 4]);
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(
+            pkt.answers[0].rdata.to_string(),
+            "1 . alpn=h2,h3 ipv4hint=104.16.132.229"
+        );
+        assert_eq!(pkt.assemble().unwrap(), msg);
+    }
+
+    #[test]
+    fn packets_display_like_dig() {
+        let mut pkt = DNSPacket::new(6699, false);
+        pkt.header.rd = 1;
+        pkt.header.ra = 1;
+        pkt.questions.push(DNSQuestion::new(
+            "example.com".to_string().to_domain_name(),
+            DNSType::A as u16,
+        ));
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::new(93, 184, 215, 14)),
+        ));
+        pkt.authorities.push(record(
+            "example.com",
+            DNSType::NS,
+            DNSRdata::Ns("a.iana-servers.net".to_string().to_domain_name()),
+        ));
+        pkt.additionals.push(DNSResourceRecord::opt(1232));
+        assert_eq!(
+            pkt.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 6699\n\
+             ;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 1, ADDITIONAL: 1\n\
+             \n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags:; udp: 1232\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;example.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             example.com.\t300\tIN\tA\t93.184.215.14\n\
+             \n\
+             ;; AUTHORITY SECTION:\n\
+             example.com.\t300\tIN\tNS\ta.iana-servers.net.\n"
+        );
+        let rr = record(
+            "xn--bcher-kva.example",
+            DNSType::CNAME,
+            DNSRdata::Cname("xn--bcher-kva.example".to_string().to_domain_name()),
+        );
+        assert_eq!(
+            format!("{:#}", rr),
+            "bücher.example.\t300\tIN\tCNAME\tbücher.example."
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn packets_survive_a_json_round_trip() {
+        let mut pkt = DNSPacket::new(0x1a2b, false);
+        pkt.questions.push(DNSQuestion::new(
+            "example.com".to_string().to_domain_name(),
+            DNSType::MX as u16,
+        ));
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::MX,
+            DNSRdata::Mx(10, "mail.example.com".to_string().to_domain_name()),
+        ));
+        pkt.additionals.push(record(
+            "mail.example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::new(192, 0, 2, 25)),
+        ));
+        pkt.additionals.push(record(
+            "mail.example.com",
+            DNSType::TXT,
+            DNSRdata::Txt(vec![b"v=spf1 -all".to_vec()]),
+        ));
+        pkt.additionals.push(DNSResourceRecord::opt(1232));
+        let json = serde_json::to_string(&pkt).unwrap();
+        // names are dotted, the root included
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["questions"][0]["qname"], "example.com");
+        assert_eq!(value["answers"][0]["rdata"]["Mx"][1], "mail.example.com");
+        assert_eq!(value["additionals"][2]["name"], ".");
+        let back: DNSPacket = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.assemble().unwrap(), pkt.assemble().unwrap());
+    }
+
+    #[test]
+    fn oversized_rdata_is_an_error() {
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::NULL,
+            DNSRdata::Other(vec![0; 65536]),
+        ));
+        assert!(matches!(pkt.assemble(), Err(RdnsError::Encode(_))));
+        pkt.answers[0].rdata = Arc::new(DNSRdata::Other(vec![0; 65535]));
+        assert!(pkt.assemble().is_ok());
+    }
+
+    #[test]
+    fn rrset_ttls_are_made_equal() {
+        let mut pkt = DNSPacket::new(1, false);
+        for (name, ttl) in [
+            ("example.com", 300),
+            ("EXAMPLE.com", 60),
+            ("other.com", 600),
+        ] {
+            let mut rr = record(name, DNSType::A, DNSRdata::A(Ipv4Addr::LOCALHOST));
+            rr.ttl = ttl;
+            pkt.answers.push(rr);
+        }
+        pkt.normalize_ttls();
+        let ttls: Vec<u32> = pkt.answers.iter().map(|x| x.ttl).collect();
+        assert_eq!(ttls, [60, 60, 600]);
+    }
+
+    #[test]
+    fn wire_len_bounds_assembled_length() {
+        let mut pkt = DNSPacket::query(
+            1,
+            DNSQuestion::new("example.com".to_string().to_domain_name(), 1),
+        );
+        pkt.additionals.push(DNSResourceRecord::opt(1232));
+        // nothing to compress
+        assert_eq!(pkt.wire_len().unwrap(), pkt.assemble().unwrap().len());
+        pkt.answers.push(record(
+            "www.example.com",
+            DNSType::CNAME,
+            DNSRdata::Cname("example.com".to_string().to_domain_name()),
+        ));
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::TXT,
+            DNSRdata::Txt(vec![b"text".to_vec()]),
+        ));
+        assert!(pkt.wire_len().unwrap() > pkt.assemble().unwrap().len());
+    }
+
+    #[test]
+    fn rdlength_follows_changed_rdata() {
+        let mut pkt = DNSPacket::new(1, false);
+        let mx = |name: &str| DNSRdata::Mx(10, name.to_string().to_domain_name());
+        pkt.answers
+            .push(record("example.com", DNSType::MX, mx("mx.example.net")));
+        let mut pkt = DNSPacket::from_raw(&pkt.assemble().unwrap()).unwrap();
+        pkt.answers[0].rdata = Arc::new(mx("mail.example.net"));
+        let msg = pkt.assemble().unwrap();
+        // the preference then the target, with nothing to compress it with
+        let at = 12 + 13 + 8;
+        assert_eq!(u16::from_be_bytes([msg[at], msg[at + 1]]), 2 + 18);
+        let pkt = DNSPacket::from_raw(&msg).unwrap();
+        assert_eq!(pkt.answers[0].rdata.to_string(), "10 mail.example.net.");
+    }
+
+    #[test]
+    fn rdata_must_fill_rdlength() {
+        let mut pkt = DNSPacket::new(1, false);
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::CNAME,
+            DNSRdata::Cname("a.example".to_string().to_domain_name()),
+        ));
+        pkt.answers.push(record(
+            "example.com",
+            DNSType::A,
+            DNSRdata::A(Ipv4Addr::LOCALHOST),
+        ));
+        let mut msg = pkt.assemble().unwrap();
+        assert!(DNSPacket::from_raw(&msg).is_ok());
+        // the rdlength of the CNAME, one more than its name takes
+        let at = 12 + 13 + 8;
+        assert_eq!(u16::from_be_bytes([msg[at], msg[at + 1]]), 11);
+        msg[at + 1] = 12;
+        assert!(matches!(
+            DNSPacket::from_raw(&msg),
+            Err(RdnsError::Parse(_))
+        ));
+        msg[at + 1] = 10;
+        assert!(DNSPacket::from_raw(&msg).is_err());
+    }
+
+    #[test]
+    fn compression_pointer_loops_are_rejected() {
+        let header = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        // a pointer to itself
+        let mut msg = header.to_vec();
+        msg.extend([0xC0, 12, 0, 1, 0, 1]);
+        assert!(matches!(
+            DNSPacket::from_raw(&msg),
+            Err(RdnsError::Parse(_))
+        ));
+        // two labels pointing at each other
+        let mut msg = header.to_vec();
+        msg.extend([1, b'a', 0xC0, 16, 1, b'b', 0xC0, 12, 0, 1, 0, 1]);
+        assert!(DNSPacket::from_raw(&msg).is_err());
+        // a name made longer than 255 octets by pointers
+        let mut msg = header.to_vec();
+        msg.extend([63]);
+        msg.extend([b'x'; 63]);
+        msg.extend([0xC0, 12, 0, 1, 0, 1]);
+        assert!(matches!(
+            DNSPacket::from_raw(&msg),
+            Err(RdnsError::InvalidDomainName(_))
+        ));
+    }
+
+    #[test]
+    fn names_have_at_most_127_labels() {
+        let header = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        let name = |labels: usize| {
+            let mut msg = header.to_vec();
+            for _ in 0..labels {
+                msg.extend([1, b'a']);
+            }
+            msg.extend([0, 0, 1, 0, 1]);
+            msg
+        };
+        let pkt = DNSPacket::from_raw(&name(127)).unwrap();
+        assert_eq!(pkt.questions[0].qname.len(), 127);
+        assert!(matches!(
+            DNSPacket::from_raw(&name(128)),
+            Err(RdnsError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn apl_address_part_is_checked_against_family() {
+        let item = AplItem {
+            family: 1,
+            prefix: 32,
+            negation: false,
+            afdpart: vec![192, 0, 2, 1, 1],
+        };
+        assert!(matches!(
+            item.to_bytes(&mut vec![]),
+            Err(RdnsError::Encode(_))
+        ));
+        let raw: &[u8] = &[0, 1, 32, 5, 192, 0, 2, 1, 1];
+        assert!(AplItem::from_raw_multi(&mut Cursor::new(raw), raw.len() as u16).is_err());
+        let raw: &[u8] = &[0, 2, 64, 0x84, 0x20, 0x01, 0x0d, 0xb8];
+        let items = AplItem::from_raw_multi(&mut Cursor::new(raw), raw.len() as u16).unwrap();
+        assert!(items[0].negation);
+        let mut out = vec![];
+        items[0].to_bytes(&mut out).unwrap();
+        assert_eq!(out, raw);
+    }
 }
@@ -0,0 +1,150 @@
+use crate::rdns::dns::{Rdns, RdnsConfig, UpstreamFamily};
+use crate::rdns::error::RdnsError;
+use crate::rdns::records::{DNSRcode, DNSResourceRecord, DNSType};
+use crate::rdns::util::Result;
+
+/// Resolves names for the program using it as a library, without serving
+/// anyone. Queries go out from an ephemeral port, and those that reach it
+/// from elsewhere are ignored
+pub struct Resolver {
+    rdns: Rdns,
+}
+
+impl Resolver {
+    pub fn new() -> Result<Self> {
+        Self::with_config(RdnsConfig::default())
+    }
+
+    /// The listeners of `config` are ignored
+    pub fn with_config(mut config: RdnsConfig) -> Result<Self> {
+        config.tcp = false;
+        config.tls_cert = None;
+        config.tls_key = None;
+        config.doh_listen = None;
+        config.metrics_listen = None;
+        let host = match config.upstream_family {
+            UpstreamFamily::V6 => "::",
+            _ => "0.0.0.0",
+        };
+        let mut rdns = Rdns::with_config(host, 0, config)?;
+        rdns.ignore_clients();
+        Ok(Self { rdns })
+    }
+
+    /// The answer records for `name`, with the CNAMEs leading to them.
+    /// A name that does not exist or has no such records gives none
+    pub fn resolve(&mut self, name: &str, qtype: DNSType) -> Result<Vec<DNSResourceRecord>> {
+        let pkt = self.rdns.resolve_full(name, qtype)?;
+        match pkt.header.rcode {
+            x if x == DNSRcode::Normal as u8 || x == DNSRcode::NameError as u8 => Ok(pkt.answers),
+            rcode => Err(RdnsError::ResolutionFailed(name.to_string(), rcode)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::domain_name::{ToDomainName, ToReadableName};
+    use crate::rdns::records::{DNSClass, DNSPacket, DNSQuestion, DNSRdata};
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Answers authoritatively for example.com with 127.0.0.10, and that
+    /// nothing else exists, until the resolver stops asking
+    fn authority() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let server = socket.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = server.recv_from(&mut buf) {
+                let mut pkt = DNSPacket::from_raw(&buf[..n]).unwrap();
+                pkt.header.qr = 1;
+                pkt.header.aa = 1;
+                pkt.additionals.clear();
+                let question = &pkt.questions[0];
+                if question.qname.to_domain_name() != "example.com" {
+                    pkt.header.set_rcode(DNSRcode::NameError);
+                } else if question.qtype == DNSType::A as u16 {
+                    pkt.answers.push(DNSResourceRecord {
+                        name: question.qname.clone(),
+                        r#type: DNSType::A as u16,
+                        class: DNSClass::IN as u16,
+                        ttl: 300,
+                        rdata: Arc::new(DNSRdata::A(Ipv4Addr::new(127, 0, 0, 10))),
+                    });
+                }
+                server.send_to(&pkt.assemble().unwrap(), from).unwrap();
+            }
+        });
+        socket
+    }
+
+    /// A resolver whose only root server is `root`, asking it the full
+    /// names as they were given
+    fn rooted_at(root: &UdpSocket) -> RdnsConfig {
+        RdnsConfig {
+            root_hints: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            upstream_port: root.local_addr().unwrap().port(),
+            qname_minimization: false,
+            case_randomization: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names_are_resolved_from_the_root() {
+        let root = authority();
+        let mut resolver = Resolver::with_config(rooted_at(&root)).unwrap();
+        let records = resolver.resolve("example.com", DNSType::A).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rdata.to_string(), "127.0.0.10");
+        // a name that does not exist has no records
+        let records = resolver.resolve("www.example.net", DNSType::A).unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn invalid_names_are_refused() {
+        let root = authority();
+        let mut resolver = Resolver::with_config(rooted_at(&root)).unwrap();
+        let long = format!("{}.example.com", "a".repeat(64));
+        for name in [long.as_str(), "www..example.com"] {
+            assert!(matches!(
+                resolver.resolve(name, DNSType::A),
+                Err(RdnsError::InvalidDomainName(_))
+            ));
+        }
+        // and leave nothing behind to get in the way of the next
+        let records = resolver.resolve("example.com", DNSType::A).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn queries_from_elsewhere_are_ignored() {
+        let root = authority();
+        let mut resolver = Resolver::with_config(rooted_at(&root)).unwrap();
+        let port = resolver.rdns.local_addr().unwrap().port();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let question = DNSQuestion::new(
+            "example.com".to_string().to_domain_name(),
+            DNSType::A as u16,
+        );
+        let msg = DNSPacket::query(1, question).assemble().unwrap();
+        // cached, it would be answered at once
+        resolver.resolve("example.com", DNSType::A).unwrap();
+        client.send_to(&msg, ("127.0.0.1", port)).unwrap();
+        // the query is read while the resolver waits for its own
+        resolver.resolve("example.com", DNSType::A).unwrap();
+        let mut buf = [0u8; 512];
+        assert!(client.recv(&mut buf).is_err());
+    }
+}
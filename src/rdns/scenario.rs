@@ -0,0 +1,1157 @@
+//! Scripted authoritative servers to run the resolver against without
+//! network access. A test declares what each server responds to the names
+//! it is asked about, then queries the resolver as a client would and
+//! checks the responses and the questions the servers got.
+//!
+//! The servers listen on addresses of the loopback network, `::1` being
+//! the only one of IPv6, all on the same port, over UDP and over TCP through `TcpConn`, and are served in
+//! turn with the resolver on the test's thread.
+
+use crate::rdns::dns::{Rdns, RdnsConfig};
+use crate::rdns::dnssec::{key_tag, signed_data};
+use crate::rdns::domain_name::{DomainName, DomainNameExt, DomainNameToBytes, ToDomainName};
+use crate::rdns::records::{
+    DNSClass, DNSPacket, DNSQuestion, DNSRcode, DNSRdata, DNSResourceRecord, DNSType,
+};
+use crate::rdns::tcp::TcpConn;
+use crate::rdns::zone;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::time;
+
+/// How long `Scenario::query` waits for the resolver's response
+const QUERY_DEADLINE: time::Duration = time::Duration::from_secs(5);
+
+/// What a server does with a query for a name
+#[derive(Clone)]
+pub enum Reply {
+    /// The records of the type asked for, or a CNAME or DNAME,
+    /// authoritatively.
+    /// Without any it is NODATA
+    Records(Vec<DNSResourceRecord>),
+    /// A referral to the nameservers of the zone, with the addresses of
+    /// those given one as glue. Applies to the names below it as well
+    Referral(Vec<(&'static str, Option<IpAddr>)>),
+    /// A referral as above, with these records added to its authority
+    /// section, the DS records of the zone and their signatures
+    SignedReferral(Vec<(&'static str, Option<IpAddr>)>, Vec<DNSResourceRecord>),
+    NxDomain,
+    Refused,
+    /// No response at all
+    Timeout,
+    /// A response with TC set over UDP, the inner one over TCP
+    Truncated(Box<Reply>),
+}
+
+struct Server {
+    udp: UdpSocket,
+    tcp: TcpListener,
+    conns: Vec<TcpConn>,
+    /// Replies by lowercase name
+    replies: HashMap<String, Reply>,
+}
+
+/// A question a server got, whether it came over TCP and whether
+/// recursion was desired
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asked {
+    pub server: IpAddr,
+    pub name: String,
+    pub qtype: u16,
+    pub tcp: bool,
+    pub rd: bool,
+}
+
+pub struct Scenario {
+    rdns: Rdns,
+    servers: HashMap<IpAddr, Server>,
+    client: UdpSocket,
+    /// Questions the servers got, in order
+    pub asked: Vec<Asked>,
+}
+
+impl Scenario {
+    /// A resolver whose root servers are `roots`, with retries and timeouts
+    /// short enough for tests, changed by `tweak` as needed
+    pub fn new(roots: &[IpAddr], tweak: impl FnOnce(&mut RdnsConfig)) -> Self {
+        let first = UdpSocket::bind((roots[0], 0)).unwrap();
+        let port = first.local_addr().unwrap().port();
+        let mut config = RdnsConfig {
+            root_hints: roots.to_vec(),
+            upstream_port: port,
+            read_timeout: Some(time::Duration::from_millis(5)),
+            query_timeout: time::Duration::from_millis(100),
+            retries: 1,
+            qname_minimization: false,
+            ..Default::default()
+        };
+        tweak(&mut config);
+        let rdns = Rdns::with_config("127.0.0.1", 0, config).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_nonblocking(true).unwrap();
+        let mut scenario = Self {
+            rdns,
+            servers: HashMap::new(),
+            client,
+            asked: vec![],
+        };
+        scenario.add_server(first);
+        for root in &roots[1..] {
+            scenario.server(*root);
+        }
+        scenario
+    }
+
+    fn port(&self) -> u16 {
+        self.rdns.config().upstream_port
+    }
+
+    fn add_server(&mut self, udp: UdpSocket) {
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).unwrap();
+        udp.set_nonblocking(true).unwrap();
+        tcp.set_nonblocking(true).unwrap();
+        self.servers.insert(
+            addr.ip(),
+            Server {
+                udp,
+                tcp,
+                conns: vec![],
+                replies: HashMap::new(),
+            },
+        );
+    }
+
+    /// Starts serving on `ip`, if not already
+    fn server(&mut self, ip: IpAddr) -> &mut Server {
+        if !self.servers.contains_key(&ip) {
+            let udp = UdpSocket::bind((ip, self.port())).unwrap();
+            self.add_server(udp);
+        }
+        self.servers.get_mut(&ip).unwrap()
+    }
+
+    /// Has `server` give `reply` to queries for `name`, or for the names
+    /// below it in the case of a referral
+    pub fn on(&mut self, server: IpAddr, name: &str, reply: Reply) -> &mut Self {
+        let name = normalize(name);
+        self.server(server).replies.insert(name, reply);
+        self
+    }
+
+    /// Sends the resolver a query and lets it and the servers run until
+    /// it responds
+    pub fn query(&mut self, name: &str, qtype: DNSType) -> DNSPacket {
+        self.send(DNSPacket::query(
+            0x1234,
+            DNSQuestion::new(name.to_string().to_domain_name(), qtype as u16),
+        ))
+    }
+
+    /// Like `query`, with a query of the test's own
+    pub fn send(&mut self, query: DNSPacket) -> DNSPacket {
+        let res = self.send_raw(&query.assemble().unwrap());
+        DNSPacket::from_raw(&res).unwrap()
+    }
+
+    /// Like `send`, with the query and its response as sent
+    pub fn send_raw(&mut self, query: &[u8]) -> Vec<u8> {
+        let to = self.rdns.local_addr().unwrap();
+        self.client.send_to(query, to).unwrap();
+        let deadline = time::Instant::now() + QUERY_DEADLINE;
+        let mut buf = vec![0u8; 65535];
+        while time::Instant::now() < deadline {
+            self.rdns.poll(&mut buf).unwrap();
+            self.serve();
+            match self.client.recv(&mut buf) {
+                Ok(n) => return buf[..n].to_vec(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        panic!(
+            "no response for {:?}",
+            DNSPacket::from_raw(query).map(|x| x.questions)
+        );
+    }
+
+    /// Handles what the servers received since last time
+    fn serve(&mut self) {
+        let mut buf = [0u8; 65535];
+        for (ip, server) in &mut self.servers {
+            while let Ok((n, from)) = server.udp.recv_from(&mut buf) {
+                if let Some(res) = respond(server, *ip, &buf[..n], false, &mut self.asked) {
+                    server.udp.send_to(&res, from).unwrap();
+                }
+            }
+            while let Ok((stream, peer)) = server.tcp.accept() {
+                server.conns.push(TcpConn::new(stream, peer).unwrap());
+            }
+            let mut conns = std::mem::take(&mut server.conns);
+            for conn in &mut conns {
+                while let Ok(Some(msg)) = conn.read_message() {
+                    if let Some(res) = respond(server, *ip, &msg, true, &mut self.asked) {
+                        conn.write_message(&res).unwrap();
+                    }
+                }
+            }
+            server.conns = conns;
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.to_string().to_domain_name().canonical()
+}
+
+/// The response of `server` to `msg`, if it gives one
+fn respond(
+    server: &Server,
+    ip: IpAddr,
+    msg: &[u8],
+    tcp: bool,
+    asked: &mut Vec<Asked>,
+) -> Option<Vec<u8>> {
+    let query = DNSPacket::from_raw(msg).unwrap();
+    let question = query.questions[0].clone();
+    asked.push(Asked {
+        server: ip,
+        name: question.qname.canonical(),
+        qtype: question.qtype,
+        tcp,
+        rd: query.header.rd == 1,
+    });
+    let mut pkt = DNSPacket::new(query.id(), false);
+    pkt.questions = vec![question.clone()];
+    // the most specific name the server knows of, the question's itself
+    // unless it is a referral
+    let name = &question.qname;
+    let found = (0..=name.len()).find_map(|i| {
+        let reply = server.replies.get(&name[i..].to_vec().canonical())?;
+        let referral = matches!(reply, Reply::Referral(_) | Reply::SignedReferral(..));
+        (i == 0 || referral).then(|| (name[i..].to_vec(), reply))
+    });
+    let (owner, mut reply) = match found {
+        Some((owner, reply)) => (owner, reply.clone()),
+        None => (vec![], Reply::Refused),
+    };
+    if let Reply::Truncated(inner) = reply {
+        if !tcp {
+            pkt.header.tc = 1;
+            return Some(pkt.assemble().unwrap());
+        }
+        reply = *inner;
+    }
+    if let Reply::SignedReferral(servers, records) = reply {
+        pkt.authorities.extend(records);
+        reply = Reply::Referral(servers);
+    }
+    match reply {
+        Reply::Records(records) => {
+            pkt.header.aa = 1;
+            pkt.answers = records
+                .into_iter()
+                .filter(|x| {
+                    let r#type = match x.rdata.as_ref() {
+                        DNSRdata::Rrsig { type_covered, .. } => *type_covered,
+                        _ => x.r#type,
+                    };
+                    r#type == question.qtype
+                        || r#type == DNSType::CNAME as u16
+                        || r#type == DNSType::DNAME as u16
+                })
+                .collect();
+            if pkt.answers.is_empty() {
+                pkt.authorities.push(zone::synthesize_soa(&owner, 300, 300));
+            }
+        }
+        Reply::Referral(servers) => {
+            for (ns, glue) in servers {
+                let ns = ns.to_string().to_domain_name();
+                pkt.authorities.push(rr(&owner, DNSRdata::Ns(ns.clone())));
+                if let Some(glue) = glue {
+                    pkt.additionals.push(addr(&ns, glue));
+                }
+            }
+        }
+        Reply::NxDomain => {
+            pkt.header.aa = 1;
+            pkt.header.set_rcode(DNSRcode::NameError);
+            pkt.authorities.push(zone::synthesize_soa(&owner, 300, 300));
+        }
+        Reply::Refused => pkt.header.set_rcode(DNSRcode::Refused),
+        Reply::Timeout => return None,
+        Reply::Truncated(_) | Reply::SignedReferral(..) => unreachable!(),
+    }
+    Some(pkt.assemble().unwrap())
+}
+
+fn rr(name: &DomainName, rdata: DNSRdata) -> DNSResourceRecord {
+    DNSResourceRecord {
+        name: name.clone(),
+        r#type: match rdata {
+            DNSRdata::A(_) => DNSType::A,
+            DNSRdata::Aaaa(_) => DNSType::AAAA,
+            DNSRdata::Ns(_) => DNSType::NS,
+            DNSRdata::Ptr(_) => DNSType::PTR,
+            DNSRdata::Dname(_) => DNSType::DNAME,
+            DNSRdata::Rrsig { .. } => DNSType::RRSIG,
+            DNSRdata::Dnskey { .. } => DNSType::DNSKEY,
+            DNSRdata::Ds { .. } => DNSType::DS,
+            _ => DNSType::CNAME,
+        } as u16,
+        class: DNSClass::IN as u16,
+        ttl: 300,
+        rdata: Arc::new(rdata),
+    }
+}
+
+/// An A or AAAA record of `name`, as `ip` is of IPv4 or IPv6
+fn addr(name: &DomainName, ip: IpAddr) -> DNSResourceRecord {
+    match ip {
+        IpAddr::V4(x) => rr(name, DNSRdata::A(x)),
+        IpAddr::V6(x) => rr(name, DNSRdata::Aaaa(x)),
+    }
+}
+
+/// An address record of `name`, as for `addr`
+pub fn a(name: &str, ip: IpAddr) -> DNSResourceRecord {
+    addr(&name.to_string().to_domain_name(), ip)
+}
+
+/// A CNAME record of `name`
+pub fn cname(name: &str, target: &str) -> DNSResourceRecord {
+    rr(
+        &name.to_string().to_domain_name(),
+        DNSRdata::Cname(target.to_string().to_domain_name()),
+    )
+}
+
+/// A key signing the records of a zone, with ECDSA P-256 and SHA-256
+/// (algorithm 13)
+pub struct ZoneKey {
+    apex: DomainName,
+    pair: EcdsaKeyPair,
+    /// Start of the validity period of the signatures, an hour before the
+    /// time the key was made for. They last a day from then
+    inception: u32,
+}
+
+impl ZoneKey {
+    /// A new key for `apex`, with signatures valid at `now`, in seconds
+    /// since the epoch
+    pub fn new(apex: &str, now: u32) -> Self {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        Self {
+            apex: apex.to_string().to_domain_name(),
+            pair,
+            inception: now - 3600,
+        }
+    }
+
+    /// The DNSKEY record of the key, a secure entry point
+    pub fn dnskey(&self) -> DNSResourceRecord {
+        rr(
+            &self.apex,
+            DNSRdata::Dnskey {
+                flags: 257,
+                protocol: 3,
+                algorithm: 13,
+                // the point without its uncompressed prefix
+                public_key: self.pair.public_key().as_ref()[1..].to_vec(),
+            },
+        )
+    }
+
+    /// The DS record of the key with a SHA-256 digest, for the parent zone
+    pub fn ds(&self) -> DNSResourceRecord {
+        let dnskey = self.dnskey();
+        let mut data = self.apex.to_bytes().unwrap();
+        data.extend(dnskey.rdata.canonical_bytes().unwrap());
+        rr(
+            &self.apex,
+            DNSRdata::Ds {
+                key_tag: key_tag(&dnskey.rdata),
+                algorithm: 13,
+                digest_type: 2,
+                digest: digest::digest(&digest::SHA256, &data).as_ref().to_vec(),
+            },
+        )
+    }
+
+    /// The RRSIG record of `rrset` made with the key
+    pub fn sign(&self, rrset: &[DNSResourceRecord]) -> DNSResourceRecord {
+        let owner = &rrset[0].name;
+        let sig = |signature| {
+            rr(
+                owner,
+                DNSRdata::Rrsig {
+                    type_covered: rrset[0].r#type,
+                    algorithm: 13,
+                    labels: owner.iter().filter(|x| *x != "*").count() as u8,
+                    original_ttl: rrset[0].ttl,
+                    expiration: self.inception + 86400,
+                    inception: self.inception,
+                    key_tag: key_tag(&self.dnskey().rdata),
+                    signer: self.apex.clone(),
+                    signature,
+                },
+            )
+        };
+        let data = signed_data(&sig(vec![]), rrset).unwrap();
+        let signature = self.pair.sign(&SystemRandom::new(), &data).unwrap();
+        sig(signature.as_ref().to_vec())
+    }
+}
+
+/// Address of a scripted server
+pub fn ip(n: u8) -> IpAddr {
+    Ipv4Addr::new(127, 0, 0, n).into()
+}
+
+/// IPv6 address of a scripted server, there being only the one
+pub fn ip6() -> IpAddr {
+    Ipv6Addr::LOCALHOST.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::dns::{BlockResponse, Dnssec};
+
+    /// Root at .2 delegating com to .3, which delegates example.com to .4
+    fn tree() -> Scenario {
+        tree_with(|_| {})
+    }
+
+    /// `tree` with the resolver's configuration changed by `tweak`
+    fn tree_with(tweak: impl FnOnce(&mut RdnsConfig)) -> Scenario {
+        let mut s = Scenario::new(&[ip(2)], tweak);
+        s.on(
+            ip(2),
+            "com",
+            Reply::Referral(vec![("a.gtld.net", Some(ip(3)))]),
+        )
+        .on(
+            ip(3),
+            "example.com",
+            Reply::Referral(vec![("ns.example.com", Some(ip(4)))]),
+        )
+        .on(
+            ip(4),
+            "www.example.com",
+            Reply::Records(vec![a("www.example.com", ip(100))]),
+        );
+        s
+    }
+
+    #[test]
+    fn referrals_are_followed_then_cached() {
+        let mut s = tree();
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::Normal as u8);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+        let servers: Vec<_> = s.asked.iter().map(|x| x.server).collect();
+        assert_eq!(servers, [ip(2), ip(3), ip(4)]);
+        // then from the cache, and the delegation is remembered
+        s.query("www.example.com", DNSType::A);
+        assert_eq!(s.asked.len(), 3);
+        // the server refusing is no answer either
+        let res = s.query("mail.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+        assert_eq!(s.asked.last().unwrap().server, ip(4));
+        assert_eq!(s.asked.len(), 4);
+    }
+
+    #[test]
+    fn cached_answers_are_found_whatever_the_case() {
+        let mut s = tree();
+        s.query("www.example.com", DNSType::A);
+        let asked = s.asked.len();
+        let res = s.query("WWW.Example.COM", DNSType::A);
+        assert_eq!(s.asked.len(), asked);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+        // the question is as the client asked it
+        assert_eq!(res.questions[0].qname, ["WWW", "Example", "COM"]);
+    }
+
+    #[test]
+    fn responses_offer_recursion_and_echo_rd() {
+        let mut s = tree();
+        for rd in [1, 0] {
+            let mut query = DNSPacket::query(
+                0x4321,
+                DNSQuestion::new("www.example.com".to_string().to_domain_name(), 1),
+            );
+            query.header.rd = rd;
+            let res = s.send(query);
+            assert_eq!(res.id(), 0x4321);
+            assert_eq!((res.header.qr, res.header.ra, res.header.rd), (1, 1, rd));
+            assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+        }
+    }
+
+    #[test]
+    fn questions_are_echoed_as_sent() {
+        let mut s = tree();
+        s.on(ip(4), "gone.example.com", Reply::NxDomain);
+        // resolved, then from the cache, then a name that does not exist
+        for name in ["wWw.ExAmPlE.cOm", "WWW.EXAMPLE.COM", "Gone.Example.Com"] {
+            let query = DNSPacket::query(
+                0x4321,
+                DNSQuestion::new(name.to_string().to_domain_name(), 1),
+            )
+            .assemble()
+            .unwrap();
+            let res = s.send_raw(&query);
+            assert_eq!(res[4..6], [0, 1]);
+            assert_eq!(res[12..query.len()], query[12..], "{}", name);
+        }
+    }
+
+    #[test]
+    fn cnames_are_chased() {
+        let mut s = tree();
+        s.on(
+            ip(4),
+            "www.example.com",
+            Reply::Records(vec![cname("www.example.com", "example.com")]),
+        )
+        .on(
+            ip(4),
+            "example.com",
+            Reply::Records(vec![a("example.com", ip(100))]),
+        );
+        let res = s.query("www.example.com", DNSType::A);
+        let answers: Vec<_> = res
+            .answers
+            .iter()
+            .map(|x| (x.name.canonical(), x.rdata.to_string()))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("www.example.com".into(), "example.com.".into()),
+                ("example.com".into(), "127.0.0.100".into())
+            ]
+        );
+        // a loop gives up
+        s.on(
+            ip(4),
+            "a.example.com",
+            Reply::Records(vec![cname("a.example.com", "b.example.com")]),
+        )
+        .on(
+            ip(4),
+            "b.example.com",
+            Reply::Records(vec![cname("b.example.com", "a.example.com")]),
+        );
+        let res = s.query("a.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+    }
+
+    #[test]
+    fn cnames_are_chased_into_other_zones() {
+        let mut s = tree();
+        s.on(
+            ip(2),
+            "net",
+            Reply::Referral(vec![("ns.cdn.net", Some(ip(5)))]),
+        )
+        .on(
+            ip(4),
+            "alias.example.com",
+            Reply::Records(vec![cname("alias.example.com", "edge.cdn.net")]),
+        )
+        .on(
+            ip(5),
+            "edge.cdn.net",
+            Reply::Records(vec![a("edge.cdn.net", ip(101))]),
+        );
+        let res = s.query("alias.example.com", DNSType::A);
+        let answers: Vec<_> = res.answers.iter().map(|x| x.rdata.to_string()).collect();
+        assert_eq!(answers, ["edge.cdn.net.", "127.0.0.101"]);
+        assert_eq!(res.questions[0].qname.canonical(), "alias.example.com");
+    }
+
+    #[test]
+    fn dnames_redirect_the_names_below_them() {
+        let mut s = tree();
+        s.on(
+            ip(2),
+            "net",
+            Reply::Referral(vec![("ns.example.net", Some(ip(5)))]),
+        )
+        .on(
+            ip(4),
+            "host.old.example.com",
+            Reply::Records(vec![rr(
+                &"old.example.com".to_string().to_domain_name(),
+                DNSRdata::Dname("new.example.net".to_string().to_domain_name()),
+            )]),
+        )
+        .on(
+            ip(5),
+            "host.new.example.net",
+            Reply::Records(vec![a("host.new.example.net", ip(105))]),
+        );
+        let res = s.query("host.old.example.com", DNSType::A);
+        let answers: Vec<_> = res
+            .answers
+            .iter()
+            .map(|x| (x.name.canonical(), x.r#type, x.rdata.to_string()))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                (
+                    "old.example.com".into(),
+                    DNSType::DNAME as u16,
+                    "new.example.net.".into()
+                ),
+                (
+                    "host.old.example.com".into(),
+                    DNSType::CNAME as u16,
+                    "host.new.example.net.".into()
+                ),
+                (
+                    "host.new.example.net".into(),
+                    DNSType::A as u16,
+                    "127.0.0.105".into()
+                ),
+            ]
+        );
+        assert_eq!(s.asked.last().unwrap().server, ip(5));
+    }
+
+    #[test]
+    fn records_outside_the_servers_zone_are_not_believed() {
+        let mut s = tree();
+        s.on(
+            ip(2),
+            "net",
+            Reply::Referral(vec![("ns.cdn.net", Some(ip(5)))]),
+        )
+        .on(
+            ip(4),
+            "alias.example.com",
+            Reply::Records(vec![
+                cname("alias.example.com", "edge.cdn.net"),
+                a("edge.cdn.net", ip(66)),
+            ]),
+        )
+        .on(
+            ip(5),
+            "edge.cdn.net",
+            Reply::Records(vec![a("edge.cdn.net", ip(101))]),
+        );
+        let res = s.query("alias.example.com", DNSType::A);
+        let answers: Vec<_> = res.answers.iter().map(|x| x.rdata.to_string()).collect();
+        assert_eq!(answers, ["edge.cdn.net.", "127.0.0.101"]);
+        // nor cached for others
+        let res = s.query("edge.cdn.net", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.101");
+    }
+
+    #[test]
+    fn unanswered_queries_are_retried_then_given_up() {
+        let mut s = tree();
+        s.on(ip(4), "slow.example.com", Reply::Timeout);
+        let res = s.query("slow.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+        let retries = s
+            .asked
+            .iter()
+            .filter(|x| x.name == "slow.example.com" && x.server == ip(4))
+            .count();
+        assert_eq!(retries, 2);
+    }
+
+    #[test]
+    fn unanswering_server_falls_back_to_the_other() {
+        let mut s = tree();
+        s.on(
+            ip(3),
+            "example.org",
+            Reply::Referral(vec![
+                ("ns1.example.org", Some(ip(6))),
+                ("ns2.example.org", Some(ip(7))),
+            ]),
+        );
+        s.on(
+            ip(2),
+            "org",
+            Reply::Referral(vec![("a.gtld.net", Some(ip(3)))]),
+        )
+        .on(ip(6), "www.example.org", Reply::Timeout)
+        .on(
+            ip(7),
+            "www.example.org",
+            Reply::Records(vec![a("www.example.org", ip(102))]),
+        );
+        let res = s.query("www.example.org", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.102");
+        // .6 is given up on after its retries, if asked first
+        let asked: Vec<_> = s
+            .asked
+            .iter()
+            .map(|x| x.server)
+            .filter(|x| *x == ip(6) || *x == ip(7))
+            .collect();
+        assert!(
+            asked == [ip(7)] || asked == [ip(6), ip(6), ip(7)],
+            "{:?}",
+            asked
+        );
+    }
+
+    #[test]
+    fn truncated_responses_are_asked_again_over_tcp() {
+        let mut s = tree();
+        s.on(
+            ip(4),
+            "big.example.com",
+            Reply::Truncated(Box::new(Reply::Records(vec![a(
+                "big.example.com",
+                ip(103),
+            )]))),
+        );
+        let res = s.query("big.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.103");
+        let asked: Vec<_> = s
+            .asked
+            .iter()
+            .filter(|x| x.server == ip(4))
+            .map(|x| x.tcp)
+            .collect();
+        assert_eq!(asked, [false, true]);
+    }
+
+    #[test]
+    fn cached_delegation_skips_the_root() {
+        let mut s = tree();
+        for name in ["a.example.com", "b.example.com"] {
+            s.on(ip(4), name, Reply::Records(vec![a(name, ip(101))]));
+        }
+        s.query("a.example.com", DNSType::A);
+        let before = s.asked.len();
+        let res = s.query("b.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.101");
+        // example.com's nameserver and its glue came with the referral
+        let servers: Vec<_> = s.asked[before..].iter().map(|x| x.server).collect();
+        assert_eq!(servers, [ip(4)]);
+    }
+
+    #[test]
+    fn servers_are_only_asked_the_next_label() {
+        let mut s = tree_with(|config| config.qname_minimization = true);
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+        let asked: Vec<_> = s
+            .asked
+            .iter()
+            .map(|x| (x.server, x.name.as_str(), x.qtype))
+            .collect();
+        // the root only learns of com, and com of example.com
+        let ns = DNSType::NS as u16;
+        assert_eq!(
+            asked,
+            [
+                (ip(2), "com", ns),
+                (ip(3), "example.com", ns),
+                (ip(4), "www.example.com", DNSType::A as u16)
+            ]
+        );
+    }
+
+    #[test]
+    fn nameserver_without_address_is_skipped() {
+        let mut s = Scenario::new(&[ip(2)], |_| {});
+        s.on(
+            ip(2),
+            "com",
+            Reply::Referral(vec![("a.gtld.net", Some(ip(3)))]),
+        )
+        .on(ip(2), "net", Reply::Referral(vec![("ns.net", Some(ip(5)))]))
+        .on(
+            ip(3),
+            "example.com",
+            Reply::Referral(vec![("ns1.dns.net", None), ("ns2.dns.net", None)]),
+        )
+        .on(ip(5), "ns1.dns.net", Reply::Records(vec![]))
+        .on(
+            ip(5),
+            "ns2.dns.net",
+            Reply::Records(vec![a("ns2.dns.net", ip(4))]),
+        )
+        .on(
+            ip(4),
+            "www.example.com",
+            Reply::Records(vec![a("www.example.com", ip(100))]),
+        );
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+        let looked_up: Vec<_> = s
+            .asked
+            .iter()
+            .filter(|x| x.server == ip(5))
+            .map(|x| (x.name.as_str(), x.qtype))
+            .collect();
+        // ns1 is asked for both its addresses before ns2 is tried
+        assert_eq!(
+            looked_up,
+            [
+                ("ns1.dns.net", DNSType::A as u16),
+                ("ns1.dns.net", DNSType::AAAA as u16),
+                ("ns2.dns.net", DNSType::A as u16)
+            ]
+        );
+    }
+
+    #[test]
+    fn refusing_servers_are_skipped() {
+        let mut s = Scenario::new(&[ip(2)], |_| {});
+        s.on(
+            ip(2),
+            "com",
+            Reply::Referral(vec![
+                ("a.gtld.net", Some(ip(3))),
+                ("b.gtld.net", Some(ip(5))),
+            ]),
+        )
+        .on(ip(3), "example.com", Reply::Refused)
+        .on(ip(5), "example.com", Reply::Refused);
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+        let mut servers: Vec<_> = s.asked[1..].iter().map(|x| x.server).collect();
+        servers.sort();
+        assert_eq!(servers, [ip(3), ip(5)]);
+        // with another server of com that answers, the query succeeds
+        let mut s = tree();
+        s.on(
+            ip(2),
+            "com",
+            Reply::Referral(vec![
+                ("a.gtld.net", Some(ip(3))),
+                ("b.gtld.net", Some(ip(5))),
+            ]),
+        )
+        .on(ip(5), "example.com", Reply::Refused);
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+    }
+
+    #[test]
+    fn nameservers_without_glue_are_kept_for_later() {
+        let mut s = Scenario::new(&[ip(2)], |_| {});
+        s.on(
+            ip(2),
+            "com",
+            Reply::Referral(vec![("a.gtld.net", Some(ip(3)))]),
+        )
+        .on(ip(2), "net", Reply::Referral(vec![("ns.net", Some(ip(5)))]))
+        .on(
+            ip(3),
+            "example.com",
+            Reply::Referral(vec![
+                ("ns1.example.com", Some(ip(6))),
+                ("ns2.dns.net", None),
+            ]),
+        )
+        .on(ip(6), "www.example.com", Reply::Timeout)
+        .on(
+            ip(5),
+            "ns2.dns.net",
+            Reply::Records(vec![a("ns2.dns.net", ip(4))]),
+        )
+        .on(
+            ip(4),
+            "www.example.com",
+            Reply::Records(vec![a("www.example.com", ip(100))]),
+        );
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+        // the glued server is given up on before the other is looked up
+        let servers: Vec<_> = s.asked.iter().map(|x| x.server).collect();
+        assert_eq!(servers, [ip(2), ip(3), ip(6), ip(6), ip(2), ip(5), ip(4)]);
+    }
+
+    #[test]
+    fn delegation_to_itself_without_glue_fails() {
+        let mut s = Scenario::new(&[ip(2)], |_| {});
+        s.on(
+            ip(2),
+            "loop.test",
+            Reply::Referral(vec![("ns.loop.test", None)]),
+        );
+        let res = s.query("www.loop.test", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+        assert!(s.asked.len() < 10, "{:?}", s.asked);
+    }
+
+    #[test]
+    fn endless_delegations_are_given_up() {
+        let mut s = Scenario::new(&[ip(2)], |config| config.max_delegations = 4);
+        // every server refers to the next, deeper down
+        for i in 2..12 {
+            let zone = ["d"; 10][..i - 1].join(".") + ".test";
+            s.on(
+                ip(i as u8),
+                &zone,
+                Reply::Referral(vec![("ns.test", Some(ip(i as u8 + 1)))]),
+            );
+        }
+        let res = s.query("d.d.d.d.d.d.d.d.d.d.test", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+        assert_eq!(s.asked.len(), 5, "{:?}", s.asked);
+    }
+
+    #[test]
+    fn names_that_do_not_exist_are_cached_as_such() {
+        let mut s = tree();
+        s.on(ip(4), "gone.example.com", Reply::NxDomain);
+        let res = s.query("gone.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::NameError as u8);
+        assert_eq!(res.authorities[0].r#type, DNSType::SOA as u16);
+        let asked = s.asked.len();
+        let res = s.query("gone.example.com", DNSType::A);
+        assert_eq!(res.header.rcode, DNSRcode::NameError as u8);
+        assert_eq!(s.asked.len(), asked);
+    }
+
+    #[test]
+    fn blocked_names_are_not_resolved() {
+        let path = std::env::temp_dir().join(format!("rdns-scenario-{}", std::process::id()));
+        std::fs::write(&path, "ads.example.com\n*.tracker.com\n").unwrap();
+        let mut s = tree_with(|config| {
+            config.blocklist = Some(path.clone());
+            config.block_response = BlockResponse::NxDomain;
+        });
+        std::fs::remove_file(&path).unwrap();
+        for name in ["ads.example.com", "pixel.tracker.com"] {
+            let res = s.query(name, DNSType::A);
+            assert_eq!(res.header.rcode, DNSRcode::NameError as u8, "{}", name);
+        }
+        assert!(s.asked.is_empty(), "{:?}", s.asked);
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.100");
+    }
+
+    #[test]
+    fn forwarders_are_asked_instead_of_the_roots() {
+        let mut s = tree_with(|config| {
+            config.forward = Some(SocketAddr::new(ip(9), config.upstream_port));
+        });
+        s.on(
+            ip(9),
+            "www.example.com",
+            Reply::Records(vec![a("www.example.com", ip(109))]),
+        );
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.109");
+        let asked: Vec<_> = s.asked.iter().map(|x| (x.server, x.rd)).collect();
+        assert_eq!(asked, [(ip(9), true)]);
+    }
+
+    #[test]
+    fn nameservers_with_only_ipv6_glue_are_asked_over_it() {
+        let mut s = tree();
+        s.on(
+            ip(3),
+            "example.com",
+            Reply::Referral(vec![("ns6.example.com", Some(ip6()))]),
+        )
+        .on(
+            ip6(),
+            "www.example.com",
+            Reply::Records(vec![a("www.example.com", ip(106))]),
+        );
+        let res = s.query("www.example.com", DNSType::A);
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.106");
+        let servers: Vec<_> = s.asked.iter().map(|x| x.server).collect();
+        assert_eq!(servers, [ip(2), ip(3), ip6()]);
+    }
+
+    #[test]
+    fn reverse_names_are_resolved() {
+        let mut s = tree();
+        let name = "100.2.0.192.in-addr.arpa";
+        s.on(
+            ip(2),
+            "arpa",
+            Reply::Referral(vec![("a.ns.arpa", Some(ip(5)))]),
+        )
+        .on(
+            ip(5),
+            "2.0.192.in-addr.arpa",
+            Reply::Referral(vec![("ns.example.com", Some(ip(4)))]),
+        )
+        .on(
+            ip(4),
+            name,
+            Reply::Records(vec![rr(
+                &name.to_string().to_domain_name(),
+                DNSRdata::Ptr("www.example.com".to_string().to_domain_name()),
+            )]),
+        );
+        let res = s.query(name, DNSType::PTR);
+        assert_eq!(res.header.rcode, DNSRcode::Normal as u8);
+        assert_eq!(res.answers[0].r#type, DNSType::PTR as u16);
+        assert_eq!(res.answers[0].rdata.to_string(), "www.example.com.");
+        let servers: Vec<_> = s.asked.iter().map(|x| x.server).collect();
+        assert_eq!(servers, [ip(2), ip(5), ip(4)]);
+    }
+
+    /// `tree` signed from the root down, with the key of the root as the
+    /// trust anchor, and net unsigned
+    fn signed_tree() -> Scenario {
+        let now = chrono::Utc::now().timestamp() as u32;
+        let (root, com, example) = (
+            ZoneKey::new(".", now),
+            ZoneKey::new("com", now),
+            ZoneKey::new("example.com", now),
+        );
+        let signed = |key: &ZoneKey, records: Vec<DNSResourceRecord>| {
+            let sig = key.sign(&records);
+            [records, vec![sig]].concat()
+        };
+        let mut s = Scenario::new(&[ip(2)], |config| {
+            config.dnssec = Dnssec::Always;
+            config.trust_anchors = vec![root.ds()];
+        });
+        let forged = a("forged.example.com", ip(66));
+        s.on(
+            ip(2),
+            ".",
+            Reply::Records(signed(&root, vec![root.dnskey()])),
+        )
+        .on(
+            ip(2),
+            "com",
+            Reply::SignedReferral(
+                vec![("a.gtld.net", Some(ip(3)))],
+                signed(&root, vec![com.ds()]),
+            ),
+        )
+        .on(
+            ip(2),
+            "net",
+            Reply::Referral(vec![("ns.cdn.net", Some(ip(5)))]),
+        )
+        .on(
+            ip(3),
+            "com",
+            Reply::Records(signed(&com, vec![com.dnskey()])),
+        )
+        .on(
+            ip(3),
+            "example.com",
+            Reply::SignedReferral(
+                vec![("ns.example.com", Some(ip(4)))],
+                signed(&com, vec![example.ds()]),
+            ),
+        )
+        .on(
+            ip(4),
+            "example.com",
+            Reply::Records(signed(&example, vec![example.dnskey()])),
+        )
+        .on(
+            ip(4),
+            "www.example.com",
+            Reply::Records(signed(&example, vec![a("www.example.com", ip(100))])),
+        )
+        .on(
+            ip(4),
+            "forged.example.com",
+            Reply::Records(vec![
+                forged,
+                example.sign(&[a("forged.example.com", ip(100))]),
+            ]),
+        )
+        .on(
+            ip(4),
+            "unsigned.example.com",
+            Reply::Records(vec![a("unsigned.example.com", ip(66))]),
+        )
+        .on(
+            ip(5),
+            "edge.cdn.net",
+            Reply::Records(vec![a("edge.cdn.net", ip(101))]),
+        );
+        s
+    }
+
+    /// A query for the A records of `name`, with the DO bit if `dnssec_ok`
+    /// and otherwise the AD bit
+    fn dnssec_query(name: &str, dnssec_ok: bool) -> DNSPacket {
+        let mut query = DNSPacket::query(
+            0x1234,
+            DNSQuestion::new(name.to_string().to_domain_name(), DNSType::A as u16),
+        );
+        query.header.set_ad(!dnssec_ok);
+        query.additionals.push(DNSResourceRecord {
+            rdata: Arc::new(DNSRdata::Opt {
+                udp_payload_size: 1232,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok,
+                z: 0,
+                options: vec![],
+            }),
+            ..DNSResourceRecord::opt(1232)
+        });
+        query
+    }
+
+    #[test]
+    fn signed_answers_are_validated() {
+        let mut s = signed_tree();
+        let res = s.send(dnssec_query("www.example.com", true));
+        assert_eq!(res.header.rcode, DNSRcode::Normal as u8);
+        assert!(res.header.ad());
+        let types: Vec<_> = res.answers.iter().map(|x| x.r#type).collect();
+        assert_eq!(types, [DNSType::A as u16, DNSType::RRSIG as u16]);
+        // with the keys of each zone down from the root
+        let keys: Vec<_> = s
+            .asked
+            .iter()
+            .filter(|x| x.qtype == DNSType::DNSKEY as u16)
+            .map(|x| x.name.as_str())
+            .collect();
+        assert_eq!(keys, [".", "com", "example.com"]);
+        // records that do not match their signature, or have none, are not
+        // given out
+        for name in ["forged.example.com", "unsigned.example.com"] {
+            let res = s.send(dnssec_query(name, false));
+            assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8, "{}", name);
+        }
+        // those of a zone without DS records are, but not as secure
+        let res = s.send(dnssec_query("edge.cdn.net", false));
+        assert_eq!(res.header.rcode, DNSRcode::Normal as u8);
+        assert!(!res.header.ad());
+        assert_eq!(res.answers[0].rdata.to_string(), "127.0.0.101");
+    }
+
+    #[test]
+    fn clients_may_not_want_answers_validated() {
+        let mut s = signed_tree();
+        let mut query = dnssec_query("forged.example.com", true);
+        // the CD bit
+        query.header.reserved |= 1;
+        let res = s.send(query);
+        assert_eq!(res.header.rcode, DNSRcode::Normal as u8);
+        assert!(!res.header.ad());
+        // but the others still get SERVFAIL
+        let res = s.send(dnssec_query("forged.example.com", false));
+        assert_eq!(res.header.rcode, DNSRcode::ServerFailure as u8);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::rdns::error::RdnsError;
+use crate::rdns::util::Result;
+use byteorder::{BigEndian, WriteBytesExt};
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time;
+
+/// How long writing a response to a client may block the resolver
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// A byte stream over a TCP socket, plain or encrypted
+pub trait Transport: Read + Write {
+    fn socket(&self) -> &TcpStream;
+    /// Whether bytes are waiting to be written to the socket, such as
+    /// those of a TLS handshake, which the next read or write sends
+    fn wants_write(&self) -> bool {
+        false
+    }
+}
+
+impl Transport for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Transport for StreamOwned<ServerConnection, TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+}
+
+impl Transport for StreamOwned<ClientConnection, TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+}
+
+/// A client connection. Messages are prefixed with their length as two
+/// octets (RFC 1035 4.2.2)
+pub struct TcpConn {
+    stream: Box<dyn Transport>,
+    pub peer: SocketAddr,
+    /// Bytes received but not yet taken as a message
+    buf: Vec<u8>,
+    /// The client will not send more, responses can still be written
+    eof: bool,
+    pub last_active: time::Instant,
+}
+
+impl TcpConn {
+    pub fn new(stream: impl Transport + 'static, peer: SocketAddr) -> Result<Self> {
+        stream.socket().set_nonblocking(true)?;
+        Ok(Self {
+            stream: Box::new(stream),
+            peer,
+            buf: Vec::new(),
+            eof: false,
+            last_active: time::Instant::now(),
+        })
+    }
+
+    /// Reads what has arrived without blocking and returns the first
+    /// complete message, if any
+    pub fn read_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut chunk = [0u8; 4096];
+        while !self.eof {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    self.last_active = time::Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+        if self.buf.len() < 2 + len {
+            return Ok(None);
+        }
+        let msg = self.buf[2..2 + len].to_vec();
+        self.buf.drain(..2 + len);
+        Ok(Some(msg))
+    }
+
+    /// Whether more can be read from the client, what `read_message`
+    /// waits for
+    pub fn wants_read(&self) -> bool {
+        !self.eof
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.stream.wants_write()
+    }
+
+    pub fn write_message(&mut self, msg: &[u8]) -> Result<()> {
+        // block for the write, a large response may not fit the send buffer
+        let socket = self.stream.socket();
+        socket.set_nonblocking(false)?;
+        socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let res = write_framed(&mut self.stream, msg);
+        self.stream.socket().set_nonblocking(true)?;
+        self.last_active = time::Instant::now();
+        res
+    }
+}
+
+impl AsRawFd for TcpConn {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.socket().as_raw_fd()
+    }
+}
+
+pub(crate) fn write_framed(stream: &mut impl Write, msg: &[u8]) -> Result<()> {
+    let len = u16::try_from(msg.len())
+        .map_err(|_| RdnsError::Encode("message exceeds 65535 bytes".into()))?;
+    let mut framed = Vec::with_capacity(2 + msg.len());
+    framed.write_u16::<BigEndian>(len)?;
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// A socket for a connection to `server`, bound to `local` if given
+fn socket_for(server: SocketAddr, local: Option<IpAddr>) -> Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(server),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // a local address of the other family cannot be bound
+    if let Some(ip) = local.filter(|x| x.is_ipv6() == server.is_ipv6()) {
+        socket.bind(&SocketAddr::new(ip, 0).into())?;
+    }
+    Ok(socket)
+}
+
+/// Starts connecting to `server` from `local`, if given. The stream is
+/// non-blocking, reads and writes would block until it is connected
+pub fn connect(server: SocketAddr, local: Option<IpAddr>) -> Result<TcpStream> {
+    let socket = socket_for(server, local)?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&server.into()) {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(socket.into())
+}
+
+/// A connection to an upstream server carrying a single query. Nothing
+/// blocks, each `poll` gets on with it as far as the socket allows
+pub struct UpstreamConn {
+    stream: Box<dyn Transport>,
+    pub peer: SocketAddr,
+    /// The framed query, less what was already written
+    out: Vec<u8>,
+    /// Bytes of the response received so far
+    buf: Vec<u8>,
+}
+
+impl UpstreamConn {
+    /// Sends `msg` to `peer` over `stream`, from `connect`
+    pub fn new(stream: impl Transport + 'static, peer: SocketAddr, msg: &[u8]) -> Result<Self> {
+        let mut out = vec![];
+        write_framed(&mut out, msg)?;
+        Ok(Self {
+            stream: Box::new(stream),
+            peer,
+            out,
+            buf: vec![],
+        })
+    }
+
+    /// Whether the query, or the TLS handshake before it, is not written
+    /// in full, which `poll` waits for the socket to allow
+    pub fn wants_write(&self) -> bool {
+        !self.out.is_empty() || self.stream.wants_write()
+    }
+
+    /// Writes what is left of the query and reads what has arrived, the
+    /// response once it is complete. Until the connection is established
+    /// both would block
+    pub fn poll(&mut self) -> Result<Option<Vec<u8>>> {
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut chunk = [0u8; 4096];
+        let eof = loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => break true,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                // a TLS server may close without close_notify once done
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break true,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let len = match self.buf.get(..2) {
+            Some(x) => u16::from_be_bytes([x[0], x[1]]) as usize,
+            None => 0,
+        };
+        if self.buf.len() >= 2 && self.buf.len() >= 2 + len {
+            return Ok(Some(self.buf.drain(..2 + len).skip(2).collect()));
+        }
+        match eof {
+            // the server closed the connection before responding in full
+            true => Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            false => Ok(None),
+        }
+    }
+}
+
+impl AsRawFd for UpstreamConn {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.socket().as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn upstream_conn_exchanges_framed_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let len = stream.read_u16::<BigEndian>().unwrap();
+            let mut msg = vec![0u8; len as usize];
+            stream.read_exact(&mut msg).unwrap();
+            msg.reverse();
+            write_framed(&mut stream, &msg).unwrap();
+        });
+        let stream = connect(server, None).unwrap();
+        let mut conn = UpstreamConn::new(stream, server, b"query").unwrap();
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        let res = loop {
+            if let Some(x) = conn.poll().unwrap() {
+                break x;
+            }
+            assert!(time::Instant::now() < deadline);
+            thread::sleep(time::Duration::from_millis(5));
+        };
+        assert_eq!(res, b"yreuq");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn upstream_conn_fails_on_early_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0, 10, 1, 2]).unwrap();
+        });
+        let stream = connect(server, None).unwrap();
+        let mut conn = UpstreamConn::new(stream, server, b"query").unwrap();
+        handle.join().unwrap();
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        loop {
+            match conn.poll() {
+                Ok(None) => assert!(time::Instant::now() < deadline),
+                Ok(Some(_)) => panic!("incomplete response taken"),
+                Err(_) => break,
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+    }
+}
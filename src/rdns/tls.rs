@@ -0,0 +1,54 @@
+use crate::rdns::error::RdnsError;
+use crate::rdns::tcp;
+use crate::rdns::util::Result;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, StreamOwned};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Configuration to serve DNS-over-TLS (RFC 7858) with the PEM encoded
+/// certificate chain and private key at the given paths
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<core::result::Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| RdnsError::Config(format!("no private key in {}", key.display())))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Configuration to query DNS-over-TLS servers, trusting the certificate
+/// authorities in the PEM file `ca` if given, else the usual public ones
+pub fn client_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Like `tcp::connect`, over TLS to a server whose certificate must be
+/// valid for `name`. The handshake goes on as the stream is used
+pub fn connect(
+    server: SocketAddr,
+    name: &str,
+    config: Arc<ClientConfig>,
+    local: Option<IpAddr>,
+) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+    let name = ServerName::try_from(name.to_string())?;
+    let conn = ClientConnection::new(config, name)?;
+    Ok(StreamOwned::new(conn, tcp::connect(server, local)?))
+}
@@ -0,0 +1,295 @@
+use crate::rdns::domain_name::ToReadableName;
+use crate::rdns::error::RdnsError;
+use crate::rdns::records::{DNSPacket, DNSQuestion, DNSRdata, DNSResourceRecord, DNSType};
+use crate::rdns::tcp::write_framed;
+use crate::rdns::util::Result;
+use log::debug;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::time;
+
+/// How long connecting to the primary, and each read from it, may take
+const TRANSFER_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// What a primary sent for a zone
+#[derive(Debug)]
+pub enum Transfer {
+    /// The zone has not changed since the version asked about
+    UpToDate,
+    /// All the records of the zone, its SOA first
+    Full(Vec<DNSResourceRecord>),
+    /// The changes from the version asked about to the latest, oldest first
+    Incremental(Vec<Diff>),
+}
+
+/// The changes from a version of a zone to the next (RFC 1995 section 4)
+#[derive(Debug)]
+pub struct Diff {
+    /// Serial of the version the changes apply to
+    pub from: u32,
+    pub deleted: Vec<DNSResourceRecord>,
+    /// SOA of the version the changes lead to
+    pub soa: DNSResourceRecord,
+    pub added: Vec<DNSResourceRecord>,
+}
+
+/// Asks `primary` over TCP for the changes to the zone since the version
+/// whose SOA is `soa` (RFC 1995). A primary that refuses incremental
+/// transfers, or does not know about them, is asked for the whole zone
+pub fn request(primary: SocketAddr, soa: &DNSResourceRecord) -> Result<Transfer> {
+    let serial = match soa.rdata.as_ref() {
+        DNSRdata::Soa { serial, .. } => *serial,
+        _ => return Err(RdnsError::Encode("IXFR needs the SOA of the zone".into())),
+    };
+    let question = DNSQuestion::new(soa.name.clone(), DNSType::IXFR as u16);
+    let mut query = DNSPacket::query(rand::random(), question);
+    query.header.set_rd(false);
+    query.authorities.push(soa.clone());
+    match fetch(primary, &query, Some(serial)) {
+        Err(RdnsError::ResolutionFailed(_, rcode)) => {
+            debug!(
+                "IXFR of {} refused by {} with rcode {}, trying AXFR",
+                soa.name.to_domain_name(),
+                primary,
+                rcode
+            );
+            let question = DNSQuestion::new(soa.name.clone(), DNSType::AXFR as u16);
+            let mut query = DNSPacket::query(rand::random(), question);
+            query.header.set_rd(false);
+            fetch(primary, &query, None)
+        }
+        x => x,
+    }
+}
+
+/// Sends `query` to `primary` and reads the messages of the response
+/// until the transfer is complete. `serial` is that of the version of the
+/// zone the client has, for an IXFR
+fn fetch(primary: SocketAddr, query: &DNSPacket, serial: Option<u32>) -> Result<Transfer> {
+    let mut stream = TcpStream::connect_timeout(&primary, TRANSFER_TIMEOUT)?;
+    stream.set_read_timeout(Some(TRANSFER_TIMEOUT))?;
+    stream.set_write_timeout(Some(TRANSFER_TIMEOUT))?;
+    write_framed(&mut stream, &query.assemble()?)?;
+    let name = query.questions[0].domain_name();
+    let mut records = vec![];
+    loop {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg)?;
+        let pkt = DNSPacket::from_raw(&msg)?;
+        if pkt.id() != query.id() || pkt.header.is_query() {
+            return Err(RdnsError::Parse("not a response to the transfer".into()));
+        }
+        if pkt.header.rcode != 0 {
+            return Err(RdnsError::ResolutionFailed(name, pkt.header.rcode));
+        }
+        records.extend(pkt.answers);
+        if let Some(transfer) = parse(&records, serial)? {
+            return Ok(transfer);
+        }
+    }
+}
+
+/// Serial of `rr` if it is an SOA record
+pub fn soa_serial(rr: &DNSResourceRecord) -> Option<u32> {
+    match rr.rdata.as_ref() {
+        DNSRdata::Soa { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// The transfer made of `records`, the answers received so far, or `None`
+/// if more are to come. `serial` is as for `fetch`
+fn parse(records: &[DNSResourceRecord], serial: Option<u32>) -> Result<Option<Transfer>> {
+    let latest = match records.first() {
+        Some(rr) => soa_serial(rr)
+            .ok_or_else(|| RdnsError::Parse("transfer does not start with an SOA".into()))?,
+        None => return Ok(None),
+    };
+    if records.len() == 1 {
+        // the whole zone may still follow when the serial is newer
+        return Ok(match serial {
+            Some(x) if !is_newer(latest, x) => Some(Transfer::UpToDate),
+            _ => None,
+        });
+    }
+    // the whole zone, closed by its SOA again, which an AXFR always is
+    if serial.is_none() || soa_serial(&records[1]).is_none() {
+        let (last, zone) = records.split_last().unwrap();
+        return Ok(soa_serial(last).map(|_| Transfer::Full(zone.to_vec())));
+    }
+    // otherwise a sequence of deletions and additions, each led by an SOA,
+    // until the SOA of the latest version where the next deletions would be
+    let mut diffs = vec![];
+    let mut i = 1;
+    while let Some(rr) = records.get(i) {
+        let from = soa_serial(rr).unwrap();
+        if from == latest {
+            if i + 1 < records.len() {
+                return Err(RdnsError::Parse("records after the end of the IXFR".into()));
+            }
+            return Ok(Some(match diffs.is_empty() {
+                // a zone of just its SOA
+                true => Transfer::Full(vec![records[0].clone()]),
+                false => Transfer::Incremental(diffs),
+            }));
+        }
+        let deleted = not_soa(&records[i + 1..]);
+        let j = i + 1 + deleted.len();
+        let soa = match records.get(j) {
+            Some(x) => x.clone(),
+            None => return Ok(None),
+        };
+        let added = not_soa(&records[j + 1..]);
+        i = j + 1 + added.len();
+        diffs.push(Diff {
+            from,
+            deleted: deleted.to_vec(),
+            soa,
+            added: added.to_vec(),
+        });
+    }
+    Ok(None)
+}
+
+/// The records up to the first SOA
+fn not_soa(records: &[DNSResourceRecord]) -> &[DNSResourceRecord] {
+    let end = records
+        .iter()
+        .position(|x| soa_serial(x).is_some())
+        .unwrap_or(records.len());
+    &records[..end]
+}
+
+/// Whether serial `a` is newer than `b`, in serial number arithmetic
+/// (RFC 1982), as serials wrap around
+pub fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::domain_name::ToDomainName;
+    use crate::rdns::records::DNSClass;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn soa(serial: u32) -> DNSResourceRecord {
+        DNSResourceRecord {
+            name: "example.com".to_string().to_domain_name(),
+            r#type: DNSType::SOA as u16,
+            class: DNSClass::IN as u16,
+            ttl: 3600,
+            rdata: Arc::new(DNSRdata::Soa {
+                mname: "ns.example.com".to_string().to_domain_name(),
+                rname: "hostmaster.example.com".to_string().to_domain_name(),
+                serial,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+            }),
+        }
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> DNSResourceRecord {
+        DNSResourceRecord {
+            name: name.to_string().to_domain_name(),
+            r#type: DNSType::A as u16,
+            class: DNSClass::IN as u16,
+            ttl: 300,
+            rdata: Arc::new(DNSRdata::A(ip.into())),
+        }
+    }
+
+    /// A primary answering the first transfer asked with `ixfr`, or
+    /// with the rcode if it is one, then the AXFR with `axfr`. Each
+    /// response is split over two messages
+    fn primary(
+        ixfr: std::result::Result<Vec<DNSResourceRecord>, u8>,
+        axfr: Vec<DNSResourceRecord>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut msg).unwrap();
+                let query = DNSPacket::from_raw(&msg).unwrap();
+                let mut pkt = DNSPacket::new(query.id(), false);
+                pkt.questions = query.questions.clone();
+                let records = match (query.questions[0].qtype, &ixfr) {
+                    (x, Ok(records)) if x == DNSType::IXFR as u16 => records.clone(),
+                    (x, Err(rcode)) if x == DNSType::IXFR as u16 => {
+                        pkt.header.rcode = *rcode;
+                        write_framed(&mut stream, &pkt.assemble().unwrap()).unwrap();
+                        continue;
+                    }
+                    _ => axfr.clone(),
+                };
+                let (first, rest) = records.split_at(records.len() / 2);
+                for part in [first, rest] {
+                    pkt.answers = part.to_vec();
+                    write_framed(&mut stream, &pkt.assemble().unwrap()).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn incremental_transfers_are_split_into_versions() {
+        let ixfr = vec![
+            soa(3),
+            soa(1),
+            a("www.example.com", [192, 0, 2, 1]),
+            soa(2),
+            a("www.example.com", [192, 0, 2, 2]),
+            soa(2),
+            soa(3),
+            a("mail.example.com", [192, 0, 2, 3]),
+            soa(3),
+        ];
+        let transfer = request(primary(Ok(ixfr), vec![]), &soa(1)).unwrap();
+        let diffs = match transfer {
+            Transfer::Incremental(x) => x,
+            x => panic!("{:?}", x),
+        };
+        assert_eq!(diffs.len(), 2);
+        assert_eq!((diffs[0].from, soa_serial(&diffs[0].soa)), (1, Some(2)));
+        assert_eq!((diffs[0].deleted.len(), diffs[0].added.len()), (1, 1));
+        assert_eq!((diffs[1].from, soa_serial(&diffs[1].soa)), (2, Some(3)));
+        assert_eq!((diffs[1].deleted.len(), diffs[1].added.len()), (0, 1));
+    }
+
+    #[test]
+    fn zones_that_did_not_change_are_up_to_date() {
+        let transfer = request(primary(Ok(vec![soa(1)]), vec![]), &soa(1)).unwrap();
+        assert!(matches!(transfer, Transfer::UpToDate));
+    }
+
+    #[test]
+    fn primaries_without_ixfr_send_the_whole_zone() {
+        let axfr = vec![soa(2), a("www.example.com", [192, 0, 2, 2]), soa(2)];
+        // not implemented
+        let transfer = request(primary(Err(4), axfr.clone()), &soa(1)).unwrap();
+        assert!(matches!(transfer, Transfer::Full(x) if x.len() == 2));
+        // or answered with the whole zone right away
+        let transfer = request(primary(Ok(axfr), vec![]), &soa(1)).unwrap();
+        assert!(matches!(transfer, Transfer::Full(x) if x.len() == 2));
+    }
+
+    #[test]
+    fn serials_wrap_around() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 1));
+        assert!(!is_newer(1, 2));
+        assert!(is_newer(1, u32::MAX));
+    }
+}
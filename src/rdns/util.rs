@@ -1,12 +1,14 @@
+use crate::rdns::error::RdnsError;
 use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, Local};
 use rand::distributions::uniform::SampleUniform;
 use rand::Rng;
-use std::error::Error;
+use std::fmt::Debug;
 use std::io::{Read, Write};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::ops::Range;
 
-pub type Result<T> = core::result::Result<T, Box<dyn Error>>;
+pub type Result<T> = core::result::Result<T, RdnsError>;
 
 pub trait ReadExt: Read {
     #[inline]
@@ -40,7 +42,7 @@ impl<R: Read + ?Sized> ReadExt for R {}
 pub trait WriteExt: Write {
     #[inline]
     fn write_string(&mut self, str: String) -> Result<()> {
-        self.write(str.as_bytes())?;
+        self.write_all(str.as_bytes())?;
         Ok(())
     }
 }
@@ -73,3 +75,18 @@ pub enum Either<L, R> {
     Left(L),
     Right(R),
 }
+
+/// Source of the current time, so that expiration can be driven by
+/// something other than the system clock
+pub trait Clock: Debug {
+    fn now(&self) -> DateTime<Local>;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
@@ -0,0 +1,179 @@
+use crate::rdns::dns::{Rdns, RdnsConfig, Shared};
+use crate::rdns::error::RdnsError;
+use crate::rdns::util::Result;
+use log::info;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::mpsc;
+use std::thread;
+
+/// Serves `host` and `port` with `config.workers` resolvers, each on a
+/// thread of its own, until one of them fails. They share the caches
+pub fn serve(host: &str, port: u16, mut config: RdnsConfig) -> Result<()> {
+    if config.workers <= 1 {
+        let mut rdns = Rdns::with_config(host, port, config)?;
+        info!("listening on {}:{}", host, port);
+        return rdns.start();
+    }
+    // the kernel spreads what arrives at the port over the workers, the
+    // responses to the queries of one must come back to it on a port of
+    // its own
+    config.reuse_port = true;
+    if config.outbound_addr.is_none() {
+        config.outbound_addr = Some(match host.parse()? {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+    }
+    let shared = Shared::new(&config)?;
+    let (tx, rx) = mpsc::channel();
+    for i in 0..config.workers {
+        let (host, config, shared, tx) =
+            (host.to_string(), config.clone(), shared.clone(), tx.clone());
+        thread::Builder::new()
+            .name(format!("worker-{}", i))
+            .spawn(move || {
+                let res = Rdns::with_shared(&host, port, config, shared).and_then(|mut rdns| {
+                    info!("worker {} listening on {}:{}", i, host, port);
+                    rdns.start()
+                });
+                let _ = tx.send(res);
+            })?;
+    }
+    drop(tx);
+    // a worker only stops on an error
+    rx.recv()
+        .unwrap_or_else(|_| Err(RdnsError::Io(io::Error::other("all workers panicked"))))
+}
+
+/// Like `serve`, on the blocking threads of the runtime it is awaited on
+#[cfg(feature = "tokio")]
+pub async fn run(host: String, port: u16, config: RdnsConfig) -> Result<()> {
+    tokio::task::spawn_blocking(move || serve(&host, port, config))
+        .await
+        .map_err(|e| RdnsError::Io(io::Error::other(e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::domain_name::{ToDomainName, ToReadableName};
+    use crate::rdns::records::{
+        DNSClass, DNSPacket, DNSQuestion, DNSRdata, DNSResourceRecord, DNSType,
+    };
+    use std::collections::HashSet;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// A forwarder answering every name with 127.0.0.1, after a second for
+    /// those starting with "slow", and the addresses it was asked from
+    fn upstream() -> (UdpSocket, Arc<Mutex<HashSet<SocketAddr>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.try_clone().unwrap();
+        let asked_from = Arc::new(Mutex::new(HashSet::new()));
+        let senders = asked_from.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = server.recv_from(&mut buf) {
+                senders.lock().unwrap().insert(from);
+                let mut pkt = DNSPacket::from_raw(&buf[..n]).unwrap();
+                pkt.header.qr = 1;
+                pkt.header.ra = 1;
+                pkt.additionals.clear();
+                let name = pkt.questions[0].qname.clone();
+                let slow = name.to_domain_name().to_lowercase().starts_with("slow");
+                let delay = match slow {
+                    true => Duration::from_secs(1),
+                    false => Duration::ZERO,
+                };
+                pkt.answers.push(DNSResourceRecord {
+                    name,
+                    r#type: DNSType::A as u16,
+                    class: DNSClass::IN as u16,
+                    ttl: 300,
+                    rdata: Arc::new(DNSRdata::A(Ipv4Addr::LOCALHOST)),
+                });
+                let server = server.try_clone().unwrap();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    server.send_to(&pkt.assemble().unwrap(), from).unwrap();
+                });
+            }
+        });
+        (socket, asked_from)
+    }
+
+    /// A port nothing listens on, to serve on
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn ask(to: u16, name: &str) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let question = DNSQuestion::new(name.to_string().to_domain_name(), DNSType::A as u16);
+        let msg = DNSPacket::query(1, question).assemble().unwrap();
+        client.send_to(&msg, ("127.0.0.1", to)).unwrap();
+        client
+    }
+
+    fn answer(client: &UdpSocket) -> DNSPacket {
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).unwrap();
+        DNSPacket::from_raw(&buf[..n]).unwrap()
+    }
+
+    #[test]
+    fn slow_upstream_does_not_hold_up_cache_hits() {
+        let (upstream, _) = upstream();
+        let port = free_port();
+        let config = RdnsConfig {
+            forward: Some(upstream.local_addr().unwrap()),
+            tcp: false,
+            workers: 2,
+            ..Default::default()
+        };
+        thread::spawn(move || serve("127.0.0.1", port, config));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(answer(&ask(port, "fast.example")).answers.len(), 1);
+        let slow = ask(port, "slow.example");
+        let started = Instant::now();
+        // the cached name is answered while the other is waited for
+        assert_eq!(answer(&ask(port, "fast.example")).answers.len(), 1);
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(answer(&slow).answers.len(), 1);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn queries_are_spread_over_the_workers() {
+        let (upstream, asked_from) = upstream();
+        let port = free_port();
+        let config = RdnsConfig {
+            forward: Some(upstream.local_addr().unwrap()),
+            tcp: false,
+            workers: 2,
+            // each worker then asks from its own socket
+            port_randomization: false,
+            ..Default::default()
+        };
+        thread::spawn(move || serve("127.0.0.1", port, config));
+        thread::sleep(Duration::from_millis(100));
+        // the kernel picks the worker of each client by its address, all
+        // of them going to the same one is unlikely enough
+        let clients: Vec<_> = (0..32)
+            .map(|i| ask(port, &format!("host{}.example", i)))
+            .collect();
+        for client in &clients {
+            assert_eq!(answer(client).answers.len(), 1);
+        }
+        assert_eq!(asked_from.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,715 @@
+use crate::rdns::domain_name::{DomainName, DomainNameExt, ToDomainName, ToReadableName};
+use crate::rdns::error::RdnsError;
+use crate::rdns::records::{
+    DNSClass, DNSPacket, DNSQuestion, DNSRcode, DNSRdata, DNSResourceRecord, DNSType,
+};
+use crate::rdns::transfer::{soa_serial, Transfer};
+use crate::rdns::util::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// CNAMEs followed inside the zone when answering
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// A zone served authoritatively, read from a simplified zone file with
+/// one record per line:
+///
+/// `<name> <ttl> <class> <type> <rdata>`
+///
+/// Names are absolute, with or without the trailing dot, and `;` starts a
+/// comment. A, AAAA, CNAME, MX, NS, TXT and SOA records are understood,
+/// as is an ALIAS record at the apex, where a CNAME is not allowed: the
+/// addresses of its target are looked up when asked for and served as the
+/// apex's. The apex is the owner of the SOA record, or else the shortest
+/// name of the file, in which case an SOA is made up for negative answers.
+/// Delegations to other servers are not followed
+pub struct Zone {
+    apex: DomainName,
+    soa: DNSResourceRecord,
+    /// Keyed by lowercase name
+    records: HashMap<String, Vec<DNSResourceRecord>>,
+    /// Target and TTL of the ALIAS record, if any
+    alias: Option<(DomainName, u32)>,
+}
+
+/// A line of a zone file with something on it
+enum Line {
+    Record(DNSResourceRecord),
+    /// Owner, TTL and target of an ALIAS record, which has no wire format
+    Alias(DomainName, u32, DomainName),
+}
+
+impl Zone {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut records = Vec::new();
+        let mut has_soa = false;
+        let mut aliases = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let tokens = tokenize(line).map_err(|e| at_line(i, e))?;
+            if tokens.is_empty() {
+                continue;
+            }
+            let rr = match parse_line(&tokens).map_err(|e| at_line(i, e))? {
+                Line::Record(x) => x,
+                Line::Alias(name, ttl, target) => {
+                    aliases.push((i, name, ttl, target));
+                    continue;
+                }
+            };
+            if rr.r#type == DNSType::SOA as u16 {
+                if has_soa {
+                    return Err(RdnsError::Config(format!(
+                        "line {}: more than one SOA record",
+                        i + 1
+                    )));
+                }
+                has_soa = true;
+            }
+            records.push(rr);
+        }
+        let mut zone = Self::from_records(records)?;
+        let apex = &zone.apex;
+        zone.alias = match aliases.as_slice() {
+            [] => None,
+            [(i, name, ttl, target)] => match name.canonical() == apex.canonical() {
+                true => Some((target.clone(), *ttl)),
+                false => return Err(at_line(*i, RdnsError::Config(alias_outside_apex()))),
+            },
+            [_, (i, ..), ..] => {
+                return Err(at_line(
+                    *i,
+                    RdnsError::Config("more than one ALIAS record".into()),
+                ))
+            }
+        };
+        Ok(zone)
+    }
+
+    /// The zone of `records`, which has the first SOA among them if any
+    fn from_records(records: Vec<DNSResourceRecord>) -> Result<Self> {
+        let soa = match records.iter().find(|x| x.r#type == DNSType::SOA as u16) {
+            Some(x) => x.clone(),
+            None => {
+                let apex = records
+                    .iter()
+                    .map(|x| &x.name)
+                    .min_by_key(|x| x.len())
+                    .ok_or_else(|| RdnsError::Config("zone has no records".into()))?;
+                synthesize_soa(apex, 3600, 300)
+            }
+        };
+        let apex = soa.name.clone();
+        if let Some(rr) = records.iter().find(|x| !x.name.is_subdomain_of(&apex)) {
+            return Err(RdnsError::Config(format!(
+                "{} is outside of zone {}",
+                rr.name.to_domain_name(),
+                apex.to_domain_name()
+            )));
+        }
+        let mut by_name: HashMap<String, Vec<DNSResourceRecord>> = HashMap::new();
+        for rr in records {
+            by_name.entry(rr.name.canonical()).or_default().push(rr);
+        }
+        Ok(Self {
+            apex,
+            soa,
+            records: by_name,
+            alias: None,
+        })
+    }
+
+    /// Whether `name` is the apex or below it
+    pub fn contains(&self, name: &DomainName) -> bool {
+        name.is_subdomain_of(&self.apex)
+    }
+
+    pub fn apex(&self) -> &DomainName {
+        &self.apex
+    }
+
+    /// SOA of the version served, made up if the zone file has none
+    pub fn soa(&self) -> &DNSResourceRecord {
+        &self.soa
+    }
+
+    /// Brings the zone to the version a transfer from its primary leads
+    /// to. The changes of an incremental one must start from the version
+    /// served, otherwise the zone is left as it was. The ALIAS record, which
+    /// is not transferred, is kept
+    pub fn apply(&mut self, transfer: Transfer) -> Result<()> {
+        let diffs = match transfer {
+            Transfer::UpToDate => return Ok(()),
+            Transfer::Full(records) => {
+                let zone = Self::from_records(records)?;
+                if !zone.apex.eq_ignore_case(&self.apex) {
+                    return Err(RdnsError::Parse(format!(
+                        "transfer of zone {} for zone {}",
+                        zone.apex.to_domain_name(),
+                        self.apex.to_domain_name()
+                    )));
+                }
+                self.soa = zone.soa;
+                self.records = zone.records;
+                return Ok(());
+            }
+            Transfer::Incremental(x) => x,
+        };
+        let mut records = self.records.clone();
+        let mut soa = self.soa.clone();
+        for diff in diffs {
+            let serial = soa_serial(&soa).unwrap();
+            if diff.from != serial {
+                return Err(RdnsError::Parse(format!(
+                    "changes from serial {} for zone {} at serial {}",
+                    diff.from,
+                    self.apex.to_domain_name(),
+                    serial
+                )));
+            }
+            for rr in &diff.deleted {
+                if let Some(rrs) = records.get_mut(&rr.name.canonical()) {
+                    rrs.retain(|x| !x.same_as(rr));
+                }
+            }
+            if let Some(rr) = diff.added.iter().find(|x| !self.contains(&x.name)) {
+                return Err(RdnsError::Parse(format!(
+                    "{} is outside of zone {}",
+                    rr.name.to_domain_name(),
+                    self.apex.to_domain_name()
+                )));
+            }
+            for rr in diff.added {
+                records.entry(rr.name.canonical()).or_default().push(rr);
+            }
+            let at_apex = records.entry(self.apex.canonical()).or_default();
+            at_apex.retain(|x| x.r#type != DNSType::SOA as u16);
+            at_apex.push(diff.soa.clone());
+            soa = diff.soa;
+        }
+        records.retain(|_, x| !x.is_empty());
+        self.soa = soa;
+        self.records = records;
+        Ok(())
+    }
+
+    /// Target and TTL of the ALIAS record if `question` asks for the
+    /// addresses of the apex and there is one. Its answer has to be looked
+    /// up rather than taken from `answer`
+    pub fn alias(&self, question: &DNSQuestion) -> Option<(&DomainName, u32)> {
+        let (target, ttl) = self.alias.as_ref()?;
+        let asks_address =
+            question.qtype == DNSType::A as u16 || question.qtype == DNSType::AAAA as u16;
+        match asks_address && question.qname.canonical() == self.apex.canonical() {
+            true => Some((target, *ttl)),
+            false => None,
+        }
+    }
+
+    /// The authoritative response to `question`, which must be in the zone
+    pub fn answer(&self, id: u16, question: &DNSQuestion) -> DNSPacket {
+        let mut pkt = DNSPacket::new(id, false);
+        pkt.header.aa = 1;
+        pkt.questions = vec![question.clone()];
+        let mut name = question.qname.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let rrs = self.records.get(&name.canonical()).map_or(&[][..], |x| x);
+            let matching: Vec<_> = rrs
+                .iter()
+                .filter(|x| x.r#type == question.qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                pkt.answers.extend(matching);
+                return pkt;
+            }
+            let cname = rrs.iter().find(|x| x.r#type == DNSType::CNAME as u16);
+            let target = match cname.map(|x| x.rdata.as_ref()) {
+                Some(DNSRdata::Cname(target)) => target.clone(),
+                _ => break,
+            };
+            pkt.answers.push(cname.unwrap().clone());
+            // the client resolves targets outside the zone itself
+            if !self.contains(&target) {
+                return pkt;
+            }
+            name = target;
+        }
+        if !pkt.answers.is_empty() {
+            return pkt;
+        }
+        // an empty non-terminal exists, though it has no records
+        let exists = self
+            .records
+            .values()
+            .any(|x| x[0].name.is_subdomain_of(&name));
+        if !exists {
+            pkt.header.set_rcode(DNSRcode::NameError);
+        }
+        pkt.authorities.push(self.negative_soa());
+        pkt
+    }
+
+    /// The SOA with the TTL negative answers may be cached for (RFC 2308
+    /// section 3)
+    fn negative_soa(&self) -> DNSResourceRecord {
+        let mut soa = self.soa.clone();
+        if let DNSRdata::Soa { minimum, .. } = soa.rdata.as_ref() {
+            soa.ttl = soa.ttl.min(*minimum);
+        }
+        soa
+    }
+}
+
+/// Problems found in the zone file `text`, each with its line number if it
+/// has one: lines that do not parse, a missing SOA or apex NS, CNAMEs to
+/// names of the zone that do not exist and names outside of the zone.
+/// `Zone::parse` is more lenient and serves any zone without the first
+/// kind of problem
+pub fn check(text: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut records = Vec::new();
+    let mut aliases = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        match tokenize(line).and_then(|x| match x.is_empty() {
+            true => Ok(None),
+            false => parse_line(&x).map(Some),
+        }) {
+            Ok(Some(Line::Record(rr))) => records.push((i, rr)),
+            Ok(Some(Line::Alias(name, ..))) => aliases.push((i, name)),
+            Ok(None) => {}
+            Err(e) => problems.push(at_line(i, e).to_string()),
+        }
+    }
+    let soas: Vec<_> = records
+        .iter()
+        .filter(|(_, x)| x.r#type == DNSType::SOA as u16)
+        .collect();
+    let apex = match soas.as_slice() {
+        [] => {
+            problems.push("no SOA record".into());
+            match records.iter().map(|(_, x)| &x.name).min_by_key(|x| x.len()) {
+                Some(x) => x.clone(),
+                None => return problems,
+            }
+        }
+        [(_, soa), rest @ ..] => {
+            for (i, _) in rest {
+                problems.push(format!("line {}: more than one SOA record", i + 1));
+            }
+            soa.name.clone()
+        }
+    };
+    let at_apex = |rr: &DNSResourceRecord| rr.name.canonical() == apex.canonical();
+    if !records
+        .iter()
+        .any(|(_, x)| x.r#type == DNSType::NS as u16 && at_apex(x))
+    {
+        problems.push(format!("no NS records at {}", apex.to_domain_name()));
+    }
+    for (n, (i, name)) in aliases.iter().enumerate() {
+        if n > 0 {
+            problems.push(format!("line {}: more than one ALIAS record", i + 1));
+        } else if name.canonical() != apex.canonical() {
+            problems.push(format!("line {}: {}", i + 1, alias_outside_apex()));
+        }
+    }
+    for (i, rr) in &records {
+        if !rr.name.is_subdomain_of(&apex) {
+            problems.push(format!(
+                "line {}: {} is outside of zone {}",
+                i + 1,
+                rr.name.to_domain_name(),
+                apex.to_domain_name()
+            ));
+        }
+        let target = match rr.rdata.as_ref() {
+            DNSRdata::Cname(x) if x.is_subdomain_of(&apex) => x.canonical(),
+            _ => continue,
+        };
+        if !records.iter().any(|(_, x)| x.name.canonical() == target) {
+            problems.push(format!(
+                "line {}: CNAME target {} does not exist",
+                i + 1,
+                target
+            ));
+        }
+    }
+    problems
+}
+
+fn at_line(i: usize, e: RdnsError) -> RdnsError {
+    RdnsError::Config(format!("line {}: {}", i + 1, e))
+}
+
+/// An SOA record for `apex`, for a zone that has none, with a negative
+/// caching TTL of `minimum`
+pub fn synthesize_soa(apex: &DomainName, ttl: u32, minimum: u32) -> DNSResourceRecord {
+    let mut rname = vec!["hostmaster".to_string()];
+    rname.extend(apex.iter().cloned());
+    DNSResourceRecord {
+        name: apex.clone(),
+        r#type: DNSType::SOA as u16,
+        class: DNSClass::IN as u16,
+        ttl,
+        rdata: Arc::new(DNSRdata::Soa {
+            mname: apex.clone(),
+            rname,
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+        }),
+    }
+}
+
+/// Splits a line into fields, keeping quoted strings whole and without
+/// their quotes
+fn tokenize(line: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut token = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => token.extend(chars.next()),
+                    Some(c) => token.push(c),
+                    None => return Err(RdnsError::Config("unterminated string".into())),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn parse_name(s: &str) -> Result<DomainName> {
+    let s = s.strip_suffix('.').unwrap_or(s);
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+    // names in Unicode are stored as their A-labels
+    let name: DomainName = match s.is_ascii() {
+        true => s.split('.').map(|x| x.to_string()).collect(),
+        false => s.to_string().to_ascii_domain_name()?,
+    };
+    if name.iter().any(|x| x.is_empty() || x.len() > 63) || name.wire_len() > 255 {
+        return Err(RdnsError::InvalidDomainName(s.to_string()));
+    }
+    Ok(name)
+}
+
+fn parse_num<T: std::str::FromStr>(s: &str) -> Result<T> {
+    s.parse()
+        .map_err(|_| RdnsError::Config(format!("invalid number `{}`", s)))
+}
+
+fn alias_outside_apex() -> String {
+    "ALIAS is only allowed at the apex".into()
+}
+
+fn parse_line(tokens: &[String]) -> Result<Line> {
+    match tokens.get(3) {
+        Some(x) if x.eq_ignore_ascii_case("ALIAS") => {
+            let (name, ttl) = parse_owner(tokens)?;
+            if tokens.len() != 5 {
+                return Err(RdnsError::Config("ALIAS takes 1 rdata fields".into()));
+            }
+            Ok(Line::Alias(name, ttl, parse_name(&tokens[4])?))
+        }
+        _ => parse_record(tokens).map(Line::Record),
+    }
+}
+
+/// The name and TTL of a record, once its class is checked
+fn parse_owner(tokens: &[String]) -> Result<(DomainName, u32)> {
+    // each type checks its rdata fields, there may be none
+    if tokens.len() < 4 {
+        return Err(RdnsError::Config(
+            "expected <name> <ttl> <class> <type> <rdata>".into(),
+        ));
+    }
+    let name = parse_name(&tokens[0])?;
+    let ttl = parse_num(&tokens[1])?;
+    if !tokens[2].eq_ignore_ascii_case("IN") {
+        return Err(RdnsError::Config(format!(
+            "unsupported class `{}`",
+            tokens[2]
+        )));
+    }
+    Ok((name, ttl))
+}
+
+fn parse_record(tokens: &[String]) -> Result<DNSResourceRecord> {
+    let (name, ttl) = parse_owner(tokens)?;
+    let args = &tokens[4..];
+    let expect = |n: usize| -> Result<()> {
+        match args.len() == n {
+            true => Ok(()),
+            false => Err(RdnsError::Config(format!(
+                "{} takes {} rdata fields",
+                tokens[3], n
+            ))),
+        }
+    };
+    let rdata = match tokens[3].to_ascii_uppercase().as_str() {
+        "A" => {
+            expect(1)?;
+            DNSRdata::A(args[0].parse()?)
+        }
+        "AAAA" => {
+            expect(1)?;
+            DNSRdata::Aaaa(args[0].parse()?)
+        }
+        "CNAME" => {
+            expect(1)?;
+            DNSRdata::Cname(parse_name(&args[0])?)
+        }
+        "NS" => {
+            expect(1)?;
+            DNSRdata::Ns(parse_name(&args[0])?)
+        }
+        "MX" => {
+            expect(2)?;
+            DNSRdata::Mx(parse_num(&args[0])?, parse_name(&args[1])?)
+        }
+        "TXT" => {
+            // the rdata is one or more character-strings (RFC 1035 3.3.14)
+            if args.is_empty() {
+                return Err(RdnsError::Config("TXT takes at least one string".into()));
+            }
+            if let Some(s) = args.iter().find(|x| x.len() > 255) {
+                return Err(RdnsError::Config(format!(
+                    "TXT string `{}` exceeds 255 bytes",
+                    s
+                )));
+            }
+            DNSRdata::Txt(args.iter().map(|x| x.as_bytes().to_vec()).collect())
+        }
+        "SOA" => {
+            expect(7)?;
+            DNSRdata::Soa {
+                mname: parse_name(&args[0])?,
+                rname: parse_name(&args[1])?,
+                serial: parse_num(&args[2])?,
+                refresh: parse_num(&args[3])?,
+                retry: parse_num(&args[4])?,
+                expire: parse_num(&args[5])?,
+                minimum: parse_num(&args[6])?,
+            }
+        }
+        x => return Err(RdnsError::Config(format!("unsupported type `{}`", x))),
+    };
+    let r#type = match rdata {
+        DNSRdata::A(_) => DNSType::A,
+        DNSRdata::Aaaa(_) => DNSType::AAAA,
+        DNSRdata::Cname(_) => DNSType::CNAME,
+        DNSRdata::Ns(_) => DNSType::NS,
+        DNSRdata::Mx(..) => DNSType::MX,
+        DNSRdata::Txt(_) => DNSType::TXT,
+        _ => DNSType::SOA,
+    };
+    Ok(DNSResourceRecord {
+        name,
+        r#type: r#type as u16,
+        class: DNSClass::IN as u16,
+        ttl,
+        rdata: Arc::new(rdata),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdns::transfer::Diff;
+
+    fn tokens(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn txt_needs_a_string() {
+        assert!(parse_record(&tokens("www 300 IN TXT")).is_err());
+        assert!(parse_record(&tokens("www 300 IN TXT ; no strings")).is_err());
+        let record = parse_record(&tokens("www 300 IN TXT \"\"")).unwrap();
+        assert!(matches!(record.rdata.as_ref(), DNSRdata::Txt(x) if x.len() == 1));
+    }
+
+    #[test]
+    fn check_reports_problems_by_line() {
+        let problems = check(
+            "example. 3600 IN SOA ns.example. hostmaster.example. 1 3600 600 86400 300\n\
+             www.example. 300 IN CNAME web.example.\n\
+             ext.example. 300 IN CNAME www.example.org.\n\
+             mail.example. 300 IN MX\n\
+             other. 300 IN A 192.0.2.1\n",
+        );
+        assert_eq!(
+            problems,
+            [
+                "line 4: MX takes 2 rdata fields",
+                "no NS records at example",
+                "line 2: CNAME target web.example does not exist",
+                "line 5: other is outside of zone example",
+            ]
+        );
+        let problems = check(
+            "example. 3600 IN SOA ns.example. hostmaster.example. 1 3600 600 86400 300\n\
+             example. 3600 IN NS ns.example.\n\
+             ns.example. 3600 IN A 192.0.2.1\n",
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(check("; empty\n"), ["no SOA record"]);
+    }
+
+    #[test]
+    fn alias_is_only_allowed_at_the_apex() {
+        let zone = Zone::parse(
+            "example. 3600 IN NS ns.example.\n\
+             example. 60 IN ALIAS target.example.org.\n",
+        )
+        .unwrap();
+        let question = |name: &str, qtype: DNSType| {
+            DNSQuestion::new(name.to_string().to_domain_name(), qtype as u16)
+        };
+        let (target, ttl) = zone.alias(&question("EXAMPLE", DNSType::AAAA)).unwrap();
+        assert_eq!(target, &"target.example.org".to_string().to_domain_name());
+        assert_eq!(ttl, 60);
+        assert!(zone.alias(&question("example", DNSType::MX)).is_none());
+        assert!(zone.alias(&question("www.example", DNSType::A)).is_none());
+        let text = "example. 3600 IN NS ns.example.\n\
+                    www.example. 60 IN ALIAS target.example.org.\n";
+        assert!(Zone::parse(text).is_err());
+        assert!(check(text).contains(&"line 2: ALIAS is only allowed at the apex".to_string()));
+    }
+
+    #[test]
+    fn transfers_are_applied_in_order() {
+        let mut zone = Zone::parse(
+            "example. 3600 IN SOA ns.example. hostmaster.example. 1 3600 600 86400 300\n\
+             www.example. 300 IN A 192.0.2.1\n",
+        )
+        .unwrap();
+        let rr = |line: &str| parse_record(&tokens(line)).unwrap();
+        let soa = |serial: u32| {
+            rr(&format!(
+                "example. 3600 IN SOA ns.example. hostmaster.example. {} 3600 600 86400 300",
+                serial
+            ))
+        };
+        let diff = |from: u32, deleted: &[&str], added: &[&str]| Diff {
+            from,
+            deleted: deleted.iter().map(|x| rr(x)).collect(),
+            soa: soa(from + 1),
+            added: added.iter().map(|x| rr(x)).collect(),
+        };
+        let www = DNSQuestion::new("www.example".to_string().to_domain_name(), 1);
+        // changes from another version leave the zone as it was
+        let stale = diff(2, &["www.example. 300 IN A 192.0.2.1"], &[]);
+        assert!(zone.apply(Transfer::Incremental(vec![stale])).is_err());
+        assert_eq!(zone.answer(1, &www).answers.len(), 1);
+        zone.apply(Transfer::Incremental(vec![
+            diff(
+                1,
+                &["WWW.example. 60 IN A 192.0.2.1"],
+                &["www.example. 300 IN A 192.0.2.2"],
+            ),
+            diff(2, &[], &["mail.example. 300 IN A 192.0.2.3"]),
+        ]))
+        .unwrap();
+        assert_eq!(soa_serial(zone.soa()), Some(3));
+        let answers = zone.answer(1, &www).answers;
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].rdata.to_string(), "192.0.2.2");
+        let mail = DNSQuestion::new("mail.example".to_string().to_domain_name(), 1);
+        assert_eq!(zone.answer(1, &mail).answers.len(), 1);
+        let apex = DNSQuestion::new(vec!["example".into()], DNSType::SOA as u16);
+        assert_eq!(zone.answer(1, &apex).answers.len(), 1);
+        // a whole zone replaces the records
+        zone.apply(Transfer::Full(vec![soa(4)])).unwrap();
+        assert_eq!(soa_serial(zone.soa()), Some(4));
+        assert_eq!(zone.answer(1, &www).header.rcode, DNSRcode::NameError as u8);
+    }
+
+    #[test]
+    fn answers_are_authoritative() {
+        let zone = Zone::parse(
+            "example. 3600 IN SOA ns.example. hostmaster.example. 1 3600 600 86400 300\n\
+             example. 3600 IN NS ns.example.\n\
+             example. 3600 IN MX 10 mail.example.\n\
+             ns.example. 3600 IN A 192.0.2.1\n\
+             www.example. 300 IN A 192.0.2.2\n\
+             www.example. 300 IN AAAA 2001:db8::2\n\
+             www.example. 300 IN TXT \"v=1\"\n\
+             a.b.example. 300 IN A 192.0.2.3\n",
+        )
+        .unwrap();
+        let answer = |name: &str, qtype: DNSType| {
+            let question = DNSQuestion::new(name.to_string().to_domain_name(), qtype as u16);
+            let pkt = zone.answer(7, &question);
+            assert_eq!((pkt.id(), pkt.header.aa), (7, 1));
+            pkt
+        };
+        // only the records of the type asked for
+        for (qtype, rdata) in [
+            (DNSType::A, "192.0.2.2"),
+            (DNSType::AAAA, "2001:db8::2"),
+            (DNSType::TXT, "\"v=1\""),
+        ] {
+            let pkt = answer("WWW.example", qtype);
+            assert_eq!(pkt.header.rcode, DNSRcode::Normal as u8);
+            let answers: Vec<_> = pkt.answers.iter().map(|x| x.rdata.to_string()).collect();
+            assert_eq!(answers, [rdata]);
+            assert!(pkt.authorities.is_empty());
+        }
+        assert_eq!(
+            answer("example", DNSType::MX).answers[0].r#type,
+            DNSType::MX as u16
+        );
+        // names that do not exist, with the SOA to cache that for its
+        // minimum
+        let pkt = answer("nope.example", DNSType::A);
+        assert_eq!(pkt.header.rcode, DNSRcode::NameError as u8);
+        assert!(pkt.answers.is_empty());
+        assert_eq!(pkt.authorities[0].r#type, DNSType::SOA as u16);
+        assert_eq!(pkt.authorities[0].ttl, 300);
+        // names that exist without records of the type, empty non-terminals
+        // included, are NODATA
+        for (name, qtype) in [("www.example", DNSType::MX), ("b.example", DNSType::A)] {
+            let pkt = answer(name, qtype);
+            assert_eq!(pkt.header.rcode, DNSRcode::Normal as u8);
+            assert!(pkt.answers.is_empty());
+            assert_eq!(pkt.authorities[0].r#type, DNSType::SOA as u16);
+        }
+    }
+
+    #[test]
+    fn rdata_fields_are_counted() {
+        assert!(parse_record(&tokens("www 300 IN A")).is_err());
+        assert!(parse_record(&tokens("www 300 IN")).is_err());
+    }
+}